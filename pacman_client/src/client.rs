@@ -1,18 +1,32 @@
+pub mod bot;
 pub mod event;
 pub mod heartbeat;
+pub mod leaderboard;
+pub mod offline;
+pub mod profile;
+pub mod replay;
 pub mod shell;
 pub mod states;
+pub mod turn;
 
-use std::sync::{atomic::AtomicBool, mpsc::Receiver, Arc};
+use std::{
+    sync::{atomic::AtomicBool, mpsc::Receiver, Arc, Mutex},
+    time::Duration,
+};
 
 use pacman_communication::{server_client, Connection};
 
+/// Join tickets the server sent for the game we host, with the time each one arrived
+pub type Tickets = Arc<Mutex<Vec<(server_client::JoinTicket, Duration)>>>;
+
 // Common info needed for all states
 pub struct CommonInfo {
     pub server: Connection,
     pub connection: Connection,
     pub recv: Receiver<server_client::Message>,
     pub keep_running: Arc<AtomicBool>,
+    pub tickets: Tickets,
+    pub bot: Option<bot::Bot>, // Plays instead of the user if set
 }
 
 pub fn run(
@@ -20,12 +34,15 @@ pub fn run(
     connection: Connection,
     recv: Receiver<server_client::Message>,
     keep_running: Arc<AtomicBool>,
+    bot: Option<bot::Bot>,
 ) {
     if let Some(connected_client) = states::Connected::new(CommonInfo {
         server,
         connection,
         recv,
         keep_running,
+        tickets: Tickets::default(),
+        bot,
     }) {
         println!("Connected to server!");
         connected_client.run();
@@ -0,0 +1,192 @@
+//! Headless players: a bot logs in by itself and keeps hosting or challenging games, choosing its
+//! moves with a `Strategy` instead of reading commands from stdin
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64},
+        Arc,
+    },
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use pacman_communication::{
+    current_time,
+    game::{Game, GameRules, Tile},
+    Direction,
+};
+
+/// Chooses the moves of a bot. Returning None stands still
+pub trait Strategy: Send {
+    fn pacman_move(&mut self, game: &Game) -> Option<Direction>;
+    fn ghost_move(&mut self, game: &Game, id: usize) -> Option<Direction>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[clap(rename_all = "kebab_case")]
+pub enum StrategyName {
+    /// Pacman goes for the closest food avoiding ghosts, ghosts chase pacman
+    #[default]
+    Greedy,
+    /// Every move is a random walkable direction
+    Random,
+}
+
+impl StrategyName {
+    #[must_use]
+    pub fn build(self) -> Box<dyn Strategy> {
+        match self {
+            Self::Greedy => Box::new(Greedy),
+            Self::Random => Box::new(Random::new()),
+        }
+    }
+}
+
+pub struct Bot {
+    pub user: String,
+    pub passwd: String,
+    /// Pacman to challenge, the bot hosts its own games if None
+    pub challenge: Option<String>,
+    pub rules: GameRules,
+    pub strategy: Box<dyn Strategy>,
+    /// Wait before each move, so humans can follow the game
+    pub delay: Duration,
+    /// Games to play before the bot quits, forever if None
+    pub max_games: Option<u32>,
+    /// Games played so far, kept across reconnections
+    pub games_played: Arc<AtomicU32>,
+    /// Seconds, as in `current_time`, until the server lets the bot log in again, `NEVER` if it
+    /// refused its password
+    pub login_after: Arc<AtomicU64>,
+}
+
+impl Bot {
+    /// Value of `login_after` once the bot should stop trying to log in
+    pub const NEVER: u64 = u64::MAX;
+}
+
+impl Bot {
+    pub fn pacman_move(&mut self, game: &Game) -> Option<Direction> {
+        std::thread::sleep(self.delay);
+        self.strategy.pacman_move(game)
+    }
+
+    pub fn ghost_move(&mut self, game: &Game, id: usize) -> Option<Direction> {
+        std::thread::sleep(self.delay);
+        self.strategy.ghost_move(game, id)
+    }
+}
+
+pub struct Greedy;
+
+impl Strategy for Greedy {
+    fn pacman_move(&mut self, game: &Game) -> Option<Direction> {
+        let pacman = game.pacman_position();
+        let frightened = game.frightened_turns() > 0;
+        // How soon a dangerous ghost can reach each tile
+        let threats: Vec<_> = if frightened {
+            Vec::new()
+        } else {
+            game.ghosts()
+                .iter()
+                .map(|ghost| game.distance_map(ghost.position))
+                .collect()
+        };
+        let danger = |position| {
+            threats
+                .iter()
+                .filter_map(|distances| distances.get(position))
+                .min()
+                .unwrap_or(u32::MAX)
+        };
+        let safe: Vec<_> = game
+            .neighbours(pacman)
+            .filter(|&(_, next)| danger(next) > 1)
+            .collect();
+        if safe.is_empty() {
+            return game
+                .neighbours(pacman)
+                .max_by_key(|&(_, next)| danger(next))
+                .map(|(dir, _)| dir);
+        }
+
+        let (height, width) = game.size();
+        let mut targets: Vec<(usize, usize)> = (0..height)
+            .flat_map(|x| (0..width).map(move |y| (x, y)))
+            .filter(|&position| {
+                matches!(game.tile_at(position), Some(Tile::Dot | Tile::PowerPellet))
+            })
+            .collect();
+        targets.extend(game.fruit_position());
+        if frightened {
+            targets.extend(game.ghosts().iter().map(|ghost| ghost.position));
+        }
+        safe.iter()
+            .min_by_key(|&&(_, next)| {
+                let distances = game.distance_map(next);
+                targets
+                    .iter()
+                    .filter_map(|&target| distances.get(target))
+                    .min()
+                    .unwrap_or(u32::MAX)
+            })
+            .map(|&(dir, _)| dir)
+    }
+
+    fn ghost_move(&mut self, game: &Game, id: usize) -> Option<Direction> {
+        let ghost = game.ghosts().iter().find(|ghost| ghost.id == id)?;
+        if game.frightened_turns() > 0 {
+            // Run away from pacman
+            let distances = game.distance_map(game.pacman_position());
+            return game
+                .neighbours(ghost.position)
+                .max_by_key(|&(_, next)| distances.get(next).unwrap_or(u32::MAX))
+                .map(|(dir, _)| dir);
+        }
+        game.shortest_path(ghost.position, game.pacman_position())?
+            .first()
+            .copied()
+    }
+}
+
+/// Xorshift generator, bots don't need the determinism of the game's own generator
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            state: current_time().as_nanos() as u64 | 1,
+        }
+    }
+
+    fn pick(&mut self, game: &Game, position: (usize, usize)) -> Option<Direction> {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let options: Vec<_> = game.neighbours(position).collect();
+        if options.is_empty() {
+            return None;
+        }
+        Some(options[(self.state % options.len() as u64) as usize].0)
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Strategy for Random {
+    fn pacman_move(&mut self, game: &Game) -> Option<Direction> {
+        self.pick(game, game.pacman_position())
+    }
+
+    fn ghost_move(&mut self, game: &Game, id: usize) -> Option<Direction> {
+        let position = game.ghosts().iter().find(|ghost| ghost.id == id)?.position;
+        self.pick(game, position)
+    }
+}
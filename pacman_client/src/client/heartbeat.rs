@@ -14,13 +14,18 @@ use pacman_communication::{
     Connection, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT,
 };
 
+use super::Tickets;
+
 const RECV_TIMEOUT: Duration = Duration::from_millis(33);
 
+/// Join tickets are kept in `tickets` as soon as they arrive, so the pacman's listener can check
+/// them while the game is running
 pub fn setup(
     server: Connection,
     connection: Connection,
     recv: Receiver<Message>,
     keep_running: Arc<AtomicBool>,
+    tickets: Tickets,
 ) -> Receiver<Message> {
     let (send, new_recv) = channel();
     let keep_running_watcher = keep_running.clone();
@@ -36,6 +41,8 @@ pub fn setup(
                 Ok(msg) => {
                     if let Message::Heartbeat = msg {
                         last_heartbeat = current_time();
+                    } else if let Message::JoinTicket(ticket) = msg {
+                        tickets.lock().unwrap().push((ticket, current_time()));
                     } else {
                         send.send(msg)
                             .expect("Not expected for receiver channel to drop!");
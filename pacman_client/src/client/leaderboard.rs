@@ -0,0 +1,150 @@
+//! Options of `lideres` and `ranking` and how the pages of the leaderboards are shown
+
+use pacman_communication::{
+    client_server::{LeaderboardQuery, RatingLeaderboardRequest, TimeWindow},
+    server_client::{LeaderboardPage, RatingPage},
+    civil_from_days, Role, PROVISIONAL_GAMES,
+};
+
+pub const LIDERES_HINT: &str =
+    "lideres [temporada|hoje|semana|sempre] [temporada=<n>] [melhores] [pagina=<n>] [usuario=<nome>]";
+
+pub const RANKING_HINT: &str = "ranking [pacman|fantasma] [pagina=<n>]";
+
+/// Query from the options of `lideres`, None if any option is invalid
+pub fn parse_query(options: &[String]) -> Option<LeaderboardQuery> {
+    let mut query = LeaderboardQuery::default();
+    for option in options {
+        match option.split_once('=') {
+            None => match option.as_str() {
+                "temporada" => query.window = TimeWindow::Season,
+                "hoje" => query.window = TimeWindow::Today,
+                "semana" => query.window = TimeWindow::Week,
+                "sempre" => query.window = TimeWindow::AllTime,
+                "melhores" => query.personal_bests = true,
+                _ => return None,
+            },
+            Some((name, value)) => match name {
+                // Pages are counted from 1 by players
+                "pagina" => query.page = value.parse::<usize>().ok()?.checked_sub(1)?,
+                "usuario" => query.user = Some(value.to_owned()),
+                "temporada" => query.season = Some(value.parse().ok()?),
+                _ => return None,
+            },
+        }
+    }
+    Some(query)
+}
+
+/// Request from the options of `ranking`, None if any option is invalid
+pub fn parse_ranking(options: &[String]) -> Option<RatingLeaderboardRequest> {
+    let mut request = RatingLeaderboardRequest {
+        role: Role::Pacman,
+        page: 0,
+    };
+    for option in options {
+        match option.split_once('=') {
+            None => match option.as_str() {
+                "pacman" => request.role = Role::Pacman,
+                "fantasma" => request.role = Role::Ghost,
+                _ => return None,
+            },
+            Some(("pagina", value)) => request.page = value.parse::<usize>().ok()?.checked_sub(1)?,
+            Some(_) => return None,
+        }
+    }
+    Some(request)
+}
+
+pub fn show_rating_page(page: &RatingPage) {
+    let role = match page.role {
+        Role::Pacman => "Pacman",
+        Role::Ghost => "fantasma",
+    };
+    if page.entries.is_empty() {
+        println!("Ninguém jogou como {role} contra outros usuários ({} no total)", page.total);
+    } else {
+        println!(
+            "Ranking como {role} ({} a {} de {}):",
+            page.first_position,
+            page.first_position + page.entries.len() - 1,
+            page.total
+        );
+    }
+    for (i, entry) in page.entries.iter().enumerate() {
+        println!(
+            "{:>4}. {} - {:.0} - {} jogos{}",
+            page.first_position + i,
+            &entry.user,
+            entry.rating,
+            entry.games,
+            if entry.games < PROVISIONAL_GAMES { " (provisório)" } else { "" }
+        );
+    }
+    match page.rank {
+        Some(rank) => println!("Sua posição: {rank}"),
+        None => println!("Você ainda não jogou como {role} contra outros usuários"),
+    }
+}
+
+pub fn show_page(page: &LeaderboardPage) {
+    if let Some(season) = &page.season {
+        println!(
+            "Temporada {} ({} a {})",
+            season.number,
+            format_time(season.start),
+            format_time(season.end)
+        );
+        match season.number {
+            1 => {}
+            2 => println!("Temporada anterior: temporada=1"),
+            n => println!("Temporadas anteriores: temporada=1 a temporada={}", n - 1),
+        }
+    }
+    if page.entries.is_empty() {
+        println!("Nenhum resultado ({} no total)", page.total);
+    } else {
+        println!(
+            "Líderes ({} a {} de {}):",
+            page.first_position,
+            page.first_position + page.entries.len() - 1,
+            page.total
+        );
+    }
+    for (i, entry) in page.entries.iter().enumerate() {
+        let opponents = if entry.opponents.is_empty() {
+            "IA".to_owned()
+        } else {
+            entry.opponents.join(", ")
+        };
+        let date = if entry.time == 0 {
+            "data desconhecida".to_owned()
+        } else {
+            format_time(entry.time)
+        };
+        println!(
+            "{:>4}. {} - {} pontos - contra {opponents} - mapa {} - {}m{:02}s - {date}",
+            page.first_position + i,
+            &entry.user,
+            entry.score,
+            if entry.map.is_empty() { "?" } else { &entry.map },
+            entry.duration / 60,
+            entry.duration % 60,
+        );
+    }
+    match page.rank {
+        Some(rank) => println!("Sua posição: {rank}"),
+        None => println!("Você ainda não tem resultados aqui"),
+    }
+}
+
+/// Seconds since UNIX_EPOCH as a date and time in UTC
+fn format_time(secs: u64) -> String {
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{day:02}/{month:02}/{year} {:02}:{:02} UTC",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60
+    )
+}
@@ -0,0 +1,155 @@
+//! Games played without a server: single-player against AI ghosts, or hot-seat with a second
+//! player moving a ghost on the same terminal
+
+use std::sync::{atomic::AtomicBool, Arc};
+
+use pacman_communication::{
+    current_time,
+    game::{Game, GameMode, GameRules, GhostController, MoveOutcome, TICK_INTERVAL},
+    replay::Replay,
+    Direction,
+};
+
+use super::{
+    replay::save_replay,
+    shell::Shell,
+    turn::{show_outcome, TurnEnd, TurnInput},
+};
+
+/// Name of the local players in replays and on the board
+const PLAYER: &str = "local";
+const GHOST_PLAYER: &str = "fantasma";
+
+pub fn run(rules: GameRules, hot_seat: bool) {
+    let keep_running = Arc::new(AtomicBool::new(true));
+    let seed = current_time().as_nanos() as u64;
+    let mut game = match Game::with_rules(rules, seed) {
+        Ok(game) => game,
+        Err(err) => {
+            println!("Erro no jogo: {err}");
+            return;
+        }
+    };
+    if hot_seat {
+        game.add_remote_ghost(GHOST_PLAYER);
+    }
+    match game.mode() {
+        GameMode::TurnBased => run_turn_based(&mut game, keep_running),
+        GameMode::RealTime => run_real_time(&mut game, keep_running),
+    }
+    println!("Jogo encerrado com pontuação {}!", game.score());
+    save_replay(&Replay::from_game(&game, PLAYER), PLAYER);
+}
+
+fn run_turn_based(game: &mut Game, keep_running: Arc<AtomicBool>) {
+    let commands = ["move", "encerra"];
+    let shell = Shell::new(&commands, keep_running);
+    let mut pacman_input = TurnInput::new(game.rules());
+    let mut ghost_input = TurnInput::new(game.rules());
+    loop {
+        // Ghosts' turns, skipped in the rounds their speed doesn't allow
+        let turn_order: Vec<(usize, GhostController)> = if game.ghosts_move() {
+            game.ghosts()
+                .iter()
+                .map(|ghost| (ghost.id, ghost.controller.clone()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        for (id, controller) in turn_order {
+            let turn = match controller {
+                GhostController::Ai => game
+                    .move_ai_ghost(id)
+                    .map(|outcome| TurnEnd::Played(None, outcome)),
+                GhostController::Remote(_) => {
+                    game.show();
+                    println!("Turno do fantasma!");
+                    ghost_input.play(
+                        &shell,
+                        "FANTASMA",
+                        |dir| dir.map_or(Ok(MoveOutcome::Moved), |dir| game.move_ghost(id, dir)),
+                        |_| {},
+                    )
+                }
+            };
+            match turn {
+                Ok(TurnEnd::Played(..)) => {}
+                Ok(TurnEnd::Quit) => return,
+                // The ghost player left, pacman keeps playing against the AI
+                Ok(TurnEnd::Forfeit) => game.remove_remote_ghost(GHOST_PLAYER),
+                Err(err) => {
+                    println!("Erro no jogo: {err}");
+                    return;
+                }
+            }
+            if game.game_over() {
+                game.show();
+                return;
+            }
+        }
+
+        game.show();
+        println!("Turno do Pacman!");
+        let turn = pacman_input.play(
+            &shell,
+            "PACMAN",
+            |dir| match dir {
+                Some(dir) => game.move_pacman(dir),
+                None => game.pass_pacman(),
+            },
+            |_| {},
+        );
+        match turn {
+            Ok(TurnEnd::Played(_, outcome)) => show_outcome(game, outcome),
+            Ok(TurnEnd::Quit) => return,
+            Ok(TurnEnd::Forfeit) => game.forfeit(),
+            Err(err) => {
+                println!("Erro no jogo: {err}");
+                return;
+            }
+        }
+        if game.game_over() {
+            game.show();
+            return;
+        }
+    }
+}
+
+fn run_real_time(game: &mut Game, keep_running: Arc<AtomicBool>) {
+    let commands = ["move", "encerra"];
+    let shell = Shell::new(&commands, keep_running);
+    let mut next_tick = current_time() + TICK_INTERVAL;
+    loop {
+        if let Some(command) = shell.poll() {
+            match command[0].as_str() {
+                "move" => {
+                    let dir = Direction::from_key(command[1].chars().next().unwrap()).unwrap();
+                    game.set_pacman_direction(dir);
+                }
+                "encerra" => return,
+                _ => unreachable!(),
+            }
+        }
+        let now = current_time();
+        if now < next_tick {
+            std::thread::sleep((next_tick - now).min(TICK_INTERVAL / 8));
+            continue;
+        }
+        next_tick += TICK_INTERVAL;
+        if let Err(err) = game.tick() {
+            println!("Erro no jogo: {err}");
+            return;
+        }
+        print!("\x1B[2J\x1B[H");
+        game.show();
+        println!(
+            "Tick {} - pontuação {} - direção atual: {} (move <direcao (wasd)> para mudar)",
+            game.ticks(),
+            game.score(),
+            game.pacman_direction().map_or('-', |dir| dir.key())
+        );
+        if game.game_over() {
+            return;
+        }
+    }
+}
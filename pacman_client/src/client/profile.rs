@@ -0,0 +1,44 @@
+//! How the profile of a user is shown by `perfil`
+
+use pacman_communication::{UserStats, PROVISIONAL_GAMES};
+
+pub fn show_profile(user: &str, stats: &UserStats) {
+    println!("Perfil de {user}:");
+    println!("- Jogos como Pacman: {}", stats.pacman_games);
+    println!("- Jogos como fantasma: {}", stats.ghost_games);
+    println!("- Capturas como fantasma: {}", stats.catches);
+    println!("- Melhor pontuação: {}", stats.best_score);
+    match stats.average_score() {
+        Some(average) => println!("- Pontuação média: {average:.1}"),
+        None => println!("- Pontuação média: -"),
+    }
+    println!("- Pontos comidos: {}", stats.dots_eaten);
+    let secs = stats.time_played;
+    println!(
+        "- Tempo jogado: {}h{:02}m{:02}s",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    );
+    let ratings = &stats.ratings;
+    println!(
+        "- Rating como Pacman: {:.0} ({} jogos contra usuários{})",
+        ratings.pacman,
+        ratings.pacman_games,
+        provisional(ratings.pacman_games)
+    );
+    println!(
+        "- Rating como fantasma: {:.0} ({} jogos contra usuários{})",
+        ratings.ghost,
+        ratings.ghost_games,
+        provisional(ratings.ghost_games)
+    );
+}
+
+fn provisional(games: u64) -> &'static str {
+    if games < PROVISIONAL_GAMES {
+        ", provisório"
+    } else {
+        ""
+    }
+}
@@ -0,0 +1,106 @@
+//! Saving and watching replays of matches, and saving reports of matches whose peers stopped
+//! agreeing on the state of the game
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use pacman_communication::{current_time, game::Game, peer::Desync, replay::Replay};
+use serde::Serialize;
+
+use super::shell::Shell;
+
+/// Saves the replay of a match in the `replays` directory
+pub fn save_replay(replay: &Replay, user: &str) {
+    let path = PathBuf::from(format!("replays/{}-{user}.json", current_time().as_secs()));
+    match replay.save(&path) {
+        Ok(()) => println!("Replay salvo em {}", path.display()),
+        Err(err) => println!("Erro ao salvar replay: {err}"),
+    }
+}
+
+#[derive(Serialize)]
+struct DesyncReport<'a> {
+    desync: &'a Desync,
+    /// Our own copy of the game when the desync was detected
+    game: &'a Game,
+}
+
+/// Saves the report of a desync in the `desyncs` directory
+pub fn save_desync_report(desync: &Desync, game: &Game, user: &str) {
+    println!("Jogo dessincronizado: {desync}");
+    let path = PathBuf::from(format!("desyncs/{}-{user}.json", current_time().as_secs()));
+    let report = DesyncReport { desync, game };
+    let result = std::fs::create_dir_all("desyncs").and_then(|()| {
+        let json = serde_json::to_string(&report)?;
+        std::fs::write(&path, json)
+    });
+    match result {
+        Ok(()) => println!("Relatório salvo em {}", path.display()),
+        Err(err) => println!("Erro ao salvar relatório: {err}"),
+    }
+}
+
+/// Slowest and fastest speeds a replay can be watched at
+pub const SPEEDS: std::ops::RangeInclusive<f64> = 0.1..=100.0;
+
+/// Shows a replay step by step, or at `speed` times the speed the match was played
+pub fn play_replay(path: &str, speed: Option<f64>, keep_running: Arc<AtomicBool>) {
+    let replay = match Replay::load(Path::new(path)) {
+        Ok(replay) => replay,
+        Err(err) => {
+            println!("Erro ao abrir o replay: {err}");
+            return;
+        }
+    };
+    let speed = speed.map(|speed| speed.clamp(*SPEEDS.start(), *SPEEDS.end()));
+    println!(
+        "Replay de {} contra {:?} (semente {}, pontuação final {})",
+        &replay.pacman, &replay.ghosts, replay.seed, replay.score
+    );
+    let commands = ["proximo", "encerra"];
+    let shell = Shell::new(&commands, keep_running);
+    let total = match replay.step_count() {
+        Ok(total) => total,
+        Err(err) => {
+            println!("Replay inválido: {err}");
+            return;
+        }
+    };
+    let mut last_time = Duration::ZERO;
+    let mut i = 0;
+    let mut finished = true;
+    let result = replay.play(|time, game| {
+        if let Some(speed) = speed {
+            std::thread::sleep(time.saturating_sub(last_time).div_f64(speed));
+            last_time = time;
+        }
+        i += 1;
+        println!("Passo {i}/{total}");
+        game.show();
+        if speed.is_none() && i < total {
+            loop {
+                let command = shell.prompt("REPLAY");
+                if command.is_empty() {
+                    continue;
+                }
+                match command[0].as_str() {
+                    "proximo" => return true,
+                    "encerra" => {
+                        finished = false;
+                        return false;
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+        true
+    });
+    match result {
+        Err(err) => println!("Replay inválido: {err}"),
+        Ok(()) if finished => println!("Fim do replay!"),
+        Ok(()) => {}
+    }
+}
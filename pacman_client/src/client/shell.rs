@@ -1,7 +1,74 @@
 use std::io::BufRead;
 use std::io::Write;
 use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use pacman_communication::{
+    current_time,
+    game::{GameMode, GameRules, Wraparound},
+};
+
+use super::leaderboard::{parse_query, parse_ranking, LIDERES_HINT, RANKING_HINT};
+use super::replay::SPEEDS;
+
+const RECV_TIMEOUT: Duration = Duration::from_millis(33);
+
+/// Remaining time of a timed prompt at which the player is warned
+const TIME_WARNING: Duration = Duration::from_secs(10);
+
+/// Lines typed on stdin. A single thread reads stdin for the whole program, so that states which
+/// poll for commands (like real-time games) don't leave a blocked reader behind to steal lines
+/// from the next prompt
+fn stdin_lines() -> &'static Mutex<Receiver<String>> {
+    static LINES: OnceLock<Mutex<Receiver<String>>> = OnceLock::new();
+    LINES.get_or_init(|| {
+        let (send, recv) = channel();
+        std::thread::spawn(move || {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                let Ok(line) = line else { return; };
+                if send.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+        Mutex::new(recv)
+    })
+}
+
+const INICIA_HINT: &str = "inicia [turnos|tempo-real] [tuneis] [fantasmas=<n>] [remotos=<n>] [velocidade=<1-100>] [limite=<turnos>] [vidas=<n>] [tempo=<segundos>] [ausencias=<n>]";
+
+/// Rules of a new game from the options of `inicia`, None if any option is invalid
+pub fn parse_rules(options: &[String]) -> Option<GameRules> {
+    let mut rules = GameRules::default();
+    for option in options {
+        match option.split_once('=') {
+            None => match option.as_str() {
+                "turnos" => rules.mode = GameMode::TurnBased,
+                "tempo-real" => rules.mode = GameMode::RealTime,
+                "tuneis" => rules.wraparound = Wraparound::Tunnels,
+                _ => return None,
+            },
+            Some((name, value)) => match name {
+                "fantasmas" => rules.ai_ghosts = value.parse().ok()?,
+                "remotos" => rules.max_remote_ghosts = value.parse().ok()?,
+                "velocidade" => rules.ghost_speed = value.parse().ok()?,
+                "limite" => rules.turn_limit = Some(value.parse().ok()?),
+                "vidas" => rules.lives = value.parse().ok()?,
+                // Zero disables the turn timer and forfeits
+                "tempo" => rules.turn_time = Some(value.parse().ok()?).filter(|&secs| secs > 0),
+                "ausencias" => {
+                    rules.max_missed_turns = Some(value.parse().ok()?).filter(|&turns| turns > 0);
+                }
+                _ => return None,
+            },
+        }
+    }
+    rules.validate().ok()?;
+    Some(rules)
+}
 
 pub struct Shell {
     keep_running: Arc<AtomicBool>,
@@ -28,12 +95,17 @@ impl Shell {
                     "novo" => "novo <usuario> <senha>",
                     "senha" => "senha <senha antiga> <senha nova>",
                     "entra" => "entra <usuario> <senha>",
-                    "lideres" => "lideres",
+                    "lideres" => LIDERES_HINT,
                     "l" => "l",
-                    "inicia" => "inicia",
+                    "perfil" => "perfil [usuario]",
+                    "ranking" => RANKING_HINT,
+                    "inicia" => INICIA_HINT,
                     "desafio" => "desafio <oponente>",
+                    "assiste" => "assiste <jogador>",
                     "move" => "move <direcao (wasd)>",
                     "atraso" => "atraso",
+                    "replay" => "replay <arquivo> [passo|<velocidade>]",
+                    "proximo" => "proximo",
                     "encerra" => "encerra",
                     "sai" => "sai",
                     "tchau" => "tchau",
@@ -52,115 +124,219 @@ impl Shell {
             }
             print!("{decoration} > ");
             std::io::stdout().flush().unwrap();
-            let mut lock = std::io::stdin().lock();
-            let mut line = String::new();
-            let _ = lock.read_line(&mut line).unwrap();
-            let tokens: Vec<String> = line
-                .split_whitespace()
-                .map(std::borrow::ToOwned::to_owned)
-                .collect();
-            if tokens.is_empty() {
-                continue;
-            }
-            let command = &tokens[0];
-            if self.allowed_commands.iter().all(|c| c != command) {
-                println!("Comando {command} não reconhecido");
-                self.help();
-                continue;
-            }
-            let len = tokens.len();
-            match match command.as_str() {
-                "novo" => {
-                    if len == 3 {
-                        Ok(())
-                    } else {
-                        Err("novo <usuario> <senha>")
-                    }
-                }
-                "senha" => {
-                    if len == 3 {
-                        Ok(())
-                    } else {
-                        Err("senha <senha antiga> <senha nova>")
-                    }
-                }
-                "entra" => {
-                    if len == 3 {
-                        Ok(())
-                    } else {
-                        Err("entra <usuario> <senha>")
-                    }
-                }
-                "lideres" => {
-                    if len == 1 {
-                        Ok(())
-                    } else {
-                        Err("lideres")
-                    }
-                }
-                "l" => {
-                    if len == 1 {
-                        Ok(())
-                    } else {
-                        Err("l")
-                    }
-                }
-                "inicia" => {
-                    if len == 1 {
-                        Ok(())
-                    } else {
-                        Err("inicia")
-                    }
-                }
-                "desafio" => {
-                    if len == 2 {
-                        Ok(())
-                    } else {
-                        Err("desafio <oponente>")
-                    }
-                }
-                "move" => {
-                    if len == 2 && ["w", "a", "s", "d"].contains(&tokens[1].as_str()) {
-                        Ok(())
-                    } else {
-                        Err("move <direcao (wasd)>")
-                    }
-                }
-                "atraso" => {
-                    if len == 1 {
-                        Ok(())
-                    } else {
-                        Err("atraso")
-                    }
-                }
-                "encerra" => {
-                    if len == 1 {
-                        Ok(())
-                    } else {
-                        Err("encerra")
-                    }
-                }
-                "sai" => {
-                    if len == 1 {
-                        Ok(())
-                    } else {
-                        Err("sai")
-                    }
-                }
-                "tchau" => {
-                    if len == 1 {
-                        Ok(())
-                    } else {
-                        Err("tchau")
-                    }
-                }
-                _ => unreachable!(),
-            } {
-                Ok(()) => return tokens,
-                Err(hint) => {
-                    println!("Argumentos inválidos: `{hint}`");
+            let line = loop {
+                if !self.keep_running.load(std::sync::atomic::Ordering::Relaxed) {
+                    println!("Encerrando shell...");
                     return Vec::new();
                 }
+                match stdin_lines().lock().unwrap().recv_timeout(RECV_TIMEOUT) {
+                    Ok(line) => break line,
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return Vec::new(),
+                }
+            };
+            if let Some(tokens) = self.parse(&line) {
+                return tokens;
+            }
+        }
+    }
+
+    /// Like `prompt`, but gives up at `deadline` (as in `current_time`) returning None
+    /// The remaining time is shown in the prompt, and once more when it is almost over
+    #[must_use]
+    pub fn prompt_until(&self, decoration: &str, deadline: Option<Duration>) -> Option<Vec<String>> {
+        let Some(deadline) = deadline else { return Some(self.prompt(decoration)); };
+        loop {
+            if !self.keep_running.load(std::sync::atomic::Ordering::Relaxed) {
+                println!("Encerrando shell...");
+                return Some(Vec::new());
+            }
+            let remaining = deadline.saturating_sub(current_time());
+            print!("{decoration} ({}s) > ", remaining.as_millis().div_ceil(1000));
+            std::io::stdout().flush().unwrap();
+            let mut warned = remaining <= TIME_WARNING;
+            let line = loop {
+                if !self.keep_running.load(std::sync::atomic::Ordering::Relaxed) {
+                    println!("Encerrando shell...");
+                    return Some(Vec::new());
+                }
+                let remaining = deadline.saturating_sub(current_time());
+                if remaining.is_zero() {
+                    println!();
+                    println!("Tempo esgotado!");
+                    return None;
+                }
+                if !warned && remaining <= TIME_WARNING {
+                    warned = true;
+                    println!();
+                    print!("Restam {}s! {decoration} > ", remaining.as_millis().div_ceil(1000));
+                    std::io::stdout().flush().unwrap();
+                }
+                match stdin_lines().lock().unwrap().recv_timeout(RECV_TIMEOUT) {
+                    Ok(line) => break line,
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return Some(Vec::new()),
+                }
+            };
+            if let Some(tokens) = self.parse(&line) {
+                return Some(tokens);
+            }
+        }
+    }
+
+    /// Non blocking version of `prompt`: returns a command only if a valid one was already typed
+    #[must_use]
+    pub fn poll(&self) -> Option<Vec<String>> {
+        let line = stdin_lines().lock().unwrap().try_recv().ok()?;
+        self.parse(&line).filter(|tokens| !tokens.is_empty())
+    }
+
+    /// Returns None if nothing usable was typed, an empty vector if the arguments are invalid
+    fn parse(&self, line: &str) -> Option<Vec<String>> {
+        let tokens: Vec<String> = line
+            .split_whitespace()
+            .map(std::borrow::ToOwned::to_owned)
+            .collect();
+        if tokens.is_empty() {
+            return None;
+        }
+        let command = &tokens[0];
+        if self.allowed_commands.iter().all(|c| c != command) {
+            println!("Comando {command} não reconhecido");
+            self.help();
+            return None;
+        }
+        let len = tokens.len();
+        match match command.as_str() {
+            "novo" => {
+                if len == 3 {
+                    Ok(())
+                } else {
+                    Err("novo <usuario> <senha>")
+                }
+            }
+            "senha" => {
+                if len == 3 {
+                    Ok(())
+                } else {
+                    Err("senha <senha antiga> <senha nova>")
+                }
+            }
+            "entra" => {
+                if len == 3 {
+                    Ok(())
+                } else {
+                    Err("entra <usuario> <senha>")
+                }
+            }
+            "lideres" => {
+                if parse_query(&tokens[1..]).is_some() {
+                    Ok(())
+                } else {
+                    Err(LIDERES_HINT)
+                }
+            }
+            "l" => {
+                if len == 1 {
+                    Ok(())
+                } else {
+                    Err("l")
+                }
+            }
+            "perfil" => {
+                if len <= 2 {
+                    Ok(())
+                } else {
+                    Err("perfil [usuario]")
+                }
+            }
+            "ranking" => {
+                if parse_ranking(&tokens[1..]).is_some() {
+                    Ok(())
+                } else {
+                    Err(RANKING_HINT)
+                }
+            }
+            "inicia" => {
+                if parse_rules(&tokens[1..]).is_some() {
+                    Ok(())
+                } else {
+                    Err(INICIA_HINT)
+                }
+            }
+            "desafio" => {
+                if len == 2 {
+                    Ok(())
+                } else {
+                    Err("desafio <oponente>")
+                }
+            }
+            "assiste" => {
+                if len == 2 {
+                    Ok(())
+                } else {
+                    Err("assiste <jogador>")
+                }
+            }
+            "move" => {
+                if len == 2 && ["w", "a", "s", "d"].contains(&tokens[1].as_str()) {
+                    Ok(())
+                } else {
+                    Err("move <direcao (wasd)>")
+                }
+            }
+            "atraso" => {
+                if len == 1 {
+                    Ok(())
+                } else {
+                    Err("atraso")
+                }
+            }
+            "replay" => {
+                if len == 2
+                    || (len == 3
+                        && (tokens[2] == "passo"
+                            || tokens[2].parse().is_ok_and(|speed| SPEEDS.contains(&speed))))
+                {
+                    Ok(())
+                } else {
+                    Err("replay <arquivo> [passo|<velocidade>]")
+                }
+            }
+            "proximo" => {
+                if len == 1 {
+                    Ok(())
+                } else {
+                    Err("proximo")
+                }
+            }
+            "encerra" => {
+                if len == 1 {
+                    Ok(())
+                } else {
+                    Err("encerra")
+                }
+            }
+            "sai" => {
+                if len == 1 {
+                    Ok(())
+                } else {
+                    Err("sai")
+                }
+            }
+            "tchau" => {
+                if len == 1 {
+                    Ok(())
+                } else {
+                    Err("tchau")
+                }
+            }
+            _ => unreachable!(),
+        } {
+            Ok(()) => Some(tokens),
+            Err(hint) => {
+                println!("Argumentos inválidos: `{hint}`");
+                Some(Vec::new())
             }
         }
     }
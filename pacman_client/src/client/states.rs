@@ -2,6 +2,7 @@ pub mod connected;
 pub mod ghost;
 pub mod idle;
 pub mod pacman;
+pub mod spectator;
 
 pub use connected::Connected;
 
@@ -22,4 +23,14 @@ pub use std::sync::{atomic::AtomicBool, mpsc::Receiver, Arc};
 
 use super::heartbeat;
 use super::shell::Shell;
-use pacman_communication::server_client;
+use pacman_communication::{current_time, server_client};
+
+/// Tells the user how long the server refuses their password after too many wrong ones
+fn show_locked(until: u64) {
+    let secs = until.saturating_sub(current_time().as_secs());
+    println!(
+        "Muitas senhas erradas! Tente de novo em {}m{:02}s",
+        secs / 60,
+        secs % 60
+    );
+}
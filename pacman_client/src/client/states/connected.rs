@@ -1,8 +1,10 @@
 use std::sync::atomic::Ordering;
 
+use crate::client::{bot::Bot, replay::play_replay};
+
 use super::{
-    heartbeat, server_client, watch, CommonInfo, CreateUserRequest, Idle, LoginRequest, Message,
-    MessageEnum, ServerMessage, Shell, WatchErr,
+    heartbeat, server_client, show_locked, watch, CommonInfo, CreateUserRequest, Idle, LoginRequest,
+    Message, MessageEnum, ServerMessage, Shell, WatchErr,
 };
 
 pub struct Connected {
@@ -25,6 +27,7 @@ impl Connected {
                     info.connection,
                     info.recv,
                     info.keep_running.clone(),
+                    info.tickets.clone(),
                 );
                 Some(Self { info })
             }
@@ -39,7 +42,10 @@ impl Connected {
 
     pub fn run(self) {
         println!(">>> CONECTADO AO SERVIDOR COM SUCESSO!");
-        let commands = ["novo", "entra", "tchau"];
+        if self.info.bot.is_some() {
+            return self.run_bot();
+        }
+        let commands = ["novo", "entra", "replay", "tchau"];
         let shell = Shell::new(&commands, self.info.keep_running.clone());
         loop {
             let command = shell.prompt("SEM LOGIN");
@@ -85,25 +91,36 @@ impl Connected {
                             passwd: passwd.clone(),
                         }),
                     });
-                    match watch(&self.info.recv, |msg| -> bool {
+                    let user = match watch(&self.info.recv, |msg| -> bool {
                         matches!(msg, ServerMessage::LoginResponse(_))
                     }) {
                         Ok(msg) => {
-                            if let ServerMessage::LoginResponse(server_client::LoginResponse::Err) =
-                                msg
-                            {
-                                println!("Login não aceito, talvez a senha ou o usuário podem estar errados");
-                                continue;
+                            let ServerMessage::LoginResponse(response) = msg else { unreachable!() };
+                            match response {
+                                server_client::LoginResponse::Ok(user) => user,
+                                server_client::LoginResponse::Locked(until) => {
+                                    show_locked(until);
+                                    continue;
+                                }
+                                server_client::LoginResponse::Err => {
+                                    println!("Login não aceito, talvez a senha ou o usuário podem estar errados");
+                                    continue;
+                                }
                             }
                         }
                         Err(WatchErr::Timeout) => {
                             println!("Timeout esperando pelo servidor!");
+                            user.clone()
                         }
                         Err(WatchErr::Disconnection) => return,
-                    }
-                    let idle_client = Idle::new(self.info, user.clone());
+                    };
+                    let idle_client = Idle::new(self.info, user);
                     return idle_client.run();
                 }
+                "replay" => {
+                    let speed = command.get(2).and_then(|speed| speed.parse().ok());
+                    play_replay(&command[1], speed, self.info.keep_running.clone());
+                }
                 "tchau" => {
                     self.info.server.send(Message {
                         connection: self.info.connection,
@@ -116,4 +133,54 @@ impl Connected {
             }
         }
     }
+
+    /// Creates the user of the bot if needed and logs in
+    fn run_bot(self) {
+        let bot = self.info.bot.as_ref().unwrap();
+        let (user, passwd) = (bot.user.clone(), bot.passwd.clone());
+        self.info.server.send(Message {
+            connection: self.info.connection,
+            message: MessageEnum::CreateUserRequest(CreateUserRequest {
+                user: user.clone(),
+                passwd: passwd.clone(),
+            }),
+        });
+        // Fails if the user already exists, which is fine
+        if let Err(WatchErr::Disconnection) = watch(&self.info.recv, |msg| -> bool {
+            matches!(msg, ServerMessage::CreateUserResponse(_))
+        }) {
+            return;
+        }
+        self.info.server.send(Message {
+            connection: self.info.connection,
+            message: MessageEnum::LoginRequest(LoginRequest {
+                user: user.clone(),
+                passwd,
+            }),
+        });
+        match watch(&self.info.recv, |msg| -> bool {
+            matches!(msg, ServerMessage::LoginResponse(_))
+        }) {
+            Ok(ServerMessage::LoginResponse(server_client::LoginResponse::Ok(user))) => {
+                println!("Bot entrou como {user}");
+                let idle_client = Idle::new(self.info, user);
+                idle_client.run()
+            }
+            Ok(ServerMessage::LoginResponse(server_client::LoginResponse::Locked(until))) => {
+                show_locked(until);
+                bot.login_after.store(until, Ordering::Relaxed);
+                self.info.keep_running.store(false, Ordering::Relaxed);
+            }
+            Ok(_) => {
+                println!("Login do bot não aceito!");
+                bot.login_after.store(Bot::NEVER, Ordering::Relaxed);
+                self.info.keep_running.store(false, Ordering::Relaxed);
+            }
+            Err(WatchErr::Timeout) => {
+                println!("Timeout esperando pelo servidor!");
+                self.info.keep_running.store(false, Ordering::Relaxed);
+            }
+            Err(WatchErr::Disconnection) => {}
+        }
+    }
 }
@@ -1,18 +1,35 @@
 use std::{
-    io::{Read, Write},
+    io::BufReader,
     net::{SocketAddr, TcpStream},
+    sync::mpsc::{channel, RecvTimeoutError},
     time::Duration,
 };
 
-use pacman_communication::{current_time, game::Game};
+use pacman_communication::{
+    current_time,
+    game::{Game, GameMode, GameRules, MoveOutcome, MAX_REMOTE_GHOSTS, TURN_TIME_GRACE},
+    peer::{read_message, write_message, Desync, GhostMessage, Hello, Replica, Snapshot, Update},
+    replay::Replay,
+    Direction,
+};
 
 use super::{CommonInfo, Idle, Message, MessageEnum, Shell};
+use crate::client::{
+    replay::{save_desync_report, save_replay},
+    turn::{show_latencies, TurnEnd, TurnInput},
+};
+
+const RECV_TIMEOUT: Duration = Duration::from_millis(33);
 
 pub struct Ghost {
     info: CommonInfo,
     user: String,
     pacman_user: String,
     stream: TcpStream,
+    reader: BufReader<TcpStream>, // Every message from pacman is a line
+    last_game: Option<Game>,      // Last known state of the match, saved as a replay
+    rules: GameRules,             // Announced by the server, the pacman must follow them
+    replica: Option<Replica>,     // Our copy, built from the first snapshot and kept up to date
     latencies: Vec<(Duration, String)>,
 }
 
@@ -22,17 +39,30 @@ impl Ghost {
         user: String,
         pacman_addr: SocketAddr,
         pacman_user: String,
-    ) {
+        rules: GameRules,
+        ticket: u64,
+    ) -> Idle {
         if let Ok(mut stream) = TcpStream::connect(pacman_addr) {
-            stream
-                .set_read_timeout(Some(Duration::from_secs(60)))
-                .unwrap();
-            stream.write_all(user.as_bytes()).unwrap();
+            // Pacman and every other ghost may use their whole turn before ours
+            let timeout = rules.turn_time.map_or(Duration::from_secs(60), |secs| {
+                (Duration::from_secs(secs) + TURN_TIME_GRACE) * (MAX_REMOTE_GHOSTS as u32 + 1)
+            });
+            stream.set_read_timeout(Some(timeout)).unwrap();
+            let hello = Hello::Ghost {
+                user: user.clone(),
+                ticket,
+            };
+            write_message(&mut stream, &hello).unwrap();
             println!("Conectado ao Pacman com sucesso!");
+            let reader = BufReader::new(stream.try_clone().unwrap());
             Self {
                 info,
                 user,
                 stream,
+                reader,
+                last_game: None,
+                rules,
+                replica: None,
                 pacman_user,
                 latencies: Vec::new(),
             }
@@ -43,84 +73,155 @@ impl Ghost {
                 connection: info.connection,
                 message: MessageEnum::QuitGameRequest,
             });
-            let idle_client = Idle::new(info, user);
-            idle_client.run()
+            Idle::new(info, user)
+        }
+    }
+
+    fn save_replay(&self) {
+        if let Some(game) = &self.last_game {
+            save_replay(&Replay::from_game(game, &self.pacman_user), &self.user);
         }
     }
 
-    pub fn finish(self) {
+    pub fn finish(self) -> Idle {
         println!("Saindo do jogo!");
+        self.save_replay();
         drop(self.stream);
         self.info.server.send(Message {
             connection: self.info.connection,
             message: MessageEnum::QuitGameRequest,
         });
-        let idle_client = Idle::new(self.info, self.user);
-        idle_client.run()
+        Idle::new(self.info, self.user)
     }
 
-    pub fn fail(self) {
+    pub fn fail(self) -> Idle {
         println!("Falha no jogo P2P!");
+        self.save_replay();
         drop(self.stream);
         self.info.server.send(Message {
             connection: self.info.connection,
             message: MessageEnum::QuitGameRequest,
         });
-        let idle_client = Idle::new(self.info, self.user);
-        idle_client.run()
+        Idle::new(self.info, self.user)
+    }
+
+    /// Ends the match because our copy of the game diverged from the pacman's
+    fn end_desync(self, desync: Desync) -> Idle {
+        let game = self.last_game.clone().unwrap_or_default();
+        save_desync_report(&desync, &game, &self.user);
+        self.fail()
+    }
+
+    /// Builds our copy of the game from the first snapshot, which must carry the whole game and
+    /// follow the rules announced by the server
+    fn start_replica(&mut self, snapshot: &Snapshot) -> bool {
+        let Update::Game(game) = &snapshot.update else {
+            println!("Jogo inválido recebido de {}", &self.pacman_user);
+            return false;
+        };
+        if game.rules() != &self.rules {
+            println!("{} não está seguindo as regras anunciadas!", &self.pacman_user);
+            return false;
+        }
+        match Replica::new(game) {
+            Ok(replica) => self.replica = Some(replica),
+            Err(err) => println!("Jogo inválido recebido de {}: {err}", &self.pacman_user),
+        }
+        self.replica.is_some()
+    }
+
+    /// Applies the snapshot to our copy of the game
+    /// Returns the desync if the pacman ended the match because of one, or if the inputs received
+    /// don't lead to its hash, in which case the pacman is told about it
+    fn check_snapshot(&mut self, snapshot: &Snapshot) -> Option<Desync> {
+        if let Some(desync) = &snapshot.desync {
+            return Some(desync.clone());
+        }
+        // Built from the first snapshot before reaching here
+        let replica = self.replica.as_mut().unwrap();
+        let result = replica.check(snapshot);
+        self.last_game = Some(replica.game().clone());
+        let Err(received) = result else { return None; };
+        let desync = Desync {
+            turn: replica.game().turns(),
+            user: self.user.clone(),
+            expected: snapshot.hash,
+            received,
+        };
+        let _ = write_message(&mut self.stream, &GhostMessage::Desync(desync.clone()));
+        Some(desync)
     }
 
-    fn run(mut self) {
-        let mut buf = [0u8; 9001];
+    fn run(mut self) -> Idle {
+        let mut turn_input = TurnInput::new(&self.rules);
         loop {
             println!("Aguardando pelo turno de {}....", &self.pacman_user);
             let mut game: Game;
-            let Ok(amt) = self.stream.read(&mut buf) else { return self.fail(); };
-            if amt == 0 {
-                println!("Conexão fechada!");
+            let spectators;
+            match read_message::<Snapshot>(&mut self.reader) {
+                Ok(Some(snapshot)) => {
+                    if self.replica.is_none() && !self.start_replica(&snapshot) {
+                        return self.fail();
+                    }
+                    if let Some(desync) = self.check_snapshot(&snapshot) {
+                        return self.end_desync(desync);
+                    }
+                    game = self.replica.as_ref().unwrap().game().clone();
+                    spectators = snapshot.spectators;
+                }
+                Ok(None) => {
+                    println!("Conexão fechada!");
+                    return self.fail();
+                }
+                Err(_) => return self.fail(),
             }
-            if let Ok(remote_game) = serde_json::from_str(std::str::from_utf8(&buf[..amt]).unwrap())
-            {
-                game = remote_game;
-            } else {
-                return self.fail();
+            if game.mode() == GameMode::RealTime {
+                return self.run_real_time(game, spectators);
             }
             game.show();
-            println!("Seu turno!");
+            println!("Espectadores: {spectators}");
             if game.game_over() {
                 return self.finish();
             }
+            let Some(id) = game.remote_ghost_id(&self.user) else { return self.fail(); };
+            println!("Seu turno!");
             let commands = ["move", "atraso", "encerra"];
             let shell = Shell::new(&commands, self.info.keep_running.clone());
-            loop {
-                let command = shell.prompt(&format!("{} - GHOST", &self.user));
-                if command.is_empty() {
-                    continue;
-                }
-                match command[0].as_str() {
-                    "move" => {
-                        let dir = command[1].chars().next().unwrap();
-                        game.move_remote_ghost(dir);
-                        break;
-                    }
-                    "atraso" => {
-                        if self.latencies.is_empty() {
-                            println!("Sem latências medidas!");
-                            continue;
-                        }
-                        let len = self.latencies.len().min(3);
-                        println!("Últimas latências:");
-                        println!("{:?}", &self.latencies[self.latencies.len() - len..]);
+            let bot_move = self.info.bot.as_mut().map(|bot| bot.ghost_move(&game, id));
+            let dir = if let Some(dir) = bot_move {
+                match dir.map(|dir| (dir, game.move_ghost(id, dir))) {
+                    Some((_, Err(err))) => {
+                        println!("Erro no jogo: {err}");
+                        return self.fail();
                     }
-                    "encerra" => {
-                        return self.finish();
+                    Some((dir, Ok(outcome))) if outcome != MoveOutcome::BlockedByWall => Some(dir),
+                    _ => None,
+                }
+            } else {
+                let latencies = &self.latencies;
+                let turn = turn_input.play(
+                    &shell,
+                    &format!("{} - GHOST", &self.user),
+                    |dir| dir.map_or(Ok(MoveOutcome::Moved), |dir| game.move_ghost(id, dir)),
+                    |_atraso| show_latencies(latencies),
+                );
+                match turn {
+                    Ok(TurnEnd::Played(dir, _)) => dir,
+                    Ok(TurnEnd::Quit | TurnEnd::Forfeit) => return self.finish(),
+                    Err(err) => {
+                        println!("Erro no jogo: {err}");
+                        return self.fail();
                     }
-                    _ => unreachable!(),
                 }
-            }
-            let game_str = serde_json::to_string(&game).unwrap();
+            };
+            // Pacman applies our move to its own copy of the game and compares the hashes
+            self.last_game = Some(game.clone());
             let start = current_time();
-            if self.stream.write_all(game_str.as_bytes()).is_err() {
+            let turn = GhostMessage::Turn {
+                direction: dir,
+                hash: game.state_hash(),
+            };
+            if write_message(&mut self.stream, &turn).is_err() {
                 return self.fail();
             }
             self.latencies
@@ -130,4 +231,98 @@ impl Ghost {
             }
         }
     }
+
+    /// Pacman sends a snapshot of the game on every tick, we send our direction whenever it
+    /// changes
+    fn run_real_time(mut self, mut game: Game, mut spectators: usize) -> Idle {
+        let Ok(stream) = self.stream.try_clone() else { return self.fail(); };
+        let _ = stream.set_read_timeout(None);
+        let (send, snapshots) = channel();
+        // The reader may have already buffered the next snapshots, so it moves to the thread
+        let mut reader = std::mem::replace(&mut self.reader, BufReader::new(stream));
+        std::thread::spawn(move || {
+            while let Ok(Some(snapshot)) = read_message::<Snapshot>(&mut reader) {
+                if send.send(snapshot).is_err() {
+                    return;
+                }
+            }
+        });
+        let commands = ["move", "atraso", "encerra"];
+        let shell = Shell::new(&commands, self.info.keep_running.clone());
+        loop {
+            let Some(id) = game.remote_ghost_id(&self.user) else { return self.fail(); };
+            let direction = game
+                .ghosts()
+                .iter()
+                .find(|ghost| ghost.id == id)
+                .and_then(|ghost| ghost.direction);
+            if let Some(bot) = self.info.bot.as_mut() {
+                let dir = bot.strategy.ghost_move(&game, id);
+                if dir.is_some_and(|dir| Some(dir) != direction)
+                    && write_message(&mut self.stream, &GhostMessage::Direction(dir.unwrap()))
+                        .is_err()
+                {
+                    return self.fail();
+                }
+            }
+            print!("\x1B[2J\x1B[H");
+            game.show();
+            println!("Espectadores: {spectators}");
+            println!(
+                "Tick {} - direção atual: {} (move <direcao (wasd)> para mudar)",
+                game.ticks(),
+                direction.map_or('-', |dir| dir.key())
+            );
+            if game.game_over() {
+                return self.finish();
+            }
+            loop {
+                if let Some(command) = shell.poll() {
+                    match command[0].as_str() {
+                        "move" => {
+                            let dir =
+                                Direction::from_key(command[1].chars().next().unwrap()).unwrap();
+                            let start = current_time();
+                            if write_message(&mut self.stream, &GhostMessage::Direction(dir))
+                                .is_err()
+                            {
+                                return self.fail();
+                            }
+                            self.latencies
+                                .push((current_time() - start, self.pacman_user.clone()));
+                        }
+                        "atraso" => show_latencies(&self.latencies),
+                        "encerra" => {
+                            return self.finish();
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                match snapshots.recv_timeout(RECV_TIMEOUT) {
+                    Ok(snapshot) => {
+                        if let Some(desync) = self.check_snapshot(&snapshot) {
+                            return self.end_desync(desync);
+                        }
+                        // The pacman compares it with the hash of its own copy at this tick
+                        // It may have closed the connection after the last tick, the reader
+                        // finds out if it did earlier
+                        let replica = self.replica.as_ref().unwrap();
+                        let hash = GhostMessage::Hash {
+                            tick: replica.game().ticks(),
+                            hash: replica.hash(),
+                        };
+                        let _ = write_message(&mut self.stream, &hash);
+                        game = replica.game().clone();
+                        spectators = snapshot.spectators;
+                        break;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        println!("Conexão fechada!");
+                        return self.fail();
+                    }
+                }
+            }
+        }
+    }
 }
@@ -1,17 +1,46 @@
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::{
+    net::{Ipv4Addr, SocketAddrV4, TcpListener},
+    time::Duration,
+};
 
 use pacman_communication::{
-    client_server::{CreateGameRequest, JoinGameRequest, ChangePasswordRequest},
-    server_client::{CreateGameResponse, JoinGameResponse, ChangePasswordResponse},
+    client_server::{
+        ChangePasswordRequest, CreateGameRequest, JoinGameRequest, ProfileRequest,
+        SpectateGameRequest,
+    },
+    game::GameRules,
+    server_client::{
+        ChangePasswordResponse, CreateGameResponse, JoinGameResponse, ProfileResponse,
+        RatingLeaderboardResponse, SpectateGameResponse,
+    },
 };
 
-use crate::client::states::{ghost::Ghost, pacman::Pacman};
+use crate::client::{
+    leaderboard::{parse_query, parse_ranking, show_page, show_rating_page},
+    profile::show_profile,
+    replay::play_replay,
+    shell::parse_rules,
+    states::{ghost::Ghost, pacman::Pacman, spectator::Spectator},
+};
 
 use super::{
-    watch, CommonInfo, Connected, ConnectedUsersResponse, LeaderboardResponse, Message,
+    show_locked, watch, CommonInfo, Connected, ConnectedUsersResponse, LeaderboardResponse, Message,
     MessageEnum, Ordering, ServerMessage, Shell, WatchErr,
 };
 
+/// Time a bot waits before trying to start a game again
+const BOT_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How an attempt to play a game from the idle state ended
+enum Attempt {
+    /// The game was played, the client is idle again
+    Played(Idle),
+    /// The server didn't let the game start
+    Refused(Idle),
+    /// The connection to the server was lost
+    Disconnected,
+}
+
 pub struct Idle {
     info: CommonInfo,
     user: String,
@@ -23,8 +52,14 @@ impl Idle {
         Self { info, user }
     }
 
-    pub fn run(self) {
-        let commands = ["senha", "lideres", "l", "inicia", "desafio", "sai", "tchau"];
+    pub fn run(mut self) {
+        if self.info.bot.is_some() {
+            return self.run_bot();
+        }
+        let commands = [
+            "senha", "lideres", "l", "perfil", "ranking", "inicia", "desafio", "assiste", "replay",
+            "sai", "tchau",
+        ];
 
         let shell = Shell::new(&commands, self.info.keep_running.clone());
         loop {
@@ -46,10 +81,12 @@ impl Idle {
                     }) {
                         Ok(msg) => {
                             let ServerMessage::ChangePasswordResponse(response) = msg else { unreachable!() };
-                            if let ChangePasswordResponse::Ok = response {
-                                println!("Senha mudada com sucesso!");
-                            } else {
-                                println!("Mudança de senha rejeitada pelo servidor!");
+                            match response {
+                                ChangePasswordResponse::Ok => println!("Senha mudada com sucesso!"),
+                                ChangePasswordResponse::Locked(until) => show_locked(until),
+                                ChangePasswordResponse::Err => {
+                                    println!("Mudança de senha rejeitada pelo servidor!");
+                                }
                             }
                         }
                         Err(WatchErr::Timeout) => {
@@ -61,14 +98,24 @@ impl Idle {
                 "lideres" => {
                     self.info.server.send(Message {
                         connection: self.info.connection,
-                        message: MessageEnum::LeaderboardRequest,
+                        message: MessageEnum::LeaderboardRequest(
+                            parse_query(&command[1..]).unwrap(),
+                        ),
                     });
                     match watch(&self.info.recv, |msg| -> bool {
                         matches!(msg, ServerMessage::LeaderboardResponse(_))
                     }) {
                         Ok(msg) => {
-                            let ServerMessage::LeaderboardResponse(LeaderboardResponse{top10}) = msg else { unreachable!() };
-                            println!("Lideres: {top10:?}");
+                            let ServerMessage::LeaderboardResponse(response) = msg else { unreachable!() };
+                            match response {
+                                LeaderboardResponse::Ok(page) => show_page(&page),
+                                LeaderboardResponse::UnknownSeason => {
+                                    println!("Essa temporada não existe!");
+                                }
+                                LeaderboardResponse::Err => {
+                                    println!("Servidor não conseguiu ler os líderes!");
+                                }
+                            }
                         }
                         Err(WatchErr::Timeout) => {
                             println!("Timeout esperando pelo servidor!");
@@ -88,7 +135,35 @@ impl Idle {
                             let ServerMessage::ConnectedUsersResponse(ConnectedUsersResponse { users }) = msg else { unreachable!() };
                             println!("Usuários online:");
                             for user in users.iter() {
-                                println!("- {user}");
+                                println!(
+                                    "- {} [Pacman {:.0}, fantasma {:.0}]",
+                                    user.description, user.ratings.pacman, user.ratings.ghost
+                                );
+                            }
+                        }
+                        Err(WatchErr::Timeout) => {
+                            println!("Timeout esperando pelo servidor!");
+                        }
+                        Err(WatchErr::Disconnection) => return,
+                    }
+                }
+                "perfil" => {
+                    let user = command.get(1).unwrap_or(&self.user).clone();
+                    self.info.server.send(Message {
+                        connection: self.info.connection,
+                        message: MessageEnum::ProfileRequest(ProfileRequest { user }),
+                    });
+                    match watch(&self.info.recv, |msg| -> bool {
+                        matches!(msg, ServerMessage::ProfileResponse(_))
+                    }) {
+                        Ok(msg) => {
+                            let ServerMessage::ProfileResponse(response) = msg else { unreachable!() };
+                            match response {
+                                ProfileResponse::Ok(user, stats) => show_profile(&user, &stats),
+                                ProfileResponse::UnknownUser => println!("Usuário não existe!"),
+                                ProfileResponse::Err => {
+                                    println!("Servidor não conseguiu ler o perfil!");
+                                }
                             }
                         }
                         Err(WatchErr::Timeout) => {
@@ -97,6 +172,35 @@ impl Idle {
                         Err(WatchErr::Disconnection) => return,
                     }
                 }
+                "ranking" => {
+                    self.info.server.send(Message {
+                        connection: self.info.connection,
+                        message: MessageEnum::RatingLeaderboardRequest(
+                            parse_ranking(&command[1..]).unwrap(),
+                        ),
+                    });
+                    match watch(&self.info.recv, |msg| -> bool {
+                        matches!(msg, ServerMessage::RatingLeaderboardResponse(_))
+                    }) {
+                        Ok(msg) => {
+                            let ServerMessage::RatingLeaderboardResponse(response) = msg else { unreachable!() };
+                            match response {
+                                RatingLeaderboardResponse::Ok(page) => show_rating_page(&page),
+                                RatingLeaderboardResponse::Err => {
+                                    println!("Servidor não conseguiu ler o ranking!");
+                                }
+                            }
+                        }
+                        Err(WatchErr::Timeout) => {
+                            println!("Timeout esperando pelo servidor!");
+                        }
+                        Err(WatchErr::Disconnection) => return,
+                    }
+                }
+                "replay" => {
+                    let speed = command.get(2).and_then(|speed| speed.parse().ok());
+                    play_replay(&command[1], speed, self.info.keep_running.clone());
+                }
                 "tchau" => {
                     self.info.server.send(Message {
                         connection: self.info.connection,
@@ -105,29 +209,33 @@ impl Idle {
                     self.info.keep_running.store(false, Ordering::Relaxed);
                     return;
                 }
-                "desafio" => {
+                "desafio" => match self.challenge(&command[1]) {
+                    Attempt::Played(idle) | Attempt::Refused(idle) => self = idle,
+                    Attempt::Disconnected => return,
+                },
+                "assiste" => {
                     let pacman = command[1].as_str();
                     self.info.server.send(Message {
                         connection: self.info.connection,
-                        message: MessageEnum::JoinGameRequest(JoinGameRequest {
+                        message: MessageEnum::SpectateGameRequest(SpectateGameRequest {
                             pacman: pacman.to_owned(),
                         }),
                     });
                     match watch(&self.info.recv, |msg| -> bool {
-                        matches!(msg, ServerMessage::JoinGameResponse(_))
+                        matches!(msg, ServerMessage::SpectateGameResponse(_))
                     }) {
                         Ok(msg) => {
-                            let ServerMessage::JoinGameResponse(response) = msg else { unreachable!() };
-                            if let JoinGameResponse::Ok(pacman_addr) = response {
-                                println!("Servidor aceitou o desafio!");
-                                return Ghost::new_and_run(
+                            let ServerMessage::SpectateGameResponse(response) = msg else { unreachable!() };
+                            if let SpectateGameResponse::Ok(pacman_addr, ticket) = response {
+                                self = Spectator::new_and_run(
                                     self.info,
                                     self.user,
                                     pacman_addr,
                                     pacman.to_owned(),
+                                    ticket,
                                 );
                             } else {
-                                println!("Servidor rejeitou o desafio!");
+                                println!("Servidor não permitiu assistir o jogo!");
                             }
                         }
                         Err(WatchErr::Timeout) => {
@@ -155,36 +263,123 @@ impl Idle {
                         Err(WatchErr::Disconnection) => return,
                     }
                 }
-                "inicia" => {
-                    let listener =
-                        TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
-                    let addr = listener.local_addr().unwrap();
-                    self.info.server.send(Message {
-                        connection: self.info.connection,
-                        message: MessageEnum::CreateGameRequest(CreateGameRequest {
-                            listener_addr: addr,
-                        }),
-                    });
-                    match watch(&self.info.recv, |msg| -> bool {
-                        matches!(msg, ServerMessage::CreateGameResponse(_))
-                    }) {
-                        Ok(msg) => {
-                            let ServerMessage::CreateGameResponse(response) = msg else { unreachable!(); };
-                            if let CreateGameResponse::Ok = response {
-                                println!("Created game with success");
-                                let pacman_client = Pacman::new(self.info, self.user, listener);
-                                return pacman_client.run();
-                            } else {
-                                println!("Couldn't create a game!");
-                            }
-                        }
-                        Err(WatchErr::Timeout) => {
-                            println!("Timeout esperando pelo servidor!");
-                        }
-                        Err(WatchErr::Disconnection) => return,
+                "inicia" => match self.start_game(parse_rules(&command[1..]).unwrap()) {
+                    Attempt::Played(idle) | Attempt::Refused(idle) => self = idle,
+                    Attempt::Disconnected => return,
+                },
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Asks the server to join the game of `pacman` and plays it
+    fn challenge(self, pacman: &str) -> Attempt {
+        self.info.server.send(Message {
+            connection: self.info.connection,
+            message: MessageEnum::JoinGameRequest(JoinGameRequest {
+                pacman: pacman.to_owned(),
+            }),
+        });
+        match watch(&self.info.recv, |msg| -> bool {
+            matches!(msg, ServerMessage::JoinGameResponse(_))
+        }) {
+            Ok(msg) => {
+                let ServerMessage::JoinGameResponse(response) = msg else { unreachable!() };
+                if let JoinGameResponse::Ok(pacman_addr, rules, ticket) = response {
+                    println!("Servidor aceitou o desafio!");
+                    println!("Regras do jogo: {rules}");
+                    return Attempt::Played(Ghost::new_and_run(
+                        self.info,
+                        self.user,
+                        pacman_addr,
+                        pacman.to_owned(),
+                        rules,
+                        ticket,
+                    ));
+                }
+                println!("Servidor rejeitou o desafio!");
+            }
+            Err(WatchErr::Timeout) => {
+                println!("Timeout esperando pelo servidor!");
+            }
+            Err(WatchErr::Disconnection) => return Attempt::Disconnected,
+        }
+        Attempt::Refused(self)
+    }
+
+    /// Asks the server to create a game with `rules` and hosts it
+    fn start_game(self, rules: GameRules) -> Attempt {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        self.info.server.send(Message {
+            connection: self.info.connection,
+            message: MessageEnum::CreateGameRequest(CreateGameRequest {
+                listener_addr: addr,
+                rules: rules.clone(),
+            }),
+        });
+        match watch(&self.info.recv, |msg| -> bool {
+            matches!(msg, ServerMessage::CreateGameResponse(_))
+        }) {
+            Ok(msg) => {
+                let ServerMessage::CreateGameResponse(response) = msg else { unreachable!(); };
+                if let CreateGameResponse::Ok(seed) = response {
+                    println!("Created game with success");
+                    println!("Regras do jogo: {rules}");
+                    let pacman_client = Pacman::new(self.info, self.user, listener, rules, seed);
+                    return Attempt::Played(pacman_client.run());
+                }
+                println!("Couldn't create a game!");
+            }
+            Err(WatchErr::Timeout) => {
+                println!("Timeout esperando pelo servidor!");
+            }
+            Err(WatchErr::Disconnection) => return Attempt::Disconnected,
+        }
+        Attempt::Refused(self)
+    }
+
+    /// Hosts or challenges games until it played as many as it should, then disconnects
+    fn run_bot(mut self) {
+        loop {
+            if !self.info.keep_running.load(Ordering::Relaxed) {
+                return;
+            }
+            let bot = self.info.bot.as_mut().unwrap();
+            let games_played = bot.games_played.load(Ordering::Relaxed);
+            if bot.max_games.is_some_and(|max| games_played >= max) {
+                println!("Bot jogou {games_played} jogos, encerrando!");
+                self.info.server.send(Message {
+                    connection: self.info.connection,
+                    message: MessageEnum::Disconnect,
+                });
+                self.info.keep_running.store(false, Ordering::Relaxed);
+                return;
+            }
+            let attempt = match bot.challenge.clone() {
+                Some(pacman) => self.challenge(&pacman),
+                None => {
+                    let rules = bot.rules.clone();
+                    self.start_game(rules)
+                }
+            };
+            match attempt {
+                Attempt::Played(idle) => {
+                    self = idle;
+                    let bot = self.info.bot.as_mut().unwrap();
+                    bot.games_played.fetch_add(1, Ordering::Relaxed);
+                    // The pacman may still be in the game that just ended, give it time to
+                    // start the next one
+                    if bot.challenge.is_some() {
+                        std::thread::sleep(BOT_RETRY_INTERVAL);
                     }
                 }
-                _ => unreachable!(),
+                Attempt::Refused(idle) => {
+                    // Nothing was played, try again later
+                    self = idle;
+                    std::thread::sleep(BOT_RETRY_INTERVAL);
+                }
+                Attempt::Disconnected => return,
             }
         }
     }
@@ -1,193 +1,641 @@
 use std::{
-    io::{Read, Write},
+    collections::VecDeque,
+    io::{BufReader, ErrorKind},
     net::{TcpListener, TcpStream},
-    sync::{atomic::Ordering, Mutex},
-    time::Duration,
+    sync::{
+        atomic::Ordering,
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use pacman_communication::{current_time, game::Game, LeaderboardEntry};
-use rand::seq::SliceRandom;
+use pacman_communication::{
+    client_server::GameResult,
+    current_time,
+    game::{
+        Game, GameError, GameMode, GameRules, GhostController, MoveOutcome, TICK_INTERVAL,
+        TURN_TIME_GRACE,
+    },
+    peer::{read_message, write_message, Desync, GhostMessage, Hello, Snapshot, TICKET_LIFETIME},
+    replay::Replay,
+    server_client::ResultResponse,
+    Connection, Direction,
+};
 
-use super::{Arc, AtomicBool, CommonInfo, Idle, Message, MessageEnum, Shell};
+use super::{
+    watch, Arc, AtomicBool, CommonInfo, Idle, Message, MessageEnum, ServerMessage, Shell,
+};
+use crate::client::{
+    replay::{save_desync_report, save_replay},
+    Tickets,
+    turn::{show_latencies, show_outcome, TurnEnd, TurnInput},
+};
 
 pub struct Pacman {
     info: CommonInfo,
     keep_running: Arc<AtomicBool>,
     user: String,
-    connection: Arc<Mutex<Option<(TcpStream, String)>>>,
+    rules: GameRules,
+    seed: u64,
+    connections: Peers, // One per remote ghost
+    spectators: Peers,
     latencies: Vec<(Duration, String)>,
 }
 
+const RECV_TIMEOUT: Duration = Duration::from_millis(33);
+
+/// How many of the last ticks the hashes sent by ghosts in a real-time game are checked against
+const HASH_HISTORY: usize = 256;
+
+/// How long a ghost that connected before its ticket arrived waits for it
+const TICKET_WAIT: Duration = Duration::from_secs(5);
+
+/// Longest a snapshot may take to be sent to a spectator
+const SPECTATOR_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Connection to a ghost or spectator. Its messages are read through `reader`, which keeps what
+/// arrived after the last message read; the thread reading a ghost in a real-time game takes it
+struct Peer {
+    stream: TcpStream,
+    reader: Option<BufReader<TcpStream>>,
+    user: String,
+    sent: Option<usize>, // Records of the history sent to the peer, None before the whole game
+}
+
+impl Peer {
+    fn new(stream: TcpStream, reader: BufReader<TcpStream>, user: String) -> Self {
+        Self {
+            stream,
+            reader: Some(reader),
+            user,
+            sent: None,
+        }
+    }
+
+    /// Snapshot with what the peer doesn't have of the game yet
+    fn snapshot(&mut self, game: &Game, spectators: usize) -> Snapshot {
+        let snapshot = Snapshot::new(game, self.sent, spectators);
+        self.sent = Some(game.history().len());
+        snapshot
+    }
+}
+
+type Peers = Arc<Mutex<Vec<Peer>>>;
+
+/// Reads who a peer that connected to the listener is and lets it into the game if it may
+fn admit(
+    stream: TcpStream,
+    tickets: &Tickets,
+    connections: &Peers,
+    spectators: &Peers,
+    max_ghosts: usize,
+) {
+    let _ = stream.set_nonblocking(false);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(60)))
+        .unwrap();
+    let Ok(reader) = stream.try_clone() else { return; };
+    let mut reader = BufReader::new(reader);
+    // Start of connection: peer should say who it is
+    let Ok(Some(hello)) = read_message::<Hello>(&mut reader) else { return; };
+    match hello {
+        Hello::Ghost {
+            user: ghost_user,
+            ticket,
+        } => {
+            // Only ghosts the server let join
+            if !redeem_ticket(tickets, &ghost_user, ticket, false) {
+                println!("Conexão de {ghost_user} recusada: convite inválido");
+                return;
+            }
+            let mut conns = connections.lock().unwrap();
+            if conns.len() < max_ghosts && conns.iter().all(|peer| peer.user != ghost_user) {
+                println!("Aceitando desafio de {ghost_user}");
+                conns.push(Peer::new(stream, reader, ghost_user));
+            }
+            drop(conns);
+        }
+        Hello::Spectator {
+            user: spectator,
+            ticket,
+        } => {
+            if !redeem_ticket(tickets, &spectator, ticket, true) {
+                println!("Conexão de {spectator} recusada: convite inválido");
+                return;
+            }
+            // A spectator that can't keep up is dropped instead of stalling the game
+            stream
+                .set_write_timeout(Some(SPECTATOR_WRITE_TIMEOUT))
+                .unwrap();
+            println!("{spectator} está assistindo o jogo");
+            spectators.lock().unwrap().push(Peer::new(stream, reader, spectator));
+        }
+    }
+}
+
+/// Takes the ticket the server gave to `user` to play as a ghost or to watch the game, waiting up
+/// to `TICKET_WAIT` for it to arrive. Each ticket is used once
+fn redeem_ticket(tickets: &Tickets, user: &str, ticket: u64, spectator: bool) -> bool {
+    let deadline = Instant::now() + TICKET_WAIT;
+    loop {
+        let mut tickets = tickets.lock().unwrap();
+        let now = current_time();
+        tickets.retain(|(_, arrival)| now.saturating_sub(*arrival) < TICKET_LIFETIME);
+        let valid = tickets.iter().position(|(join_ticket, _)| {
+            join_ticket.user == user
+                && join_ticket.ticket == ticket
+                && join_ticket.spectator == spectator
+        });
+        if let Some(idx) = valid {
+            tickets.remove(idx);
+            return true;
+        }
+        drop(tickets);
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(RECV_TIMEOUT);
+    }
+}
+
 impl Pacman {
     #[must_use]
-    pub fn new(info: CommonInfo, user: String, listener: TcpListener) -> Self {
+    pub fn new(
+        info: CommonInfo,
+        user: String,
+        listener: TcpListener,
+        rules: GameRules,
+        seed: u64,
+    ) -> Self {
         let keep_running = Arc::new(AtomicBool::new(true));
         let keep_running1 = keep_running.clone();
-        let connection = Arc::new(Mutex::new(None));
-        let connection1 = connection.clone();
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let connections1 = connections.clone();
+        let spectators = Arc::new(Mutex::new(Vec::new()));
+        let spectators1 = spectators.clone();
+        let max_ghosts = rules.max_remote_ghosts;
+        let tickets = info.tickets.clone();
+        tickets.lock().unwrap().clear();
         std::thread::spawn(move || {
             listener.set_nonblocking(true).unwrap();
             while keep_running1.load(Ordering::Relaxed) {
                 std::thread::sleep(Duration::from_millis(33));
-                if let Ok((mut stream, _)) = listener.accept() {
-                    stream
-                        .set_read_timeout(Some(Duration::from_secs(60)))
-                        .unwrap();
-                    let mut buf = [0u8; 9001];
-                    let Ok(amt) = stream.read(&mut buf) else {
-                            continue;
-                        };
-                    // Start of connection: Ghost should send its user
-                    let Ok(ghost_user) = std::str::from_utf8(&buf[..amt]) else { continue; };
-                    let mut conn = connection1.lock().unwrap();
-                    if conn.is_none() {
-                        println!("Aceitando desafio de {ghost_user}");
-                        *conn = Some((stream, ghost_user.to_owned()));
-                    }
-                    drop(conn);
-                }
+                let Ok((stream, _)) = listener.accept() else { continue; };
+                // A ghost may have to wait for its ticket, the others are let in meanwhile
+                let tickets = tickets.clone();
+                let connections = connections1.clone();
+                let spectators = spectators1.clone();
+                std::thread::spawn(move || {
+                    admit(stream, &tickets, &connections, &spectators, max_ghosts);
+                });
             }
         });
         Self {
             info,
             keep_running,
             user,
-            connection,
+            rules,
+            seed,
+            connections,
+            spectators,
             latencies: Vec::new(),
         }
     }
 
-    pub fn fail(self) {
+    pub fn fail(self) -> Idle {
         println!("Falha no jogo P2P!");
-        let mut conn = self.connection.lock().unwrap();
-        if let Some((stream, _)) = conn.as_mut() {
-            let _ = stream.shutdown(std::net::Shutdown::Both);
-        }
-        drop(conn);
+        self.close_connections();
         self.info.server.send(Message {
             connection: self.info.connection,
             message: MessageEnum::QuitGameRequest,
         });
         self.keep_running.store(false, Ordering::Relaxed);
-        let idle_client = Idle::new(self.info, self.user);
-        idle_client.run()
+        Idle::new(self.info, self.user)
     }
 
-    pub fn finish(self, game: Game) {
+    pub fn finish(self, game: Game) -> Idle {
         println!("Jogo P2P encerrado com pontuação {}!", game.score());
-        let mut conn = self.connection.lock().unwrap();
-        if let Some((stream, _)) = conn.as_mut() {
-            let _ = stream.shutdown(std::net::Shutdown::Both);
-        }
-        drop(conn);
-        self.info.server.send(Message {
+        save_replay(&Replay::from_game(&game, &self.user), &self.user);
+        self.close_connections();
+        self.submit_result(&game);
+        self.keep_running.store(false, Ordering::Relaxed);
+        Idle::new(self.info, self.user)
+    }
+
+    /// Sends the result of the game to the leaderboard and tells the player whether the server
+    /// took it. Results carry every input, so they go through TCP whatever the connection is
+    fn submit_result(&self, game: &Game) {
+        let result = GameResult {
+            score: game.score(),
+            hash: game.state_hash(),
+            seed: game.seed(),
+            map: game.map().clone(),
+            inputs: game.history().iter().map(|record| record.input.clone()).collect(),
+        };
+        let server = Connection::Tcp(self.info.server.addr());
+        let message = Message {
             connection: self.info.connection,
-            message: MessageEnum::AddLeaderboardEntry(LeaderboardEntry {
-                score: game.score(),
-                user: self.user.clone(),
-            }),
+            message: MessageEnum::AddLeaderboardEntry(result),
+        };
+        if let Err(err) = server.try_send(message) {
+            println!("Não foi possível enviar o resultado ao servidor: {err}");
+            return;
+        }
+        match watch(&self.info.recv, |msg| matches!(msg, ServerMessage::ResultResponse(_))) {
+            Ok(ServerMessage::ResultResponse(ResultResponse::Ok)) => {
+                println!("Resultado registrado no placar!");
+            }
+            Ok(ServerMessage::ResultResponse(ResultResponse::Rejected(reason))) => {
+                println!("Resultado recusado pelo servidor: {reason}");
+            }
+            Ok(_) => unreachable!(),
+            Err(err) => println!("O servidor não confirmou o resultado: {err}"),
+        }
+    }
+
+    /// Ends the match because a copy of the game diverged from ours, every peer is told why
+    pub fn end_desync(self, game: &Game, desync: Desync) -> Idle {
+        save_desync_report(&desync, game, &self.user);
+        let mut spectators = self.spectators.lock().unwrap();
+        let count = spectators.len();
+        let mut conns = self.connections.lock().unwrap();
+        for peer in conns.iter_mut().chain(spectators.iter_mut()) {
+            let mut snapshot = peer.snapshot(game, count);
+            snapshot.desync = Some(desync.clone());
+            let _ = write_message(&mut peer.stream, &snapshot);
+        }
+        drop(conns);
+        drop(spectators);
+        self.fail()
+    }
+
+    fn close_connections(&self) {
+        let conns = self.connections.lock().unwrap();
+        for peer in conns.iter() {
+            let _ = peer.stream.shutdown(std::net::Shutdown::Both);
+        }
+        drop(conns);
+        let spectators = self.spectators.lock().unwrap();
+        for peer in spectators.iter() {
+            let _ = peer.stream.shutdown(std::net::Shutdown::Both);
+        }
+        drop(spectators);
+    }
+
+    fn show(&self, game: &Game) {
+        game.show();
+        println!("Espectadores: {}", self.spectators.lock().unwrap().len());
+    }
+
+    /// Sends the state of the game to every spectator, dropping the ones that left
+    fn update_spectators(&self, game: &Game) {
+        let mut spectators = self.spectators.lock().unwrap();
+        let count = spectators.len();
+        spectators.retain_mut(|peer| {
+            let snapshot = peer.snapshot(game, count);
+            write_message(&mut peer.stream, &snapshot).is_ok()
         });
-        self.keep_running.store(false, Ordering::Relaxed);
-        let idle_client = Idle::new(self.info, self.user);
-        idle_client.run()
+        drop(spectators);
+    }
+
+    /// Sends the state of the game to every remote ghost and spectator
+    /// Returns the ghosts whose connection failed
+    fn broadcast(&mut self, game: &Game) -> Vec<String> {
+        let spectators = self.spectators.lock().unwrap().len();
+        let mut failed = Vec::new();
+        let mut conns = self.connections.lock().unwrap();
+        for peer in conns.iter_mut() {
+            let snapshot = peer.snapshot(game, spectators);
+            let start = current_time();
+            if write_message(&mut peer.stream, &snapshot).is_err() {
+                failed.push(peer.user.clone());
+            } else {
+                self.latencies.push((current_time() - start, peer.user.clone()));
+            }
+        }
+        drop(conns);
+        self.update_spectators(game);
+        failed
+    }
+
+    /// Sends the game to the remote ghost `ghost_user` and applies the direction it chooses
+    /// Connections that fail are dropped and their ghost removed from the game
+    /// Returns the desync if the ghost's copy of the game no longer matches ours
+    fn remote_turn(&mut self, game: &mut Game, ghost_user: &str) -> Option<Desync> {
+        let spectators = self.spectators.lock().unwrap().len();
+        let mut conns = self.connections.lock().unwrap();
+        let Some(idx) = conns.iter().position(|peer| peer.user == ghost_user) else {
+            game.remove_remote_ghost(ghost_user);
+            return None;
+        };
+        let peer = &mut conns[idx];
+        println!("Esperando pelo turno de {ghost_user}");
+        let timeout = self.rules.turn_time.map_or(Duration::from_secs(60), |secs| {
+            Duration::from_secs(secs) + TURN_TIME_GRACE
+        });
+        let _ = peer.stream.set_read_timeout(Some(timeout));
+        let mut desync = None;
+        let snapshot = peer.snapshot(game, spectators);
+        let start = current_time();
+        let mut ok = false;
+        if write_message(&mut peer.stream, &snapshot).is_err() {
+            println!("Erro de conexão com o usuário {ghost_user}");
+        } else {
+            let latency = current_time() - start;
+            self.latencies.push((latency, ghost_user.to_owned()));
+            let msg = match peer.reader.as_mut() {
+                Some(reader) => read_message::<GhostMessage>(reader),
+                None => Ok(None),
+            };
+            match msg {
+                Ok(None) => println!("Conexão fechada!"),
+                Err(_) => println!("Erro de conexão com o usuário {ghost_user}"),
+                Ok(Some(msg)) => {
+                    let id = game.remote_ghost_id(ghost_user).unwrap();
+                    match msg {
+                        // No direction: the ghost stood still
+                        GhostMessage::Turn { direction, hash } => {
+                            match direction
+                                .map_or(Ok(MoveOutcome::Moved), |dir| game.move_ghost(id, dir))
+                            {
+                                Ok(_) => ok = true,
+                                Err(err) => println!("Movimento inválido de {ghost_user}: {err}"),
+                            }
+                            if ok && hash != game.state_hash() {
+                                desync = Some(Desync {
+                                    turn: game.turns(),
+                                    user: ghost_user.to_owned(),
+                                    expected: game.state_hash(),
+                                    received: hash,
+                                });
+                            }
+                        }
+                        GhostMessage::Desync(ghost_desync) => desync = Some(ghost_desync),
+                        GhostMessage::Direction(_) | GhostMessage::Hash { .. } => {
+                            println!("Movimento inválido de {ghost_user}: jogo por turnos");
+                        }
+                    }
+                }
+            }
+        }
+        if !ok && desync.is_none() {
+            conns.remove(idx);
+            game.remove_remote_ghost(ghost_user);
+        }
+        drop(conns);
+        desync
+    }
+
+    /// Moves pacman in `dir`, or stands still if there is no direction or it is blocked
+    fn move_or_pass(game: &mut Game, dir: Option<Direction>) -> Result<MoveOutcome, GameError> {
+        let outcome = match dir {
+            Some(dir) => game.move_pacman(dir)?,
+            None => game.pass_pacman()?,
+        };
+        if outcome == MoveOutcome::BlockedByWall {
+            return game.pass_pacman();
+        }
+        Ok(outcome)
+    }
+
+    pub fn run(self) -> Idle {
+        match self.rules.mode {
+            GameMode::TurnBased => self.run_turn_based(),
+            GameMode::RealTime => self.run_real_time(),
+        }
     }
 
-    pub fn run(mut self) {
-        let mut game = Game::new();
+    fn run_turn_based(mut self) -> Idle {
+        let mut game = Game::with_rules(self.rules.clone(), self.seed).unwrap();
         game.show();
+        let mut turn_input = TurnInput::new(&self.rules);
         loop {
-            // Local ghost's turn
-            let mut array = ['w', 'a', 's', 'd'];
-            let mut rng = rand::thread_rng();
-            array.shuffle(&mut rng);
-            let random_dir = array[0];
-            game.move_local_ghost(random_dir);
-            if game.game_over() {
-                let mut conn = self.connection.lock().unwrap();
-                if let Some((stream, _)) = conn.as_mut() {
-                    let _ = stream.write_all(serde_json::to_string(&game).unwrap().as_bytes());
-                }
-                drop(conn);
-                return self.finish(game.clone());
-            }
-
-            // Remote ghost's turn
-            let mut conn = self.connection.lock().unwrap();
-            if let Some((stream, ghost_user)) = conn.as_mut() {
-                game.add_remote_ghost();
-                println!("Esperando pelo turno de {ghost_user}");
-                let game_str = serde_json::to_string(&game).unwrap();
-                let mut buf = [0u8; 9001];
-                let start = current_time();
-                if stream.write_all(game_str.as_bytes()).is_err() {
-                    println!("Erro de conexão com o usuário {ghost_user}");
-                    *conn = None;
-                } else {
-                    let latency = current_time() - start;
-                    self.latencies.push((latency, ghost_user.clone()));
-                    if let Ok(amt) = stream.read(&mut buf) {
-                        if amt == 0 {
-                            println!("Conexão fechada!");
-                            *conn = None;
-                        } else if let Ok(remote_game) =
-                            serde_json::from_str(std::str::from_utf8(&buf[..amt]).unwrap())
-                        {
-                            game = remote_game
-                        } else {
-                            println!("Erro de conexão com o usuário {ghost_user}");
-                            *conn = None;
+            // Ghosts that connected since the last round enter at the end of the turn order
+            let conns = self.connections.lock().unwrap();
+            for peer in conns.iter() {
+                game.add_remote_ghost(&peer.user);
+            }
+            drop(conns);
+
+            // Ghosts' turns, skipped in the rounds their speed doesn't allow
+            let turn_order: Vec<(usize, GhostController)> = if game.ghosts_move() {
+                game.ghosts()
+                    .iter()
+                    .map(|ghost| (ghost.id, ghost.controller.clone()))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            for (id, controller) in turn_order {
+                match controller {
+                    GhostController::Ai => {
+                        if let Err(err) = game.move_ai_ghost(id) {
+                            println!("Erro no jogo: {err}");
+                            return self.fail();
+                        }
+                    }
+                    GhostController::Remote(ghost_user) => {
+                        if let Some(desync) = self.remote_turn(&mut game, &ghost_user) {
+                            return self.end_desync(&game, desync);
                         }
-                    } else {
-                        println!("Erro de conexão com o usuário {ghost_user}");
-                        *conn = None;
                     }
                 }
-            } else {
-                *conn = None;
-            }
-            drop(conn);
-            if game.game_over() {
-                return self.finish(game);
+                self.update_spectators(&game);
+                if game.game_over() {
+                    let _ = self.broadcast(&game);
+                    return self.finish(game);
+                }
             }
 
             // Our turn
-            game.show();
+            self.show(&game);
             println!("Seu turno!");
             let commands = ["move", "atraso", "encerra"];
             let shell = Shell::new(&commands, self.info.keep_running.clone());
+            if let Some(bot) = self.info.bot.as_mut() {
+                let dir = bot.pacman_move(&game);
+                if let Err(err) = Self::move_or_pass(&mut game, dir) {
+                    println!("Erro no jogo: {err}");
+                    return self.fail();
+                }
+                self.update_spectators(&game);
+                if game.game_over() {
+                    let _ = self.broadcast(&game);
+                    return self.finish(game);
+                }
+                continue;
+            }
+            let latencies = &self.latencies;
+            let turn = turn_input.play(
+                &shell,
+                &format!("{} - PACMAN", &self.user),
+                |dir| match dir {
+                    Some(dir) => game.move_pacman(dir),
+                    None => game.pass_pacman(),
+                },
+                |_atraso| show_latencies(latencies),
+            );
+            match turn {
+                Ok(TurnEnd::Played(_, outcome)) => {
+                    show_outcome(&game, outcome);
+                    self.update_spectators(&game);
+                }
+                Ok(TurnEnd::Quit) => return self.finish(game),
+                Ok(TurnEnd::Forfeit) => {
+                    game.forfeit();
+                    let _ = self.broadcast(&game);
+                    return self.finish(game);
+                }
+                Err(err) => {
+                    println!("Erro no jogo: {err}");
+                    return self.fail();
+                }
+            }
+            if game.game_over() {
+                let _ = self.broadcast(&game);
+                return self.finish(game);
+            }
+        }
+    }
+
+    /// Reads the messages sent by a remote ghost in a real-time game
+    /// Sends None when the connection is closed
+    fn spawn_ghost_reader(
+        mut reader: BufReader<TcpStream>,
+        ghost_user: String,
+        send: Sender<(String, Option<GhostMessage>)>,
+    ) {
+        std::thread::spawn(move || {
+            let _ = reader.get_ref().set_read_timeout(None);
             loop {
-                let command = shell.prompt(&format!("{} - PACMAN", &self.user));
-                if command.is_empty() {
+                match read_message(&mut reader) {
+                    Ok(None) => break,
+                    Ok(Some(msg)) => {
+                        if send.send((ghost_user.clone(), Some(msg))).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) if err.kind() == ErrorKind::InvalidData => continue,
+                    Err(_) => break,
+                }
+            }
+            let _ = send.send((ghost_user, None));
+        });
+    }
+
+    fn drop_ghost(&self, game: &mut Game, ghost_user: &str) {
+        println!("Conexão com o usuário {ghost_user} encerrada");
+        let mut conns = self.connections.lock().unwrap();
+        conns.retain(|peer| peer.user != ghost_user);
+        drop(conns);
+        game.remove_remote_ghost(ghost_user);
+    }
+
+    fn run_real_time(mut self) -> Idle {
+        let mut game = Game::with_rules(self.rules.clone(), self.seed).unwrap();
+        let (send, inputs) = channel();
+        let mut readers: Vec<String> = Vec::new();
+        let mut hashes: VecDeque<(u64, u64)> = VecDeque::new(); // Tick and hash of the snapshots
+        let commands = ["move", "atraso", "encerra"];
+        let shell = Shell::new(&commands, self.info.keep_running.clone());
+        let mut next_tick = current_time() + TICK_INTERVAL;
+        loop {
+            if !self.info.keep_running.load(Ordering::Relaxed) {
+                return self.fail();
+            }
+
+            // Ghosts that connected since the last tick
+            let mut conns = self.connections.lock().unwrap();
+            for peer in conns.iter_mut() {
+                if readers.contains(&peer.user) || game.add_remote_ghost(&peer.user).is_none() {
                     continue;
                 }
+                if let Some(reader) = peer.reader.take() {
+                    Self::spawn_ghost_reader(reader, peer.user.clone(), send.clone());
+                    readers.push(peer.user.clone());
+                }
+            }
+            drop(conns);
+
+            // Direction changes of remote ghosts
+            match inputs.recv_timeout(RECV_TIMEOUT) {
+                Ok((ghost_user, Some(GhostMessage::Direction(dir)))) => {
+                    if let Some(id) = game.remote_ghost_id(&ghost_user) {
+                        let _ = game.set_ghost_direction(id, dir);
+                    }
+                }
+                Ok((_, Some(GhostMessage::Desync(desync)))) => {
+                    return self.end_desync(&game, desync);
+                }
+                Ok((ghost_user, Some(GhostMessage::Hash { tick, hash }))) => {
+                    let expected = hashes.iter().find(|(t, _)| *t == tick).map(|(_, h)| *h);
+                    if let Some(expected) = expected.filter(|expected| *expected != hash) {
+                        let desync = Desync {
+                            turn: tick,
+                            user: ghost_user,
+                            expected,
+                            received: hash,
+                        };
+                        return self.end_desync(&game, desync);
+                    }
+                }
+                Ok((_, Some(GhostMessage::Turn { .. }))) => {}
+                Ok((ghost_user, None)) => {
+                    readers.retain(|user| user != &ghost_user);
+                    self.drop_ghost(&mut game, &ghost_user);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            }
+
+            // Our direction changes
+            if let Some(command) = shell.poll() {
                 match command[0].as_str() {
                     "move" => {
-                        let dir = command[1].chars().next().unwrap();
-                        game.move_pacman(dir);
-                        break;
-                    }
-                    "atraso" => {
-                        if self.latencies.is_empty() {
-                            println!("Sem latências medidas!");
-                            continue;
-                        }
-                        let len = self.latencies.len().min(3);
-                        println!("Últimas latências:");
-                        println!("{:?}", &self.latencies[self.latencies.len() - 1 - len..]);
+                        let dir = Direction::from_key(command[1].chars().next().unwrap()).unwrap();
+                        game.set_pacman_direction(dir);
                     }
+                    "atraso" => show_latencies(&self.latencies),
                     "encerra" => {
                         return self.finish(game);
                     }
                     _ => unreachable!(),
                 }
             }
-            if game.game_over() {
-                let mut conn = self.connection.lock().unwrap();
-                if let Some((stream, _)) = conn.as_mut() {
-                    let _ = stream.write_all(serde_json::to_string(&game).unwrap().as_bytes());
+
+            if current_time() < next_tick {
+                continue;
+            }
+            next_tick += TICK_INTERVAL;
+            if let Some(bot) = self.info.bot.as_mut() {
+                let dir = bot.strategy.pacman_move(&game);
+                if dir.is_some_and(|dir| Some(dir) != game.pacman_direction()) {
+                    game.set_pacman_direction(dir.unwrap());
                 }
-                drop(conn);
-                return self.finish(game.clone());
+            }
+            if let Err(err) = game.tick() {
+                println!("Erro no jogo: {err}");
+                return self.fail();
+            }
+            print!("\x1B[2J\x1B[H");
+            self.show(&game);
+            println!(
+                "Tick {} - direção atual: {} (move <direcao (wasd)> para mudar)",
+                game.ticks(),
+                game.pacman_direction().map_or('-', |dir| dir.key())
+            );
+            let failed = self.broadcast(&game);
+            hashes.push_back((game.ticks(), game.state_hash()));
+            if hashes.len() > HASH_HISTORY {
+                hashes.pop_front();
+            }
+            for ghost_user in failed {
+                readers.retain(|user| user != &ghost_user);
+                self.drop_ghost(&mut game, &ghost_user);
+            }
+            if game.game_over() {
+                return self.finish(game);
             }
         }
     }
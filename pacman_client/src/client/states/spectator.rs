@@ -0,0 +1,132 @@
+use std::{
+    io::BufReader,
+    net::{SocketAddr, TcpStream},
+    sync::mpsc::{channel, RecvTimeoutError},
+    time::Duration,
+};
+
+use pacman_communication::{
+    game::GameMode,
+    peer::{read_message, write_message, Hello, Replica, Snapshot, Update},
+};
+
+use super::{CommonInfo, Idle, Message, MessageEnum, Shell};
+
+const RECV_TIMEOUT: Duration = Duration::from_millis(33);
+
+/// Watches a match hosted by another user, without taking part in it
+pub struct Spectator {
+    info: CommonInfo,
+    user: String,
+    pacman_user: String,
+    stream: TcpStream,
+}
+
+impl Spectator {
+    pub fn new_and_run(
+        info: CommonInfo,
+        user: String,
+        pacman_addr: SocketAddr,
+        pacman_user: String,
+        ticket: u64,
+    ) -> Idle {
+        if let Ok(mut stream) = TcpStream::connect(pacman_addr) {
+            let hello = Hello::Spectator {
+                user: user.clone(),
+                ticket,
+            };
+            write_message(&mut stream, &hello).unwrap();
+            println!("Assistindo o jogo de {pacman_user}!");
+            Self {
+                info,
+                user,
+                pacman_user,
+                stream,
+            }
+            .run()
+        } else {
+            println!("Conexão ao Pacman não foi bem sucedida!");
+            info.server.send(Message {
+                connection: info.connection,
+                message: MessageEnum::QuitGameRequest,
+            });
+            Idle::new(info, user)
+        }
+    }
+
+    pub fn leave(self) -> Idle {
+        println!("Saindo do jogo de {}!", &self.pacman_user);
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+        self.info.server.send(Message {
+            connection: self.info.connection,
+            message: MessageEnum::QuitGameRequest,
+        });
+        Idle::new(self.info, self.user)
+    }
+
+    fn run(self) -> Idle {
+        let Ok(stream) = self.stream.try_clone() else { return self.leave(); };
+        let (send, snapshots) = channel();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stream);
+            while let Ok(Some(snapshot)) = read_message::<Snapshot>(&mut reader) {
+                if send.send(snapshot).is_err() {
+                    return;
+                }
+            }
+        });
+        let commands = ["encerra"];
+        let shell = Shell::new(&commands, self.info.keep_running.clone());
+        println!("Aguardando pelo próximo estado do jogo... (encerra para sair)");
+        let mut replica: Option<Replica> = None; // Our copy, built from the first snapshot
+        loop {
+            if shell.poll().is_some() {
+                // encerra is the only command
+                return self.leave();
+            }
+            match snapshots.recv_timeout(RECV_TIMEOUT) {
+                Ok(snapshot) => {
+                    if let Some(desync) = &snapshot.desync {
+                        println!("Jogo dessincronizado: {desync}");
+                        return self.leave();
+                    }
+                    if replica.is_none() {
+                        // The first snapshot carries the whole game
+                        let Update::Game(game) = &snapshot.update else {
+                            println!("Jogo inválido recebido de {}", &self.pacman_user);
+                            return self.leave();
+                        };
+                        match Replica::new(game) {
+                            Ok(new) => replica = Some(new),
+                            Err(err) => {
+                                println!("Jogo inválido recebido de {}: {err}", &self.pacman_user);
+                                return self.leave();
+                            }
+                        }
+                    }
+                    let replica = replica.as_mut().unwrap();
+                    if replica.check(&snapshot).is_err() {
+                        println!("O jogo recebido não confere com o hash de {}!", &self.pacman_user);
+                        return self.leave();
+                    }
+                    let (game, spectators) = (replica.game(), snapshot.spectators);
+                    if game.mode() == GameMode::RealTime {
+                        print!("\x1B[2J\x1B[H");
+                    }
+                    game.show();
+                    println!("Pontuação: {} - Espectadores: {spectators}", game.score());
+                    println!("encerra para sair");
+                    if game.game_over() {
+                        println!("Fim de jogo!");
+                        return self.leave();
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    println!("Conexão fechada!");
+                    return self.leave();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Input of a player's turn in a turn-based game, shared by networked, offline and hot-seat
+//! games
+
+use std::time::Duration;
+
+use pacman_communication::{
+    current_time,
+    game::{Game, GameError, GameRules, MoveOutcome},
+    Direction,
+};
+
+use super::shell::Shell;
+
+pub enum TurnEnd {
+    /// Direction moved to, None if the player stood still
+    Played(Option<Direction>, MoveOutcome),
+    /// The player typed `encerra`
+    Quit,
+    /// The player missed too many turns in a row
+    Forfeit,
+}
+
+/// Turns of a single player, remembered so their move can be played for them when they run out
+/// of time
+pub struct TurnInput {
+    turn_time: Option<Duration>,
+    max_missed_turns: Option<u32>,
+    last_dir: Option<Direction>,
+    missed_turns: u32,
+}
+
+impl TurnInput {
+    #[must_use]
+    pub fn new(rules: &GameRules) -> Self {
+        Self {
+            turn_time: rules.turn_time.map(Duration::from_secs),
+            max_missed_turns: rules.max_missed_turns,
+            last_dir: None,
+            missed_turns: 0,
+        }
+    }
+
+    /// Asks for a move until an allowed one is typed. When the time runs out, the last direction
+    /// is played again, or the player stands still if it is blocked
+    /// `try_move` applies a direction (None to stand still) to the game, and `on_command`
+    /// handles the commands of the shell besides `move` and `encerra`
+    pub fn play(
+        &mut self,
+        shell: &Shell,
+        decoration: &str,
+        mut try_move: impl FnMut(Option<Direction>) -> Result<MoveOutcome, GameError>,
+        mut on_command: impl FnMut(&[String]),
+    ) -> Result<TurnEnd, GameError> {
+        let deadline = self.turn_time.map(|time| current_time() + time);
+        loop {
+            let Some(command) = shell.prompt_until(decoration, deadline) else {
+                self.missed_turns += 1;
+                if self.max_missed_turns.is_some_and(|max| self.missed_turns >= max) {
+                    println!("Você perdeu por ausência!");
+                    return Ok(TurnEnd::Forfeit);
+                }
+                println!("Turno jogado automaticamente");
+                if let Some(dir) = self.last_dir {
+                    let outcome = try_move(Some(dir))?;
+                    if outcome != MoveOutcome::BlockedByWall {
+                        return Ok(TurnEnd::Played(Some(dir), outcome));
+                    }
+                }
+                return Ok(TurnEnd::Played(None, try_move(None)?));
+            };
+            if command.is_empty() {
+                continue;
+            }
+            match command[0].as_str() {
+                "move" => {
+                    let dir = Direction::from_key(command[1].chars().next().unwrap()).unwrap();
+                    match try_move(Some(dir))? {
+                        MoveOutcome::BlockedByWall => println!("Há uma parede nessa direção!"),
+                        outcome => {
+                            self.last_dir = Some(dir);
+                            self.missed_turns = 0;
+                            return Ok(TurnEnd::Played(Some(dir), outcome));
+                        }
+                    }
+                }
+                "encerra" => return Ok(TurnEnd::Quit),
+                _ => on_command(&command),
+            }
+        }
+    }
+}
+
+/// Last latencies measured with the other peers, shown by `atraso`
+pub fn show_latencies(latencies: &[(Duration, String)]) {
+    if latencies.is_empty() {
+        println!("Sem latências medidas!");
+        return;
+    }
+    let len = latencies.len().min(3);
+    println!("Últimas latências:");
+    println!("{:?}", &latencies[latencies.len() - len..]);
+}
+
+/// Tells the pacman player what their move did
+pub fn show_outcome(game: &Game, outcome: MoveOutcome) {
+    match outcome {
+        MoveOutcome::AtePowerPellet => println!("Os fantasmas estão assustados!"),
+        MoveOutcome::AteFruit => println!("Você comeu a fruta bônus!"),
+        MoveOutcome::AteGhost => println!("Você comeu um fantasma!"),
+        _ => {}
+    }
+    println!("Pontuação: {}", game.score());
+}
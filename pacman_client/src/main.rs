@@ -1,8 +1,7 @@
 use std::{
-    io::Read,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
         mpsc::channel,
         Arc,
     },
@@ -10,7 +9,15 @@ use std::{
 };
 
 use clap::{Parser, ValueEnum};
-use pacman_communication::{client_server, server_client, Connection, PacmanMessage};
+use client::{
+    bot::{Bot, StrategyName},
+    shell::parse_rules,
+};
+use pacman_communication::{
+    client_server, current_time,
+    game::{GameMode, GameRules},
+    read_frame, server_client, Connection, PacmanMessage, MAX_DATAGRAM_SIZE,
+};
 
 #[derive(Debug, Clone, PartialEq, ValueEnum)]
 #[clap(rename_all = "kebab_case")]
@@ -24,23 +31,95 @@ pub mod client;
 #[derive(Parser, Debug)]
 #[command(about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
-    server_addr: SocketAddr,
-    #[arg(short, long)]
-    protocol: Protocol,
+    #[arg(short, long, required_unless_present = "offline")]
+    server_addr: Option<SocketAddr>,
+    #[arg(short, long, required_unless_present = "offline")]
+    protocol: Option<Protocol>,
+    /// Play a game locally against AI ghosts, without a server
+    #[arg(long, conflicts_with = "bot")]
+    offline: bool,
+    /// Offline: a second player moves a ghost on the same terminal (turn-based games only)
+    #[arg(long, requires = "offline")]
+    hot_seat: bool,
+    /// Play by itself as a bot, without reading commands from stdin
+    #[arg(long, requires_all = ["user", "password"])]
+    bot: bool,
+    /// Bot: user to log in as, created if it doesn't exist
+    #[arg(long)]
+    user: Option<String>,
+    /// Bot: password of the user
+    #[arg(long)]
+    password: Option<String>,
+    /// Bot: challenge the games of this pacman, instead of hosting games
+    #[arg(long)]
+    challenge: Option<String>,
+    /// Bot: strategy used to choose moves
+    #[arg(long, value_enum, default_value_t)]
+    strategy: StrategyName,
+    /// Bot and offline: options of `inicia` for the games played, such as "tempo-real vidas=3"
+    #[arg(long, default_value = "")]
+    rules: String,
+    /// Bot: milliseconds to wait before each move in turn-based games
+    #[arg(long, default_value_t = 300)]
+    delay: u64,
+    /// Bot: number of games to play before quitting, plays forever if not set
+    #[arg(long)]
+    games: Option<u32>,
+}
+
+impl Args {
+    fn rules(&self) -> GameRules {
+        let options: Vec<String> = self.rules.split_whitespace().map(str::to_owned).collect();
+        let Some(rules) = parse_rules(&options) else {
+            eprintln!("Regras inválidas: {}", self.rules);
+            std::process::exit(1);
+        };
+        rules
+    }
+
+    fn bot(&self, games_played: &Arc<AtomicU32>, login_after: &Arc<AtomicU64>) -> Option<Bot> {
+        if !self.bot {
+            return None;
+        }
+        let rules = self.rules();
+        Some(Bot {
+            user: self.user.clone().unwrap(),
+            passwd: self.password.clone().unwrap(),
+            challenge: self.challenge.clone(),
+            rules,
+            strategy: self.strategy.build(),
+            delay: Duration::from_millis(self.delay),
+            max_games: self.games,
+            games_played: games_played.clone(),
+            login_after: login_after.clone(),
+        })
+    }
 }
 
 fn main() {
     let args = Args::parse();
+    if args.offline {
+        let rules = args.rules();
+        if args.hot_seat && rules.mode == GameMode::RealTime {
+            eprintln!("O modo hot-seat só funciona em jogos por turnos");
+            std::process::exit(1);
+        }
+        return client::offline::run(rules, args.hot_seat);
+    }
+    let (Some(server_addr), Some(protocol)) = (args.server_addr, args.protocol.clone()) else {
+        unreachable!()
+    };
+    let games_played = Arc::new(AtomicU32::new(0));
+    let login_after = Arc::new(AtomicU64::new(0));
     loop {
         let keep_running = Arc::new(AtomicBool::new(true));
         println!("Starting a new client!");
         let (send, recv) = channel::<server_client::Message>();
         let (server, connection);
-        match args.protocol {
+        match protocol {
             Protocol::Tcp => {
                 let keep_running = keep_running.clone();
-                server = Connection::Tcp(args.server_addr);
+                server = Connection::Tcp(server_addr);
                 let server_addr = server;
                 let listener =
                     TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
@@ -48,12 +127,13 @@ fn main() {
                 let listener_addr = Connection::Tcp(listener.local_addr().unwrap());
                 connection = listener_addr;
                 std::thread::spawn(move || {
-                    let mut buf = [0u8; 9001];
                     while keep_running.load(Ordering::Relaxed) {
                         std::thread::sleep(Duration::from_millis(33));
                         let Ok((mut stream, _)) = listener.accept() else { continue; };
-                        let Ok(amt) = stream.read(&mut buf) else { continue; };
-                        let Some(msg) = PacmanMessage::from_bytes(&buf[..amt]) else { continue; };
+                        let _ = stream.set_nonblocking(false);
+                        let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+                        let Ok(buf) = read_frame(&mut stream) else { continue; };
+                        let Some(msg) = PacmanMessage::from_bytes(&buf) else { continue; };
                         send.send(msg).unwrap();
                     }
                     server_addr.send(client_server::Message {
@@ -64,7 +144,7 @@ fn main() {
             }
             Protocol::Udp => {
                 let keep_running = keep_running.clone();
-                server = Connection::Udp(args.server_addr);
+                server = Connection::Udp(server_addr);
                 let server_addr = server;
                 let listener =
                     UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
@@ -72,7 +152,7 @@ fn main() {
                 let listener_addr = Connection::Udp(listener.local_addr().unwrap());
                 connection = listener_addr;
                 std::thread::spawn(move || {
-                    let mut buf = [0u8; 9001];
+                    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
                     while keep_running.load(Ordering::Relaxed) {
                         std::thread::sleep(Duration::from_millis(33));
                         let Ok(amt) = listener.recv(&mut buf) else { continue; };
@@ -86,7 +166,29 @@ fn main() {
                 });
             }
         }
-        client::run(server, connection, recv, keep_running);
+        client::run(
+            server,
+            connection,
+            recv,
+            keep_running,
+            args.bot(&games_played, &login_after),
+        );
+        if args.bot {
+            let games_played = games_played.load(Ordering::Relaxed);
+            if args.games.is_some_and(|max| games_played >= max) {
+                return;
+            }
+            let login_after = login_after.load(Ordering::Relaxed);
+            if login_after == Bot::NEVER {
+                eprintln!("O servidor recusou o login do bot");
+                std::process::exit(1);
+            }
+            // Waits for the server to unlock the user instead of locking it again
+            let wait = login_after.saturating_sub(current_time().as_secs()).max(1);
+            println!("Bot reconnecting to the server...");
+            std::thread::sleep(Duration::from_secs(wait));
+            continue;
+        }
         println!("Client was terminated. Trying to connect to server again in 10 seconds...");
         std::thread::sleep(Duration::from_secs(10));
     }
@@ -1,7 +1,17 @@
 use std::net::SocketAddr;
 
-use crate::{Connection, LeaderboardEntry};
-use serde::{Deserialize, Serialize};
+use crate::{
+    game::{GameRules, Input, Map},
+    Connection, Role,
+};
+use serde::{
+    de::{Error, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+
+/// Longest history a `GameResult` may have. Longer ones are refused while they are read, before
+/// they are kept in memory or simulated
+pub const MAX_RESULT_INPUTS: usize = 100_000;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Message {
@@ -22,8 +32,11 @@ pub enum MessageEnum {
     ConnectedUsersRequest,
     CreateGameRequest(CreateGameRequest),
     JoinGameRequest(JoinGameRequest),
-    LeaderboardRequest,
-    AddLeaderboardEntry(LeaderboardEntry),
+    SpectateGameRequest(SpectateGameRequest),
+    LeaderboardRequest(LeaderboardQuery),
+    AddLeaderboardEntry(GameResult),
+    ProfileRequest(ProfileRequest),
+    RatingLeaderboardRequest(RatingLeaderboardRequest),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -47,9 +60,110 @@ pub struct ChangePasswordRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CreateGameRequest {
     pub listener_addr: SocketAddr,
+    pub rules: GameRules,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JoinGameRequest {
     pub pacman: String,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpectateGameRequest {
+    pub pacman: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileRequest {
+    pub user: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RatingLeaderboardRequest {
+    pub role: Role,
+    /// Pages have `LEADERBOARD_PAGE_SIZE` users, the first one is 0
+    pub page: usize,
+}
+
+/// Results of the matches that ended within this window, counted in UTC
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeWindow {
+    /// The current season, or every result if the server has no seasons
+    #[default]
+    Season,
+    Today,
+    /// Since the last Monday
+    Week,
+    AllTime,
+}
+
+/// Which results of the leaderboard to show. The default is the first page of the current season
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LeaderboardQuery {
+    pub window: TimeWindow,
+    /// The final standings of this season that already ended instead, ignoring `window`
+    pub season: Option<u64>,
+    /// Only the best result of each user
+    pub personal_bests: bool,
+    /// Only the results of this user
+    pub user: Option<String>,
+    /// Pages have `LEADERBOARD_PAGE_SIZE` results, the first one is 0
+    pub page: usize,
+}
+
+/// Result of the game hosted by the sender. The server simulates the match again and only adds
+/// it to the leaderboard if it gets the same score and final state, then answers with a
+/// `ResultResponse`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GameResult {
+    pub score: u64,
+    /// `Game::state_hash` of the final state of the match
+    pub hash: u64,
+    pub seed: u64,
+    pub map: Map,
+    #[serde(deserialize_with = "deserialize_inputs")]
+    pub inputs: Vec<Input>,
+}
+
+fn deserialize_inputs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Input>, D::Error> {
+    struct Inputs;
+
+    impl<'de> Visitor<'de> for Inputs {
+        type Value = Vec<Input>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "at most {MAX_RESULT_INPUTS} inputs")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut inputs = Vec::new();
+            while let Some(input) = seq.next_element()? {
+                if inputs.len() == MAX_RESULT_INPUTS {
+                    return Err(A::Error::invalid_length(inputs.len() + 1, &self));
+                }
+                inputs.push(input);
+            }
+            Ok(inputs)
+        }
+    }
+
+    deserializer.deserialize_seq(Inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result_json(inputs: usize) -> String {
+        let map = serde_json::to_string(&Map::default()).unwrap();
+        let inputs = vec!["\"PassPacman\""; inputs].join(",");
+        format!(r#"{{"score":0,"hash":0,"seed":0,"map":{map},"inputs":[{inputs}]}}"#)
+    }
+
+    #[test]
+    fn results_with_too_many_inputs_are_refused() {
+        let result: GameResult = serde_json::from_str(&result_json(MAX_RESULT_INPUTS)).unwrap();
+        assert_eq!(result.inputs.len(), MAX_RESULT_INPUTS);
+        assert!(serde_json::from_str::<GameResult>(&result_json(MAX_RESULT_INPUTS + 1)).is_err());
+    }
+}
@@ -1,65 +1,556 @@
+use std::{collections::VecDeque, fmt, time::Duration};
+
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{current_time, Direction};
 
 const H: usize = 5;
 const W: usize = 27;
 
 const WALL: u8 = b'*';
 const PACDOT: u8 = b'.';
+const POWER_PELLET: u8 = b'o';
 const EMPTY: u8 = b' ';
 
+/// Symbol of the bonus fruit, which is kept apart from the board
+const FRUIT: u8 = b'%';
+
 const INITIAL_BOARD: [&str; H] = [
     "******.**... .....**.******",
-    "******.**.*******.**.******",
+    "******o**.*******.**o******",
     "******.**.*.. ..*.**.******",
     "..... ....*.....*..........",
     "******.**.*.. ..*.**.******",
 ];
 
+/// Maximum number of remote (human controlled) ghosts in a single game
+pub const MAX_REMOTE_GHOSTS: usize = 4;
+
+/// Maximum number of AI ghosts in a single game
+pub const MAX_AI_GHOSTS: usize = 4;
+
+const PACMAN_SPAWN: (usize, usize) = (2, 13);
+
+/// Spawn of each remote ghost slot, the slot also decides the symbol shown on the board
+const REMOTE_GHOST_SPAWNS: [(usize, usize); MAX_REMOTE_GHOSTS] = [(3, 3), (3, 7), (3, 18), (3, 21)];
+
+/// Spawns of AI ghosts, reused cyclically if there are more ghosts than spawns
+const AI_GHOST_SPAWNS: [(usize, usize); MAX_AI_GHOSTS] = [(3, 24), (0, 6), (4, 20), (0, 20)];
+
+/// Time between two ticks of a real-time game
+pub const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Extra time a peer waits for a timed turn of another peer, to account for the network
+pub const TURN_TIME_GRACE: Duration = Duration::from_secs(5);
+
+/// Points given by each thing pacman eats. Ranked games are always played with the default
+/// table, where a dot is worth 1 point as it was before maps had a table, so scores on the
+/// leaderboard stay comparable
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScoreTable {
+    pub dot: u64,
+    pub power_pellet: u64,
+    /// Points for each ghost eaten after the same power pellet, the last value repeats
+    pub ghost_combo: Vec<u64>,
+    pub fruit: u64,
+}
+
+impl Default for ScoreTable {
+    fn default() -> Self {
+        Self {
+            dot: 1,
+            power_pellet: 5,
+            ghost_combo: vec![20, 40, 80, 160],
+            fruit: 10,
+        }
+    }
+}
+
+/// When and where the bonus fruit appears
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FruitSettings {
+    pub tile: (usize, usize),
+    /// A fruit spawns each time the number of dots eaten reaches one of these
+    pub dot_thresholds: Vec<u64>,
+    /// Turns the fruit stays on the board before disappearing
+    pub duration: u64,
+}
+
+impl Default for FruitSettings {
+    fn default() -> Self {
+        Self {
+            tile: (3, 13),
+            dot_thresholds: vec![15, 35],
+            duration: 20,
+        }
+    }
+}
+
+/// Layout of the board together with its scoring rules. Only games on the default map are ranked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Map {
+    /// Identifies the map in the leaderboard
+    pub name: String,
+    pub board: Vec<String>,
+    pub scoring: ScoreTable,
+    pub fruit: FruitSettings,
+    /// Turns ghosts can be eaten after pacman eats a power pellet
+    pub frightened_turns: u64,
+    /// Tiles on the border of the board that wrap around when wraparound is limited to tunnels
+    pub tunnels: Vec<(usize, usize)>,
+}
+
+fn default_map_name() -> String {
+    "classico".to_owned()
+}
+
+impl Map {
+    /// Checks the size and tiles of the board, and that the fruit and every spawn are inside
+    /// it and not on a wall
+    pub fn validate(&self) -> Result<(), GameError> {
+        if self.board.len() != H || self.board.iter().any(|line| line.len() != W) {
+            return Err(GameError::InvalidMapSize);
+        }
+        for x in 0..H {
+            for y in 0..W {
+                self.tile((x, y))?;
+            }
+        }
+        if self.tile(self.fruit.tile)? == WALL {
+            return Err(GameError::FruitOnWall(self.fruit.tile));
+        }
+        for &tunnel in &self.tunnels {
+            Game::check_bounds(tunnel)?;
+        }
+        let spawns = REMOTE_GHOST_SPAWNS.iter().chain(&AI_GHOST_SPAWNS);
+        for &spawn in std::iter::once(&PACMAN_SPAWN).chain(spawns) {
+            if self.tile(spawn)? == WALL {
+                return Err(GameError::SpawnOnWall(spawn));
+            }
+        }
+        Ok(())
+    }
+
+    fn tile(&self, (x, y): (usize, usize)) -> Result<u8, GameError> {
+        Game::check_bounds((x, y))?;
+        match self.board[x].as_bytes()[y] {
+            byte @ (WALL | PACDOT | POWER_PELLET | EMPTY) => Ok(byte),
+            byte => Err(GameError::InvalidTile {
+                position: (x, y),
+                byte,
+            }),
+        }
+    }
+}
+
+impl Default for Map {
+    fn default() -> Self {
+        Self {
+            name: default_map_name(),
+            board: INITIAL_BOARD.iter().map(|&line| line.to_owned()).collect(),
+            scoring: ScoreTable::default(),
+            fruit: FruitSettings::default(),
+            frightened_turns: 10,
+            tunnels: [(3, 0), (3, W - 1)]
+                .into_iter()
+                .chain((11..=15).flat_map(|y| [(0, y), (H - 1, y)]))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameMode {
+    /// Pacman and every ghost move once per round, in turn order
+    TurnBased,
+    /// Every entity keeps moving in its current direction on each tick
+    RealTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wraparound {
+    /// Leaving the board through any border enters it from the opposite one
+    Everywhere,
+    /// Only the tunnels of the map wrap around, every other border blocks
+    Tunnels,
+}
+
+/// Rule variant of a game, chosen by its host. Every peer must run a game with the same rules
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct GameRules {
+    pub mode: GameMode,
+    pub wraparound: Wraparound,
+    pub ai_ghosts: usize,
+    pub max_remote_ghosts: usize,
+    /// Percentage of the turns (or ticks) in which ghosts move, 100 is as fast as pacman
+    pub ghost_speed: u64,
+    /// The game ends after pacman plays this many turns (or ticks)
+    pub turn_limit: Option<u64>,
+    pub lives: u32,
+    /// Seconds each player has to play a turn of a turn-based game, the turn is played
+    /// automatically when they run out
+    pub turn_time: Option<u64>,
+    /// Consecutive turns a player may miss before forfeiting the game
+    pub max_missed_turns: Option<u32>,
+}
+
+impl GameRules {
+    pub fn validate(&self) -> Result<(), GameError> {
+        if self.ai_ghosts > MAX_AI_GHOSTS {
+            return Err(GameError::InvalidRules("too many AI ghosts"));
+        }
+        if !(1..=MAX_REMOTE_GHOSTS).contains(&self.max_remote_ghosts) {
+            return Err(GameError::InvalidRules("invalid number of remote ghosts"));
+        }
+        if !(1..=100).contains(&self.ghost_speed) {
+            return Err(GameError::InvalidRules("ghost speed must be between 1 and 100"));
+        }
+        if self.turn_limit == Some(0) {
+            return Err(GameError::InvalidRules("turn limit must be positive"));
+        }
+        if self.lives == 0 {
+            return Err(GameError::InvalidRules("pacman needs at least one life"));
+        }
+        if self.turn_time == Some(0) {
+            return Err(GameError::InvalidRules("turn time must be positive"));
+        }
+        if self.max_missed_turns == Some(0) {
+            return Err(GameError::InvalidRules("missed turns before forfeit must be positive"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for GameRules {
+    fn default() -> Self {
+        Self {
+            mode: GameMode::TurnBased,
+            wraparound: Wraparound::Everywhere,
+            ai_ghosts: 1,
+            max_remote_ghosts: MAX_REMOTE_GHOSTS,
+            ghost_speed: 100,
+            turn_limit: None,
+            lives: 1,
+            turn_time: Some(30),
+            max_missed_turns: Some(3),
+        }
+    }
+}
+
+impl fmt::Display for GameRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            GameMode::TurnBased => "por turnos",
+            GameMode::RealTime => "tempo real",
+        };
+        let wraparound = match self.wraparound {
+            Wraparound::Everywhere => "todas as bordas",
+            Wraparound::Tunnels => "só túneis",
+        };
+        let turn_limit = self
+            .turn_limit
+            .map_or("sem limite".to_owned(), |limit| limit.to_string());
+        let turn_time = self
+            .turn_time
+            .map_or("sem limite".to_owned(), |secs| format!("{secs}s"));
+        let max_missed_turns = self
+            .max_missed_turns
+            .map_or("nunca".to_owned(), |turns| format!("após {turns} turnos perdidos"));
+        write!(
+            f,
+            "{mode}, atravessa {wraparound}, {} fantasmas IA, até {} fantasmas remotos, velocidade dos fantasmas {}%, turnos: {turn_limit}, vidas: {}, tempo por turno: {turn_time}, desistência: {max_missed_turns}",
+            self.ai_ghosts, self.max_remote_ghosts, self.ghost_speed, self.lives
+        )
+    }
+}
+
+/// What happened after trying to move an entity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveOutcome {
+    Moved,
+    BlockedByWall,
+    AteDot,
+    AtePowerPellet,
+    AteFruit,
+    /// Pacman ate a frightened ghost, which went back to its spawn
+    AteGhost,
+    /// Pacman and a ghost are on the same tile, pacman loses a life. The game ends when there
+    /// are no lives left
+    Collided,
+    /// The game had already ended, nothing moved
+    GameOver,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum GameError {
+    #[error("invalid byte {byte:?} on tile {position:?} of the board")]
+    InvalidTile { position: (usize, usize), byte: u8 },
+    #[error("position {0:?} is outside of the board")]
+    OutOfBounds((usize, usize)),
+    #[error("there is no ghost with id {0}")]
+    UnknownGhost(usize),
+    #[error("the board of a map must have {H} lines of {W} tiles")]
+    InvalidMapSize,
+    #[error("the fruit tile {0:?} is a wall")]
+    FruitOnWall((usize, usize)),
+    #[error("the spawn {0:?} is a wall")]
+    SpawnOnWall((usize, usize)),
+    #[error("invalid rules: {0}")]
+    InvalidRules(&'static str),
+    #[error("a replay can't have more than {0} steps")]
+    TooManySteps(usize),
+}
+
+/// Deterministic random number generator (SplitMix64). It is part of the game state, so a game
+/// can be replayed bit for bit from its seed and the inputs of the players
 #[derive(Serialize, Deserialize, Debug, Clone)]
+struct GameRng {
+    state: u64,
+}
+
+impl GameRng {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn direction(&mut self) -> Direction {
+        Direction::ALL[(self.next_u64() % 4) as usize]
+    }
+}
+
+/// Contents of a tile of the board
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tile {
+    Wall,
+    Dot,
+    PowerPellet,
+    Empty,
+}
+
+/// Distance, in moves, from a tile to every other tile of the board
+#[derive(Debug, Clone)]
+pub struct DistanceMap {
+    distances: [[Option<u32>; W]; H],
+}
+
+impl DistanceMap {
+    /// None if `position` can't be reached
+    pub fn get(&self, (x, y): (usize, usize)) -> Option<u32> {
+        *self.distances.get(x)?.get(y)?
+    }
+}
+
+/// Everything that changes a game besides its own random choices. Applying the same inputs to a
+/// game with the same settings and seed always gives the same result
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    MovePacman(Direction),
+    MoveGhost(usize, Direction),
+    MoveAiGhost(usize),
+    AddRemoteGhost(String),
+    RemoveRemoteGhost(String),
+    SetPacmanDirection(Direction),
+    SetGhostDirection(usize, Direction),
+    /// Consecutive ticks are kept in a single record
+    Ticks(u64),
+    /// Pacman stood still in its turn
+    PassPacman,
+    /// Pacman gave up, which ends the game
+    Forfeit,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputRecord {
+    pub time: Duration, // Since UNIX_EPOCH, as in `current_time`
+    pub input: Input,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum GhostController {
+    /// Moved by whoever hosts the game (the Pacman)
+    Ai,
+    /// Moved by the remote player with this username
+    Remote(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ghost {
+    pub id: usize,
+    pub controller: GhostController,
+    pub spawn: (usize, usize),
+    pub position: (usize, usize),
+    pub direction: Option<Direction>, // Only used in real-time games
+}
+
+/// Fields missing from games saved by older versions take the values of a new game
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct Game {
     board: [[u8; W]; H],
+    map: Map,
+    rules: GameRules,
+    seed: u64,
+    rng: GameRng,
     pacman: (usize, usize),
+    pacman_direction: Option<Direction>, // Only used in real-time games
     score: u64,
-    local_ghost: (usize, usize),
-    remote_ghost: Option<(usize, usize)>,
+    lives: u32,
+    turns: u64, // Turns played by pacman, or ticks in a real-time game
+    dots_eaten: u64,
+    fruit: Option<u64>, // Turns left before the fruit disappears
+    fruits_spawned: usize,
+    frightened: u64,     // Turns left in which ghosts can be eaten
+    ghosts_eaten: usize, // Since the last power pellet
+    ghosts: Vec<Ghost>, // Ghosts play in the order they appear here
+    next_ghost_id: usize,
+    ticks: u64,
+    history: Vec<InputRecord>,
     ended: bool,
+    caught_by: Vec<usize>, // Ids of the ghosts that caught pacman, in order
 }
 
 impl Game {
+    /// New game with the default rules
     pub fn new() -> Self {
-        let board: [[u8; W]; H] = [
-            INITIAL_BOARD[0].as_bytes().try_into().unwrap(),
-            INITIAL_BOARD[1].as_bytes().try_into().unwrap(),
-            INITIAL_BOARD[2].as_bytes().try_into().unwrap(),
-            INITIAL_BOARD[3].as_bytes().try_into().unwrap(),
-            INITIAL_BOARD[4].as_bytes().try_into().unwrap(),
-        ];
-        let pacman = (2, 13);
+        Self::with_rules(GameRules::default(), 0).unwrap()
+    }
+
+    /// `seed` decides every random choice of the game, such as the moves of AI ghosts
+    pub fn with_rules(rules: GameRules, seed: u64) -> Result<Self, GameError> {
+        Self::with_map(Map::default(), rules, seed)
+    }
+
+    /// Same as `with_rules`, on a custom map
+    pub fn with_map(map: Map, rules: GameRules, seed: u64) -> Result<Self, GameError> {
+        rules.validate()?;
+        map.validate()?;
+        let mut board = [[EMPTY; W]; H];
+        for (x, line) in map.board.iter().enumerate() {
+            board[x].copy_from_slice(line.as_bytes());
+        }
+        let pacman = PACMAN_SPAWN;
         let score = 0;
-        let local_ghost = (3, 24); // totally random initial position
-        let remote_ghost = None;
-        Self {
+        let ai_ghosts = rules.ai_ghosts;
+        let ghosts = (0..ai_ghosts)
+            .map(|id| {
+                let spawn = AI_GHOST_SPAWNS[id % AI_GHOST_SPAWNS.len()];
+                Ghost {
+                    id,
+                    controller: GhostController::Ai,
+                    spawn,
+                    position: spawn,
+                    direction: None,
+                }
+            })
+            .collect();
+        let game = Self {
             board,
+            map,
+            lives: rules.lives,
+            rules,
+            seed,
+            rng: GameRng::new(seed),
             pacman,
+            pacman_direction: None,
             score,
-            local_ghost,
-            remote_ghost,
+            turns: 0,
+            dots_eaten: 0,
+            fruit: None,
+            fruits_spawned: 0,
+            frightened: 0,
+            ghosts_eaten: 0,
+            ghosts,
+            next_ghost_id: ai_ghosts,
+            ticks: 0,
+            history: Vec::new(),
             ended: false,
-        }
+            caught_by: Vec::new(),
+        };
+        game.validate()?;
+        Ok(game)
+    }
+
+    /// The map the game started with
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    pub fn rules(&self) -> &GameRules {
+        &self.rules
+    }
+
+    pub fn lives(&self) -> u32 {
+        self.lives
+    }
+
+    /// Turns played by pacman, or ticks elapsed in a real-time game
+    pub fn turns(&self) -> u64 {
+        self.turns
+    }
+
+    pub fn dots_eaten(&self) -> u64 {
+        self.dots_eaten
+    }
+
+    /// Ids of the ghosts that caught pacman, once for each life it lost to them
+    pub fn caught_by(&self) -> &[usize] {
+        &self.caught_by
     }
 
     pub fn show(&self) {
         println!("Estado do jogo:");
         let mut copy = self.board;
         let (x, y) = self.pacman;
+        if self.fruit.is_some() {
+            let (fx, fy) = self.map.fruit.tile;
+            copy[fx][fy] = FRUIT;
+        }
         copy[x][y] = b'P';
-        let (x, y) = self.local_ghost;
-        copy[x][y] = b'F';
-        if let Some((x, y)) = self.remote_ghost {
-            copy[x][y] = b'f';
+        for ghost in &self.ghosts {
+            let (x, y) = ghost.position;
+            copy[x][y] = Self::ghost_symbol(ghost);
         }
         for line in copy {
-            println!("{}", std::str::from_utf8(&line).unwrap());
+            println!("{}", String::from_utf8_lossy(&line));
+        }
+        for ghost in &self.ghosts {
+            if let GhostController::Remote(user) = &ghost.controller {
+                println!("{}: {user}", Self::ghost_symbol(ghost) as char);
+            }
+        }
+        if self.frightened > 0 {
+            println!("Fantasmas assustados por mais {} turnos!", self.frightened);
+        }
+        if self.rules.lives > 1 {
+            println!("Vidas: {}", self.lives);
+        }
+        if let Some(limit) = self.rules.turn_limit {
+            println!("Turno {}/{limit}", self.turns);
+        }
+    }
+
+    /// AI ghosts are shown as `F`, remote ghosts by the number of their slot
+    fn ghost_symbol(ghost: &Ghost) -> u8 {
+        match ghost.controller {
+            GhostController::Ai => b'F',
+            GhostController::Remote(_) => {
+                let slot = REMOTE_GHOST_SPAWNS
+                    .iter()
+                    .position(|&spawn| spawn == ghost.spawn)
+                    .unwrap_or(0);
+                b'1' + slot as u8
+            }
         }
     }
 
@@ -71,95 +562,612 @@ impl Game {
         self.score
     }
 
-    fn dir_vec(dir: char) -> Option<(isize, isize)> {
-        match dir {
-            'w' => Some((-1, 0)),
-            'a' => Some((0, -1)),
-            's' => Some((1, 0)),
-            'd' => Some((0, 1)),
-            _ => None,
+    pub fn mode(&self) -> GameMode {
+        self.rules.mode
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Number of ticks elapsed in a real-time game
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn pacman_direction(&self) -> Option<Direction> {
+        self.pacman_direction
+    }
+
+    pub fn ghosts(&self) -> &[Ghost] {
+        &self.ghosts
+    }
+
+    /// Every input applied to the game so far, in order
+    pub fn history(&self) -> &[InputRecord] {
+        &self.history
+    }
+
+    /// Hash of the whole state of the game: board, positions, directions, score, turn, random
+    /// number generator, fruit and frightened ghosts
+    /// Uses FNV-1a, so it is the same on every platform and version of Rust, and peers can compare
+    /// their copies of the game
+    pub fn state_hash(&self) -> u64 {
+        const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0100_0000_01b3;
+        let mut hash = OFFSET;
+        let mut write = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(PRIME);
+            }
+        };
+        for row in &self.board {
+            write(row);
+        }
+        let mut write_u64 = |value: u64| write(&value.to_le_bytes());
+        let direction = |dir: Option<Direction>| dir.map_or(0, |dir| dir as u64 + 1);
+        write_u64(self.pacman.0 as u64);
+        write_u64(self.pacman.1 as u64);
+        write_u64(direction(self.pacman_direction));
+        for ghost in &self.ghosts {
+            write_u64(ghost.id as u64);
+            write_u64(ghost.position.0 as u64);
+            write_u64(ghost.position.1 as u64);
+            write_u64(direction(ghost.direction));
+        }
+        write_u64(self.score);
+        write_u64(u64::from(self.lives));
+        write_u64(self.turns);
+        write_u64(self.ticks);
+        write_u64(self.rng.state);
+        write_u64(self.dots_eaten);
+        write_u64(self.fruit.map_or(0, |turns| turns + 1));
+        write_u64(self.fruits_spawned as u64);
+        write_u64(self.frightened);
+        write_u64(self.ghosts_eaten as u64);
+        write_u64(u64::from(self.ended));
+        hash
+    }
+
+    fn record(&mut self, input: Input) {
+        let time = current_time();
+        if let Input::Ticks(n) = input {
+            if let Some(InputRecord {
+                input: Input::Ticks(ticks),
+                ..
+            }) = self.history.last_mut()
+            {
+                *ticks += n;
+                return;
+            }
+        }
+        self.history.push(InputRecord { time, input });
+    }
+
+    /// Applies an input, as recorded in the history of another game
+    pub fn apply(&mut self, input: &Input) -> Result<MoveOutcome, GameError> {
+        match input {
+            Input::MovePacman(dir) => self.move_pacman(*dir),
+            Input::MoveGhost(id, dir) => self.move_ghost(*id, *dir),
+            Input::MoveAiGhost(id) => self.move_ai_ghost(*id),
+            Input::AddRemoteGhost(user) => {
+                self.add_remote_ghost(user);
+                Ok(MoveOutcome::Moved)
+            }
+            Input::RemoveRemoteGhost(user) => {
+                self.remove_remote_ghost(user);
+                Ok(MoveOutcome::Moved)
+            }
+            Input::SetPacmanDirection(dir) => {
+                self.set_pacman_direction(*dir);
+                Ok(MoveOutcome::Moved)
+            }
+            Input::SetGhostDirection(id, dir) => {
+                self.set_ghost_direction(*id, *dir)?;
+                Ok(MoveOutcome::Moved)
+            }
+            Input::PassPacman => self.pass_pacman(),
+            Input::Forfeit => {
+                self.forfeit();
+                Ok(MoveOutcome::GameOver)
+            }
+            Input::Ticks(n) => {
+                for _ in 0..*n {
+                    self.tick()?;
+                }
+                Ok(MoveOutcome::Moved)
+            }
+        }
+    }
+
+    /// Same as `apply`, keeping the time the input was recorded at in the other game
+    pub fn apply_record(&mut self, record: &InputRecord) -> Result<MoveOutcome, GameError> {
+        let len = self.history.len();
+        let outcome = self.apply(&record.input)?;
+        if self.history.len() > len {
+            self.history.last_mut().unwrap().time = record.time;
+        }
+        Ok(outcome)
+    }
+
+    /// Id of the ghost controlled by `user`, if they are in the game
+    pub fn remote_ghost_id(&self, user: &str) -> Option<usize> {
+        self.ghosts
+            .iter()
+            .find(|ghost| matches!(&ghost.controller, GhostController::Remote(u) if u == user))
+            .map(|ghost| ghost.id)
+    }
+
+    /// Height and width of the board
+    pub fn size(&self) -> (usize, usize) {
+        (H, W)
+    }
+
+    pub fn pacman_position(&self) -> (usize, usize) {
+        self.pacman
+    }
+
+    /// Where the bonus fruit is, if it is on the board
+    pub fn fruit_position(&self) -> Option<(usize, usize)> {
+        self.fruit.map(|_| self.map.fruit.tile)
+    }
+
+    /// Turns left in which pacman can eat ghosts
+    pub fn frightened_turns(&self) -> u64 {
+        self.frightened
+    }
+
+    /// None if `position` is outside of the board
+    pub fn tile_at(&self, (x, y): (usize, usize)) -> Option<Tile> {
+        match *self.board.get(x)?.get(y)? {
+            PACDOT => Some(Tile::Dot),
+            POWER_PELLET => Some(Tile::PowerPellet),
+            EMPTY => Some(Tile::Empty),
+            _ => Some(Tile::Wall),
         }
     }
 
-    fn update_game_state(&mut self) {
-        if self.pacman == self.local_ghost {
+    /// Dots left on the board, power pellets included
+    pub fn remaining_dots(&self) -> usize {
+        self.board
+            .iter()
+            .flatten()
+            .filter(|&&byte| byte == PACDOT || byte == POWER_PELLET)
+            .count()
+    }
+
+    /// Tiles reachable in a single move from `position`, following the wraparound rules
+    pub fn neighbours(
+        &self,
+        position: (usize, usize),
+    ) -> impl Iterator<Item = (Direction, (usize, usize))> + '_ {
+        Direction::ALL.into_iter().filter_map(move |dir| {
+            let next = self.new_position(position, dir)?;
+            (self.tile_at(next)? != Tile::Wall).then_some((dir, next))
+        })
+    }
+
+    /// Distance from `from` to every tile, found with a breadth-first search
+    pub fn distance_map(&self, from: (usize, usize)) -> DistanceMap {
+        let mut distances = [[None; W]; H];
+        if self.tile_at(from).is_none() {
+            return DistanceMap { distances };
+        }
+        let mut queue = VecDeque::from([from]);
+        distances[from.0][from.1] = Some(0);
+        while let Some(position) = queue.pop_front() {
+            let distance = distances[position.0][position.1].unwrap();
+            for (_, (x, y)) in self.neighbours(position) {
+                if distances[x][y].is_none() {
+                    distances[x][y] = Some(distance + 1);
+                    queue.push_back((x, y));
+                }
+            }
+        }
+        DistanceMap { distances }
+    }
+
+    /// Moves of one of the shortest paths from `from` to `to`, None if `to` can't be reached
+    pub fn shortest_path(&self, from: (usize, usize), to: (usize, usize)) -> Option<Vec<Direction>> {
+        self.tile_at(from)?;
+        self.tile_at(to)?;
+        // Move that first reached each tile and the tile it came from
+        let mut parents = [[None; W]; H];
+        let mut visited = [[false; W]; H];
+        visited[from.0][from.1] = true;
+        let mut queue = VecDeque::from([from]);
+        while let Some(position) = queue.pop_front() {
+            if position == to {
+                break;
+            }
+            for (dir, (x, y)) in self.neighbours(position) {
+                if !visited[x][y] {
+                    visited[x][y] = true;
+                    parents[x][y] = Some((dir, position));
+                    queue.push_back((x, y));
+                }
+            }
+        }
+        if !visited[to.0][to.1] {
+            return None;
+        }
+        let mut path = Vec::new();
+        let mut position = to;
+        while let Some((dir, previous)) = parents[position.0][position.1] {
+            path.push(dir);
+            position = previous;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Checks that the rules and every tile and position are valid, games received from other
+    /// peers must be validated before being used
+    pub fn validate(&self) -> Result<(), GameError> {
+        self.rules.validate()?;
+        for x in 0..H {
+            for y in 0..W {
+                self.tile((x, y))?;
+            }
+        }
+        self.map.validate()?;
+        Self::check_bounds(self.pacman)?;
+        for ghost in &self.ghosts {
+            if self.tile(ghost.spawn)? == WALL {
+                return Err(GameError::SpawnOnWall(ghost.spawn));
+            }
+            Self::check_bounds(ghost.position)?;
+        }
+        Ok(())
+    }
+
+    fn check_bounds((x, y): (usize, usize)) -> Result<(), GameError> {
+        if x < H && y < W {
+            Ok(())
+        } else {
+            Err(GameError::OutOfBounds((x, y)))
+        }
+    }
+
+    fn tile(&self, (x, y): (usize, usize)) -> Result<u8, GameError> {
+        Self::check_bounds((x, y))?;
+        match self.board[x][y] {
+            byte @ (WALL | PACDOT | POWER_PELLET | EMPTY) => Ok(byte),
+            byte => Err(GameError::InvalidTile {
+                position: (x, y),
+                byte,
+            }),
+        }
+    }
+
+    /// Resolves every ghost on the same tile as pacman: frightened ghosts are eaten, any other
+    /// ends the game
+    fn update_game_state(&mut self) -> Option<MoveOutcome> {
+        let mut outcome = None;
+        for idx in 0..self.ghosts.len() {
+            if self.ghosts[idx].position != self.pacman {
+                continue;
+            }
+            let caught = self.catch(idx);
+            outcome = Some(caught);
+            if caught == MoveOutcome::Collided {
+                break;
+            }
+        }
+        outcome
+    }
+
+    /// Pacman and the ghost at `idx` met
+    fn catch(&mut self, idx: usize) -> MoveOutcome {
+        if self.frightened == 0 {
+            self.caught_by.push(self.ghosts[idx].id);
+            self.lose_life();
+            return MoveOutcome::Collided;
+        }
+        let combo = &self.map.scoring.ghost_combo;
+        let points = combo
+            .get(self.ghosts_eaten)
+            .or(combo.last())
+            .copied()
+            .unwrap_or(0);
+        self.score += points;
+        self.ghosts_eaten += 1;
+        self.ghosts[idx].position = self.ghosts[idx].spawn;
+        MoveOutcome::AteGhost
+    }
+
+    /// Ends the game when pacman has no lives left, otherwise everyone goes back to their spawn
+    fn lose_life(&mut self) {
+        self.lives = self.lives.saturating_sub(1);
+        if self.lives == 0 {
             self.ended = true;
+            return;
         }
-        let Some(remote_ghost) = self.remote_ghost else { return; };
-        if self.pacman == remote_ghost {
+        self.pacman = PACMAN_SPAWN;
+        self.pacman_direction = None;
+        for ghost in &mut self.ghosts {
+            ghost.position = ghost.spawn;
+            ghost.direction = None;
+        }
+    }
+
+    /// Whether ghosts move in `turn`, according to their speed
+    fn ghosts_move_in(&self, turn: u64) -> bool {
+        let speed = self.rules.ghost_speed;
+        turn * speed / 100 > turn.saturating_sub(1) * speed / 100
+    }
+
+    /// Whether ghosts move in the next round of a turn-based game, before pacman's turn
+    pub fn ghosts_move(&self) -> bool {
+        self.ghosts_move_in(self.turns + 1)
+    }
+
+    fn check_turn_limit(&mut self) {
+        if self.rules.turn_limit.is_some_and(|limit| self.turns >= limit) {
             self.ended = true;
         }
     }
 
-    fn new_position((x, y): (usize, usize), dir: char) -> (usize, usize) {
-        let Some((dx, dy)) = Self::dir_vec(dir) else { return (x, y); };
-        let (mut nx, mut ny) = (
-            (x as isize + dx) % H as isize,
-            (y as isize + dy) % W as isize,
-        );
-        if nx < 0 {
-            nx += H as isize;
+    /// Counts the turn and down the fruit and the frightened ghosts, once per turn of pacman or
+    /// per tick
+    fn advance_timers(&mut self) {
+        self.turns += 1;
+        if let Some(turns) = self.fruit {
+            self.fruit = turns.checked_sub(1).filter(|&turns| turns > 0);
         }
-        if ny < 0 {
-            ny += W as isize;
+        self.frightened = self.frightened.saturating_sub(1);
+    }
+
+    fn eat_dot(&mut self) {
+        self.score += self.map.scoring.dot;
+        self.dots_eaten += 1;
+        let thresholds = &self.map.fruit.dot_thresholds;
+        if thresholds.get(self.fruits_spawned) == Some(&self.dots_eaten) {
+            self.fruits_spawned += 1;
+            self.fruit = Some(self.map.fruit.duration);
         }
-        (nx as usize, ny as usize)
     }
 
-    pub fn move_pacman(&mut self, dir: char) {
-        let (nx, ny) = Self::new_position(self.pacman, dir);
-        match self.board[nx][ny] {
+    /// Returns None if the move would cross a border that doesn't wrap around
+    fn new_position(&self, (x, y): (usize, usize), dir: Direction) -> Option<(usize, usize)> {
+        let [dx, dy] = dir.as_vector();
+        let (mut nx, mut ny) = (x as isize + dx, y as isize + dy);
+        let wraps = nx < 0 || ny < 0 || nx >= H as isize || ny >= W as isize;
+        if wraps
+            && self.rules.wraparound == Wraparound::Tunnels
+            && !self.map.tunnels.contains(&(x, y))
+        {
+            return None;
+        }
+        nx = nx.rem_euclid(H as isize);
+        ny = ny.rem_euclid(W as isize);
+        Some((nx as usize, ny as usize))
+    }
+
+    pub fn move_pacman(&mut self, dir: Direction) -> Result<MoveOutcome, GameError> {
+        if self.ended {
+            return Ok(MoveOutcome::GameOver);
+        }
+        Self::check_bounds(self.pacman)?;
+        let Some(position) = self.new_position(self.pacman, dir) else {
+            return Ok(MoveOutcome::BlockedByWall);
+        };
+        if self.tile(position)? == WALL {
+            return Ok(MoveOutcome::BlockedByWall);
+        }
+        self.advance_timers();
+        let outcome = self.step_pacman(dir)?;
+        self.check_turn_limit();
+        self.record(Input::MovePacman(dir));
+        Ok(outcome)
+    }
+
+    /// Pacman stays where it is, which still counts as one of its turns
+    pub fn pass_pacman(&mut self) -> Result<MoveOutcome, GameError> {
+        if self.ended {
+            return Ok(MoveOutcome::GameOver);
+        }
+        self.advance_timers();
+        self.check_turn_limit();
+        self.record(Input::PassPacman);
+        Ok(MoveOutcome::Moved)
+    }
+
+    /// Ends the game, as pacman gave up
+    pub fn forfeit(&mut self) {
+        if self.ended {
+            return;
+        }
+        self.ended = true;
+        self.record(Input::Forfeit);
+    }
+
+    fn step_pacman(&mut self, dir: Direction) -> Result<MoveOutcome, GameError> {
+        if self.ended {
+            return Ok(MoveOutcome::GameOver);
+        }
+        Self::check_bounds(self.pacman)?;
+        let Some((nx, ny)) = self.new_position(self.pacman, dir) else {
+            return Ok(MoveOutcome::BlockedByWall);
+        };
+        let mut outcome = MoveOutcome::Moved;
+        match self.tile((nx, ny))? {
             WALL => {
-                return;
+                return Ok(MoveOutcome::BlockedByWall);
             }
             PACDOT => {
-                self.score += 1;
                 self.board[nx][ny] = EMPTY;
+                self.eat_dot();
+                outcome = MoveOutcome::AteDot;
             }
-            EMPTY => {}
-            _ => panic!("Invalid byte!"),
+            POWER_PELLET => {
+                self.board[nx][ny] = EMPTY;
+                self.score += self.map.scoring.power_pellet;
+                self.frightened = self.map.frightened_turns;
+                self.ghosts_eaten = 0;
+                outcome = MoveOutcome::AtePowerPellet;
+            }
+            _ => {}
         }
         self.pacman = (nx, ny);
-        self.update_game_state();
+        if self.fruit.is_some() && self.pacman == self.map.fruit.tile {
+            self.fruit = None;
+            self.score += self.map.scoring.fruit;
+            outcome = MoveOutcome::AteFruit;
+        }
+        Ok(self.update_game_state().unwrap_or(outcome))
     }
 
-    pub fn move_local_ghost(&mut self, dir: char) {
-        let (nx, ny) = Self::new_position(self.local_ghost, dir);
-        match self.board[nx][ny] {
-            WALL => {
-                return;
-            }
-            PACDOT => {}
-            EMPTY => {}
-            _ => panic!("Invalid byte!"),
+    pub fn move_ghost(&mut self, id: usize, dir: Direction) -> Result<MoveOutcome, GameError> {
+        let outcome = self.step_ghost(id, dir)?;
+        if outcome != MoveOutcome::GameOver {
+            self.record(Input::MoveGhost(id, dir));
         }
-        self.local_ghost = (nx, ny);
-        self.update_game_state();
+        Ok(outcome)
     }
 
-    pub fn move_remote_ghost(&mut self, dir: char) {
-        let Some(remote_ghost) = &mut self.remote_ghost else { return; };
-        let (nx, ny) = Self::new_position(*remote_ghost, dir);
-        match self.board[nx][ny] {
-            WALL => {
-                return;
+    fn step_ghost(&mut self, id: usize, dir: Direction) -> Result<MoveOutcome, GameError> {
+        if self.ended {
+            return Ok(MoveOutcome::GameOver);
+        }
+        let Some(idx) = self.ghosts.iter().position(|ghost| ghost.id == id) else {
+            return Err(GameError::UnknownGhost(id));
+        };
+        Self::check_bounds(self.ghosts[idx].position)?;
+        let Some((nx, ny)) = self.new_position(self.ghosts[idx].position, dir) else {
+            return Ok(MoveOutcome::BlockedByWall);
+        };
+        if self.tile((nx, ny))? == WALL {
+            return Ok(MoveOutcome::BlockedByWall);
+        }
+        self.ghosts[idx].position = (nx, ny);
+        Ok(self.update_game_state().unwrap_or(MoveOutcome::Moved))
+    }
+
+    /// Moves an AI ghost in a direction chosen by the game's random number generator
+    pub fn move_ai_ghost(&mut self, id: usize) -> Result<MoveOutcome, GameError> {
+        if self.ended {
+            return Ok(MoveOutcome::GameOver);
+        }
+        if !self.ghosts.iter().any(|ghost| ghost.id == id) {
+            return Err(GameError::UnknownGhost(id));
+        }
+        let dir = self.rng.direction();
+        let outcome = self.step_ghost(id, dir)?;
+        self.record(Input::MoveAiGhost(id));
+        Ok(outcome)
+    }
+
+    /// Direction pacman keeps moving to on every tick
+    pub fn set_pacman_direction(&mut self, dir: Direction) {
+        if self.ended {
+            return;
+        }
+        self.pacman_direction = Some(dir);
+        self.record(Input::SetPacmanDirection(dir));
+    }
+
+    /// Direction the ghost keeps moving to on every tick
+    pub fn set_ghost_direction(&mut self, id: usize, dir: Direction) -> Result<(), GameError> {
+        if self.ended {
+            return Ok(());
+        }
+        let Some(ghost) = self.ghosts.iter_mut().find(|ghost| ghost.id == id) else {
+            return Err(GameError::UnknownGhost(id));
+        };
+        ghost.direction = Some(dir);
+        self.record(Input::SetGhostDirection(id, dir));
+        Ok(())
+    }
+
+    /// Advances a real-time game by one tick: AI ghosts pick a new direction, then pacman and
+    /// every ghost move in their current direction, ghosts only on the ticks their speed allows.
+    /// Pacman and a ghost crossing each other also counts as a catch
+    pub fn tick(&mut self) -> Result<(), GameError> {
+        if self.ended {
+            return Ok(());
+        }
+        self.ticks += 1;
+        self.record(Input::Ticks(1));
+        self.advance_timers();
+        for idx in 0..self.ghosts.len() {
+            if self.ghosts[idx].controller == GhostController::Ai {
+                self.ghosts[idx].direction = Some(self.rng.direction());
+            }
+        }
+        let pacman_before = self.pacman;
+        let lives = self.lives;
+        if let Some(dir) = self.pacman_direction {
+            self.step_pacman(dir)?;
+        }
+        let ghosts_move = self.ghosts_move_in(self.turns);
+        let moves: Vec<(usize, (usize, usize), Option<Direction>)> = if ghosts_move {
+            self.ghosts
+                .iter()
+                .map(|ghost| (ghost.id, ghost.position, ghost.direction))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        for (id, ghost_before, dir) in moves {
+            // Everyone went back to their spawn
+            if self.ended || self.lives != lives {
+                break;
+            }
+            if let Some(dir) = dir {
+                self.step_ghost(id, dir)?;
+            }
+            let Some(idx) = self.ghosts.iter().position(|ghost| ghost.id == id) else { continue; };
+            if ghost_before == self.pacman && self.ghosts[idx].position == pacman_before {
+                self.catch(idx);
             }
-            PACDOT => {}
-            EMPTY => {}
-            _ => panic!("Invalid byte!"),
         }
-        *remote_ghost = (nx, ny);
-        self.update_game_state();
+        self.check_turn_limit();
+        Ok(())
     }
 
-    pub fn add_remote_ghost(&mut self) {
-        if self.remote_ghost.is_none() {
-            self.remote_ghost = Some((3, 3)); // totally random starting position
+    /// Adds a ghost controlled by `user` to the end of the turn order
+    /// Returns its id, or None if every remote ghost slot is taken or the game is over
+    pub fn add_remote_ghost(&mut self, user: &str) -> Option<usize> {
+        if let Some(id) = self.remote_ghost_id(user) {
+            return Some(id);
+        }
+        if self.ended {
+            return None;
+        }
+        let remote_ghosts = self
+            .ghosts
+            .iter()
+            .filter(|ghost| ghost.controller != GhostController::Ai)
+            .count();
+        if remote_ghosts >= self.rules.max_remote_ghosts {
+            return None;
         }
+        let spawn = *REMOTE_GHOST_SPAWNS
+            .iter()
+            .find(|&&spawn| self.ghosts.iter().all(|ghost| ghost.spawn != spawn))?;
+        let id = self.next_ghost_id;
+        self.next_ghost_id += 1;
+        self.ghosts.push(Ghost {
+            id,
+            controller: GhostController::Remote(user.to_owned()),
+            spawn,
+            position: spawn,
+            direction: None,
+        });
+        self.record(Input::AddRemoteGhost(user.to_owned()));
+        Some(id)
     }
 
-    pub fn remove_remote_ghost(&mut self) {
-        self.remote_ghost = None;
+    /// Ghosts stay in a game that is over, so nothing is recorded after its end
+    pub fn remove_remote_ghost(&mut self, user: &str) {
+        if self.ended || self.remote_ghost_id(user).is_none() {
+            return;
+        }
+        self.ghosts
+            .retain(|ghost| !matches!(&ghost.controller, GhostController::Remote(u) if u == user));
+        self.record(Input::RemoveRemoteGhost(user.to_owned()));
     }
 }
 
@@ -168,3 +1176,139 @@ impl Default for Game {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Direction::*;
+
+    fn rules(mode: GameMode) -> GameRules {
+        GameRules {
+            mode,
+            ai_ghosts: 2,
+            lives: 3,
+            ..GameRules::default()
+        }
+    }
+
+    /// The same moves of pacman and of a remote ghost, with the AI ghosts moving in between
+    fn play(seed: u64) -> Game {
+        let mut game = Game::with_rules(rules(GameMode::TurnBased), seed).unwrap();
+        let ghost = game.add_remote_ghost("bob").unwrap();
+        for dir in [West, West, East, East, East, North, South, East] {
+            game.move_pacman(dir).unwrap();
+            game.move_ghost(ghost, dir).unwrap();
+            game.move_ai_ghost(0).unwrap();
+            game.move_ai_ghost(1).unwrap();
+        }
+        game
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_the_same_hash() {
+        let game = play(7);
+        assert_eq!(game.state_hash(), play(7).state_hash());
+        assert_ne!(game.state_hash(), play(8).state_hash());
+
+        let mut replayed = Game::with_rules(rules(GameMode::TurnBased), 7).unwrap();
+        for record in game.history() {
+            replayed.apply(&record.input).unwrap();
+        }
+        assert_eq!(replayed.state_hash(), game.state_hash());
+        assert_eq!(replayed.score(), game.score());
+    }
+
+    #[test]
+    fn real_time_games_are_deterministic() {
+        let play = || {
+            let mut game = Game::with_rules(rules(GameMode::RealTime), 7).unwrap();
+            let ghost = game.add_remote_ghost("bob").unwrap();
+            game.set_pacman_direction(West);
+            game.set_ghost_direction(ghost, East).unwrap();
+            for _ in 0..10 {
+                game.tick().unwrap();
+            }
+            game.set_pacman_direction(East);
+            for _ in 0..10 {
+                game.tick().unwrap();
+            }
+            game
+        };
+        let game = play();
+        assert_eq!(game.ticks(), 20);
+        assert_eq!(game.state_hash(), play().state_hash());
+    }
+
+    #[test]
+    fn hash_covers_the_whole_state() {
+        let game = play(7);
+        let changes: [fn(&mut Game); 6] = [
+            |game| {
+                game.rng.next_u64();
+            },
+            |game| game.frightened += 1,
+            |game| game.fruit = Some(game.fruit.map_or(1, |turns| turns + 1)),
+            |game| game.ghosts_eaten += 1,
+            |game| game.pacman_direction = Some(South),
+            |game| game.ghosts[0].direction = Some(North),
+        ];
+        for change in changes {
+            let mut changed = game.clone();
+            change(&mut changed);
+            assert_ne!(changed.state_hash(), game.state_hash());
+        }
+    }
+
+    /// A corridor along the fourth line, with a dead end going up from it to (0, 6) and a
+    /// closed off tile on (0, 20). Only the tunnels of the map wrap around, and it has none
+    fn corridor() -> Game {
+        let mut board = [[WALL; W]; H];
+        board[3] = [PACDOT; W];
+        for line in &mut board[..3] {
+            line[6] = PACDOT;
+        }
+        board[2][13] = EMPTY;
+        board[0][20] = PACDOT;
+        board[4][20] = PACDOT;
+        let map = Map {
+            board: board.iter().map(|line| String::from_utf8(line.to_vec()).unwrap()).collect(),
+            tunnels: Vec::new(),
+            ..Map::default()
+        };
+        let rules = GameRules {
+            wraparound: Wraparound::Tunnels,
+            ..GameRules::default()
+        };
+        Game::with_map(map, rules, 0).unwrap()
+    }
+
+    #[test]
+    fn shortest_path_follows_the_corridor() {
+        let game = corridor();
+        let path = game.shortest_path(PACMAN_SPAWN, (0, 6)).unwrap();
+        let expected: Vec<_> = [South].into_iter().chain([West; 7]).chain([North; 3]).collect();
+        assert_eq!(path, expected);
+        assert_eq!(game.shortest_path((3, 5), (3, 5)).unwrap(), []);
+        assert_eq!(game.shortest_path(PACMAN_SPAWN, (0, 20)), None);
+        // Walls can't be reached either
+        assert_eq!(game.shortest_path(PACMAN_SPAWN, (0, 0)), None);
+    }
+
+    #[test]
+    fn distance_map_counts_moves() {
+        let game = corridor();
+        let distances = game.distance_map(PACMAN_SPAWN);
+        assert_eq!(distances.get(PACMAN_SPAWN), Some(0));
+        assert_eq!(distances.get((0, 6)), Some(11));
+        assert_eq!(distances.get((4, 20)), Some(9));
+        assert_eq!(distances.get((3, 0)), Some(14));
+        assert_eq!(distances.get((0, 20)), None);
+        assert_eq!(distances.get((0, 0)), None);
+
+        // Leaving through any border wraps around when the rules allow it
+        let mut game = corridor();
+        game.rules.wraparound = Wraparound::Everywhere;
+        assert_eq!(game.distance_map((3, 0)).get((3, W - 1)), Some(1));
+        assert_eq!(game.shortest_path((3, 0), (3, W - 1)).unwrap(), [West]);
+    }
+}
@@ -3,10 +3,12 @@
 
 pub mod client_server;
 pub mod game;
+pub mod peer;
+pub mod replay;
 pub mod server_client;
 
 use std::{
-    io::Write,
+    io::{ErrorKind, Read, Write},
     net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
@@ -15,6 +17,13 @@ use serde::{Deserialize, Serialize};
 
 pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
 pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(20);
+/// Largest message sent in a single UDP datagram
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+/// Largest message sent through TCP, big enough for the results of long matches, which carry
+/// every input
+pub const MAX_STREAM_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// Number of results in each page of the leaderboard
+pub const LEADERBOARD_PAGE_SIZE: usize = 10;
 
 /// Each connection has a listener
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Copy)]
@@ -27,22 +36,75 @@ pub fn current_time() -> Duration {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
 }
 
+/// Year, month and day of a day counted from UNIX_EPOCH, from Howard Hinnant's `civil_from_days`
+pub fn civil_from_days(day: u64) -> (u64, u64, u64) {
+    let days = day + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    (year_of_era + era * 400 + u64::from(month <= 2), month, day)
+}
+
 impl Connection {
     pub fn send<T: PacmanMessage>(&self, msg: T) {
+        let _ = self.try_send(msg);
+    }
+
+    /// Same as `send`, but tells why the message couldn't be sent, such as being too large
+    pub fn try_send<T: PacmanMessage>(&self, msg: T) -> std::io::Result<()> {
+        let bytes = msg.to_bytes();
         match self {
             Connection::Udp(addr) => {
-                let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
-                let _ = socket.send_to(&msg.to_bytes(), addr);
+                if bytes.len() > MAX_DATAGRAM_SIZE {
+                    return Err(ErrorKind::InvalidInput.into());
+                }
+                let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+                socket.send_to(&bytes, addr)?;
+                Ok(())
             }
             Connection::Tcp(addr) => {
-                let Ok(mut stream) = TcpStream::connect(addr) else {return; };
-                let _ = stream.write(&msg.to_bytes());
+                let mut stream = TcpStream::connect(addr)?;
+                write_frame(&mut stream, &bytes)
             }
         }
     }
+
+    /// Address of the listener, the server listens to both protocols on the same port
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Connection::Udp(addr) | Connection::Tcp(addr) => *addr,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Writes a message to a TCP stream, prefixed by its length as a big endian `u32`
+pub fn write_frame(stream: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+    if bytes.len() > MAX_STREAM_MESSAGE_SIZE {
+        return Err(ErrorKind::InvalidInput.into());
+    }
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(bytes)
+}
+
+/// Reads a message written by `write_frame`
+pub fn read_frame(stream: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_STREAM_MESSAGE_SIZE {
+        return Err(ErrorKind::InvalidData.into());
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Direction {
     North,
     West,
@@ -51,6 +113,13 @@ pub enum Direction {
 }
 
 impl Direction {
+    pub const ALL: [Direction; 4] = [
+        Direction::North,
+        Direction::West,
+        Direction::South,
+        Direction::East,
+    ];
+
     pub fn as_vector(&self) -> [isize; 2] {
         use Direction::*;
         match self {
@@ -60,12 +129,105 @@ impl Direction {
             South => [1, 0],
         }
     }
+
+    /// Direction typed by the player, using wasd keys
+    pub fn from_key(key: char) -> Option<Self> {
+        use Direction::*;
+        match key {
+            'w' => Some(North),
+            'a' => Some(West),
+            's' => Some(South),
+            'd' => Some(East),
+            _ => None,
+        }
+    }
+
+    pub fn key(&self) -> char {
+        use Direction::*;
+        match self {
+            North => 'w',
+            West => 'a',
+            South => 's',
+            East => 'd',
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct LeaderboardEntry {
     pub score: u64,
     pub user: String,
+    /// `Game::state_hash` of the final state of the match, kept to settle disputes
+    #[serde(default)]
+    pub hash: Option<u64>,
+    /// When the match ended, in seconds since UNIX_EPOCH, 0 for results kept by older servers
+    #[serde(default)]
+    pub time: u64,
+    /// Users who played as ghosts in the match
+    #[serde(default)]
+    pub opponents: Vec<String>,
+    #[serde(default)]
+    pub map: String,
+    /// Seconds from the creation of the game to its result
+    #[serde(default)]
+    pub duration: u64,
+}
+
+/// What a user did in every match that finished with a verified result
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct UserStats {
+    pub pacman_games: u64,
+    pub ghost_games: u64,
+    /// Lives of pacman taken while playing as a ghost
+    pub catches: u64,
+    /// Scores of the games played as pacman
+    pub best_score: u64,
+    pub total_score: u64,
+    /// Eaten as pacman
+    pub dots_eaten: u64,
+    /// Seconds, as pacman or as a ghost
+    pub time_played: u64,
+    pub ratings: Ratings,
+}
+
+/// Skill of a user in each role, from the matches against other users. Matches against AI
+/// ghosts only don't count
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct Ratings {
+    pub pacman: f64,
+    pub ghost: f64,
+    /// Rated matches played in each role
+    pub pacman_games: u64,
+    pub ghost_games: u64,
+}
+
+pub const INITIAL_RATING: f64 = 1500.0;
+/// Rated matches in a role before a rating settles, until then it changes faster
+pub const PROVISIONAL_GAMES: u64 = 10;
+
+impl Default for Ratings {
+    fn default() -> Self {
+        Self {
+            pacman: INITIAL_RATING,
+            ghost: INITIAL_RATING,
+            pacman_games: 0,
+            ghost_games: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Pacman,
+    Ghost,
+}
+
+impl UserStats {
+    pub fn average_score(&self) -> Option<f64> {
+        (self.pacman_games > 0).then(|| self.total_score as f64 / self.pacman_games as f64)
+    }
 }
 
 pub trait PacmanMessage: Sized + std::fmt::Debug {
@@ -0,0 +1,269 @@
+//! Messages exchanged directly between the peers of a match: the pacman, who hosts it, and the
+//! ghosts and spectators connected to it
+//! Every message is a line of JSON
+
+use std::{
+    io::{BufRead, Read, Write},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    game::{Game, GameError, Input, InputRecord},
+    Direction, MAX_STREAM_MESSAGE_SIZE,
+};
+
+/// How long a ghost has to connect to the pacman after the server gives it a join ticket
+pub const TICKET_LIFETIME: Duration = Duration::from_secs(30);
+
+/// First message sent to the pacman's listener
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Hello {
+    /// The ticket is the one the server gave the ghost when it joined the game
+    Ghost { user: String, ticket: u64 },
+    /// The ticket is the one the server gave the spectator when it started watching the game
+    Spectator { user: String, ticket: u64 },
+}
+
+/// State of the match sent by the pacman to ghosts and spectators
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub update: Update,
+    pub spectators: usize,
+    /// `Game::state_hash` of the pacman's copy of the game
+    pub hash: u64,
+    /// Set when the match ends because a copy of the game diverged from the pacman's
+    pub desync: Option<Desync>,
+}
+
+/// What a peer needs to bring its copy of the game up to date
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Update {
+    /// The whole game, in the first snapshot a peer receives
+    Game(Box<Game>),
+    /// Records of the history from index `from` on
+    Inputs {
+        from: usize,
+        records: Vec<InputRecord>,
+    },
+}
+
+impl Snapshot {
+    /// `sent` is how many records of the history the peer already received, None if it hasn't
+    /// received the game yet
+    /// The last record it received is sent again, since more ticks may have been merged into it
+    #[must_use]
+    pub fn new(game: &Game, sent: Option<usize>, spectators: usize) -> Self {
+        let update = match sent {
+            None => Update::Game(Box::new(game.clone())),
+            Some(sent) => {
+                let from = sent.saturating_sub(1).min(game.history().len());
+                Update::Inputs {
+                    from,
+                    records: game.history()[from..].to_vec(),
+                }
+            }
+        };
+        Self {
+            update,
+            spectators,
+            hash: game.state_hash(),
+            desync: None,
+        }
+    }
+}
+
+/// Copy of a match kept by a ghost or spectator, built only from the inputs in the history of
+/// the snapshots, so it tells whether the pacman's copy still follows the rules
+pub struct Replica {
+    game: Game,
+    records: usize, // Records of the history applied so far
+    ticks: u64,     // Ticks of the last record applied, more may be merged into it later
+}
+
+impl Replica {
+    /// Starts from a new game with the same map, rules and seed as `game`, the one in the first
+    /// snapshot
+    pub fn new(game: &Game) -> Result<Self, GameError> {
+        Ok(Self {
+            game: Game::with_map(game.map().clone(), game.rules().clone(), game.seed())?,
+            records: 0,
+            ticks: 0,
+        })
+    }
+
+    /// The replica's copy of the game
+    #[must_use]
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    /// `Game::state_hash` of the replica
+    #[must_use]
+    pub fn hash(&self) -> u64 {
+        self.game.state_hash()
+    }
+
+    /// Applies the inputs of the snapshot not applied yet and compares the result with the hash
+    /// received, and with the game if the snapshot carries it
+    /// Returns the hash of the replica if they differ
+    pub fn check(&mut self, snapshot: &Snapshot) -> Result<(), u64> {
+        let (from, records) = match &snapshot.update {
+            Update::Game(game) if game.state_hash() != snapshot.hash => {
+                return Err(self.game.state_hash())
+            }
+            Update::Game(game) => (0, game.history()),
+            Update::Inputs { from, records } => (*from, records.as_slice()),
+        };
+        // The history only grows, so no record may be skipped or taken back
+        if from > self.records
+            || from + records.len() < self.records
+            || self.apply(from, records).is_err()
+            || self.game.state_hash() != snapshot.hash
+        {
+            return Err(self.game.state_hash());
+        }
+        Ok(())
+    }
+
+    /// `records` start at index `from` of the history, which is at most `self.records`
+    fn apply(&mut self, from: usize, records: &[InputRecord]) -> Result<(), GameError> {
+        let applied = self.records - from;
+        if let Some(Input::Ticks(n)) = applied.checked_sub(1).map(|i| &records[i].input) {
+            for _ in self.ticks..*n {
+                self.game.tick()?;
+            }
+            self.ticks = *n;
+        }
+        for record in &records[applied..] {
+            self.game.apply_record(record)?;
+            self.ticks = if let Input::Ticks(n) = record.input { n } else { 0 };
+        }
+        self.records = from + records.len();
+        Ok(())
+    }
+}
+
+/// Sent by a ghost to the pacman
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GhostMessage {
+    /// Move of a turn-based game, None to stand still, with the hash of the ghost's copy of the
+    /// game after the move
+    Turn {
+        direction: Option<Direction>,
+        hash: u64,
+    },
+    /// Direction change in a real-time game
+    Direction(Direction),
+    /// Hash of the ghost's copy of a real-time game after the snapshot of the given tick, so the
+    /// pacman can compare it with its own
+    Hash { tick: u64, hash: u64 },
+    /// The ghost's copy of the game diverged from the pacman's
+    Desync(Desync),
+}
+
+/// Two copies of the same match that stopped agreeing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Desync {
+    /// Turn, or tick in real-time games, in which the copies diverged
+    pub turn: u64,
+    /// Peer whose copy diverged from the pacman's
+    pub user: String,
+    /// Hash of the pacman's copy
+    pub expected: u64,
+    /// Hash of the other peer's copy
+    pub received: u64,
+}
+
+impl std::fmt::Display for Desync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cópia de {} divergiu no turno {} (esperado {:016x}, recebido {:016x})",
+            self.user, self.turn, self.expected, self.received
+        )
+    }
+}
+
+pub fn write_message<T: Serialize>(stream: &mut impl Write, msg: &T) -> std::io::Result<()> {
+    let msg_str = serde_json::to_string(msg)? + "\n";
+    stream.write_all(msg_str.as_bytes())
+}
+
+/// Returns None if the connection was closed
+/// Lines longer than MAX_STREAM_MESSAGE_SIZE are rejected without being buffered whole
+pub fn read_message<T: DeserializeOwned>(reader: &mut impl BufRead) -> std::io::Result<Option<T>> {
+    let mut line = String::new();
+    let limit = MAX_STREAM_MESSAGE_SIZE as u64 + 1;
+    if reader.take(limit).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && line.len() > MAX_STREAM_MESSAGE_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "message too long",
+        ));
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{GameMode, GameRules};
+
+    fn real_time_game() -> Game {
+        let rules = GameRules {
+            mode: GameMode::RealTime,
+            ai_ghosts: 2,
+            ..GameRules::default()
+        };
+        Game::with_rules(rules, 3).unwrap()
+    }
+
+    #[test]
+    fn replica_follows_the_inputs_sent_after_the_game() {
+        let mut game = real_time_game();
+        game.add_remote_ghost("bob");
+        let mut sent = None;
+        let mut send = |game: &Game| {
+            let snapshot = Snapshot::new(game, sent, 0);
+            sent = Some(game.history().len());
+            snapshot
+        };
+        let first = send(&game);
+        let Update::Game(first_game) = &first.update else { panic!("game not sent") };
+        let mut replica = Replica::new(first_game).unwrap();
+        replica.check(&first).unwrap();
+        for tick in 0..20 {
+            if tick % 7 == 0 {
+                game.set_pacman_direction(Direction::West);
+            }
+            game.tick().unwrap();
+            let snapshot = send(&game);
+            assert!(matches!(snapshot.update, Update::Inputs { .. }));
+            replica.check(&snapshot).unwrap();
+        }
+        assert_eq!(replica.hash(), game.state_hash());
+        assert_eq!(replica.game().history().len(), game.history().len());
+    }
+
+    #[test]
+    fn replica_rejects_a_rewritten_history() {
+        let mut game = real_time_game();
+        let first = Snapshot::new(&game, None, 0);
+        let Update::Game(first_game) = &first.update else { panic!("game not sent") };
+        let mut replica = Replica::new(first_game).unwrap();
+        replica.check(&first).unwrap();
+        game.set_pacman_direction(Direction::East);
+        game.tick().unwrap();
+        replica.check(&Snapshot::new(&game, Some(0), 0)).unwrap();
+        let mut skipped = Snapshot::new(&game, Some(0), 0);
+        skipped.update = Update::Inputs {
+            from: 0,
+            records: Vec::new(),
+        };
+        assert!(replica.check(&skipped).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Recording of matches, so they can be watched again after they end
+
+use std::{fs::File, io::Write, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::game::{
+    Game, GameError, GameRules, Input, InputRecord, Map, TICK_INTERVAL,
+};
+
+/// Most steps a replay may have, far more than any match takes. Longer replays are rejected
+/// instead of being simulated
+pub const MAX_REPLAY_STEPS: usize = 1_000_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Replay {
+    pub map: Map,
+    pub rules: GameRules,
+    pub seed: u64,
+    pub pacman: String,
+    pub ghosts: Vec<String>, // Every remote ghost that took part in the match
+    pub inputs: Vec<InputRecord>,
+    pub score: u64,
+}
+
+impl Replay {
+    #[must_use]
+    pub fn from_game(game: &Game, pacman: &str) -> Self {
+        let mut ghosts = Vec::new();
+        for record in game.history() {
+            if let Input::AddRemoteGhost(user) = &record.input {
+                if !ghosts.contains(user) {
+                    ghosts.push(user.clone());
+                }
+            }
+        }
+        Self {
+            map: game.map().clone(),
+            rules: game.rules().clone(),
+            seed: game.seed(),
+            pacman: pacman.to_owned(),
+            ghosts,
+            inputs: game.history().to_vec(),
+            score: game.score(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(path)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let replay_str = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&replay_str)?)
+    }
+
+    /// Number of steps `play` goes through: the initial state, one per tick of a real-time game
+    /// and one per other input. Fails if there are more than `MAX_REPLAY_STEPS`
+    pub fn step_count(&self) -> Result<usize, GameError> {
+        let mut steps: usize = 1;
+        for record in &self.inputs {
+            let record_steps = match record.input {
+                Input::Ticks(n) => usize::try_from(n).unwrap_or(usize::MAX),
+                _ => 1,
+            };
+            steps = steps.saturating_add(record_steps);
+            if steps > MAX_REPLAY_STEPS {
+                return Err(GameError::TooManySteps(MAX_REPLAY_STEPS));
+            }
+        }
+        Ok(steps)
+    }
+
+    /// Simulates the match again, calling `step` with the state of the game after each step
+    /// together with the time it happened, relative to the first input. Each tick of a real-time
+    /// game is a step. Stops early when `step` returns false
+    pub fn play(&self, mut step: impl FnMut(Duration, &Game) -> bool) -> Result<(), GameError> {
+        self.step_count()?;
+        let mut game = Game::with_map(self.map.clone(), self.rules.clone(), self.seed)?;
+        if !step(Duration::ZERO, &game) {
+            return Ok(());
+        }
+        let Some(start) = self.inputs.first().map(|record| record.time) else { return Ok(()); };
+        for record in &self.inputs {
+            let time = record.time.saturating_sub(start);
+            if let Input::Ticks(n) = record.input {
+                for tick in 0..n {
+                    game.tick()?;
+                    if !step(time + TICK_INTERVAL * tick as u32, &game) {
+                        return Ok(());
+                    }
+                }
+            } else {
+                game.apply(&record.input)?;
+                if !step(time, &game) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(inputs: &[Input]) -> Replay {
+        let mut replay = Replay::from_game(&Game::new(), "ana");
+        replay.inputs = inputs
+            .iter()
+            .map(|input| InputRecord {
+                time: Duration::ZERO,
+                input: input.clone(),
+            })
+            .collect();
+        replay
+    }
+
+    #[test]
+    fn steps_are_counted_per_tick() {
+        let replay = replay(&[Input::Ticks(10), Input::PassPacman, Input::Ticks(5)]);
+        assert_eq!(replay.step_count(), Ok(17));
+    }
+
+    #[test]
+    fn absurd_replays_are_rejected() {
+        let too_many = GameError::TooManySteps(MAX_REPLAY_STEPS);
+        let replay = replay(&[Input::Ticks(u64::MAX), Input::Ticks(u64::MAX)]);
+        assert_eq!(replay.step_count(), Err(too_many.clone()));
+        assert_eq!(replay.play(|_, _| true), Err(too_many));
+    }
+}
@@ -2,6 +2,8 @@ use std::net::SocketAddr;
 
 use serde::{Deserialize, Serialize};
 
+use crate::game::GameRules;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
     Heartbeat,
@@ -12,8 +14,13 @@ pub enum Message {
     LogoutResponse,
     CreateGameResponse(CreateGameResponse),
     JoinGameResponse(JoinGameResponse),
+    SpectateGameResponse(SpectateGameResponse),
     ConnectedUsersResponse(ConnectedUsersResponse),
     LeaderboardResponse(LeaderboardResponse),
+    ProfileResponse(ProfileResponse),
+    RatingLeaderboardResponse(RatingLeaderboardResponse),
+    ResultResponse(ResultResponse),
+    JoinTicket(JoinTicket),
     NotConnected,
 }
 
@@ -25,34 +32,127 @@ pub enum CreateUserResponse {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum LoginResponse {
-    Ok,
+    Ok(String), // Name of the user as it was created, which may differ in case from the one typed
     Err,
+    /// Too many wrong passwords for the user or from the address, they may try again at this
+    /// time in seconds since UNIX_EPOCH
+    Locked(u64),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ChangePasswordResponse {
     Ok,
     Err,
+    /// As in `LoginResponse::Locked`
+    Locked(u64),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConnectedUsersResponse {
-    pub users: Box<[String]>,
+    pub users: Box<[ConnectedUser]>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectedUser {
+    pub description: String, // Name of the user and what they are doing
+    pub ratings: crate::Ratings,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CreateGameResponse {
-    Ok,
+    Ok(u64), // Seed of the new game
     Err,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum JoinGameResponse {
-    Ok(SocketAddr),
+    // Rules chosen by the pacman, the game must follow them, and the ticket to show the pacman
+    Ok(SocketAddr, GameRules, u64),
+    Err,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SpectateGameResponse {
+    // Address of the pacman and the ticket to show them
+    Ok(SocketAddr, u64),
     Err,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct LeaderboardResponse {
-    pub top10: Box<[crate::LeaderboardEntry]>,
+pub enum LeaderboardResponse {
+    Ok(LeaderboardPage),
+    UnknownSeason,
+    Err,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SeasonInfo {
+    pub number: u64, // Counted from 1
+    /// Seconds since UNIX_EPOCH. A season that didn't end yet ends at `end` if nobody changes
+    /// the length of the seasons before that
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaderboardPage {
+    /// Position of the first entry among every result of the query, starting at 1
+    pub first_position: usize,
+    pub entries: Box<[crate::LeaderboardEntry]>,
+    /// Number of results of the query
+    pub total: usize,
+    /// Position of the best result of the user who asked, ignoring the `user` of the query
+    pub rank: Option<usize>,
+    /// Season of the results, if the query was for a season
+    pub season: Option<SeasonInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ProfileResponse {
+    Ok(String, crate::UserStats), // Name of the user as it was created, and their statistics
+    UnknownUser,
+    Err,
+}
+
+/// Answer to the result of a game, `AddLeaderboardEntry`
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ResultResponse {
+    /// The result was verified and added to the leaderboard
+    Ok,
+    /// Why the result was not added to the leaderboard
+    Rejected(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RatingLeaderboardResponse {
+    Ok(RatingPage),
+    Err,
+}
+
+/// Users with at least one rated match in the role, best rated first
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RatingPage {
+    pub role: crate::Role,
+    /// Position of the first entry, starting at 1
+    pub first_position: usize,
+    pub entries: Box<[RatingEntry]>,
+    pub total: usize,
+    /// Position of the user who asked
+    pub rank: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RatingEntry {
+    pub user: String,
+    pub rating: f64,
+    pub games: u64,
+}
+
+/// Sent to the pacman when a ghost joins its game, or a spectator starts watching it. The pacman
+/// only accepts the connection of the user if it presents the same ticket
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JoinTicket {
+    pub user: String,
+    pub ticket: u64,
+    pub spectator: bool,
 }
@@ -4,7 +4,7 @@ use clap::Parser;
 use log::LevelFilter;
 use log4rs::{
     append::file::FileAppender,
-    config::{Appender, Root},
+    config::{Appender, Logger, Root},
     Config,
 };
 use std::path::{Path, PathBuf};
@@ -12,10 +12,16 @@ use std::path::{Path, PathBuf};
 #[derive(Parser, Debug)]
 #[command(about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
-    port: u16,
+    #[arg(short, long, required_unless_present = "import_files")]
+    port: Option<u16>,
     #[arg(short, long, default_value = "pacman_server_config")]
     config_dir: PathBuf,
+    /// Maximum number of ghosts that may join a single game (at most 4)
+    #[arg(short, long, default_value_t = 4)]
+    max_ghosts: usize,
+    /// Copy the users and leaderboard kept in files into the SQLite database, then exit
+    #[arg(long)]
+    import_files: bool,
 }
 
 fn main() {
@@ -37,8 +43,16 @@ fn main() {
 
     // Setup logging
     let log_file = FileAppender::builder().build("log/log.txt").unwrap();
+    // Failed logins and password changes also go to their own file
+    let audit_file = FileAppender::builder().build("log/audit.txt").unwrap();
     let config = Config::builder()
         .appender(Appender::builder().build("log_file", Box::new(log_file)))
+        .appender(Appender::builder().build("audit_file", Box::new(audit_file)))
+        .logger(
+            Logger::builder()
+                .appender("audit_file")
+                .build("audit", LevelFilter::Info),
+        )
         .build(
             Root::builder()
                 .appender("log_file")
@@ -47,7 +61,14 @@ fn main() {
         .unwrap();
     log4rs::init_config(config).unwrap();
 
+    if args.import_files {
+        log::info!("Importing files into the SQLite database");
+        server::import_files();
+        return;
+    }
+    let Some(port) = args.port else { unreachable!() };
+
     log::info!("New server is initialized");
-    server::run(args.port);
+    server::run(port, args.max_ghosts);
     log::info!("Server is terminating!");
 }
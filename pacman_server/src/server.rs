@@ -1,39 +1,129 @@
+mod config;
 mod database;
 mod game;
 mod heartbeat;
 mod listeners;
+mod password;
+mod rating;
+mod results;
+mod season;
+mod storage;
+mod throttle;
+mod username;
 
-use std::sync::{Arc, Mutex};
+use std::{
+    net::IpAddr,
+    path::Path,
+    sync::{mpsc::channel, Arc, Mutex},
+    time::Duration,
+};
 
-use database::Database;
+use database::{CreateUserError, Database};
 use pacman_communication::{
-    client_server,
+    client_server::{self, GameResult},
+    current_time, Connection, LeaderboardEntry,
     server_client::{
-        self, ChangePasswordResponse, ConnectedUsersResponse, CreateGameResponse,
-        CreateUserResponse, JoinGameResponse, LeaderboardResponse, LoginResponse,
+        self, ChangePasswordResponse, ConnectedUser, ConnectedUsersResponse, CreateGameResponse,
+        CreateUserResponse, JoinGameResponse, JoinTicket, LeaderboardResponse, LoginResponse,
+        ProfileResponse, RatingLeaderboardResponse, ResultResponse, SpectateGameResponse,
     },
+    Ratings,
 };
 
-use crate::server::game::GameStatus;
+use crate::server::{
+    game::{GameStatus, HostedGame},
+    results::MatchSummary,
+};
 
-pub fn run(port: u16) {
-    let mut database = Database::new();
+/// Handled in order by the main loop
+pub enum Event {
+    /// Received by a listener, with the address it came from
+    Message(IpAddr, client_server::Message),
+    /// A result whose match was simulated again away from the main loop
+    Verified(Verified),
+}
 
-    let conn_table = Arc::new(Mutex::new(game::ConnectionTable::new()));
+pub struct Verified {
+    conn: Connection,
+    user: String,
+    /// Entry for the leaderboard, with what the simulation tells about the players, or why the
+    /// result was rejected
+    outcome: Result<(LeaderboardEntry, MatchSummary), String>,
+}
+
+/// Simulates the match of the result again, for `Event::Verified`
+fn verify(conn: Connection, game: HostedGame, result: GameResult, duration: Duration) -> Verified {
+    let outcome = results::verify(&result, &game, duration).map(|summary| {
+        let entry = LeaderboardEntry {
+            score: result.score,
+            user: game.user.clone(),
+            hash: Some(result.hash),
+            time: current_time().as_secs(),
+            opponents: summary.opponents.clone(),
+            map: result.map.name,
+            duration: duration.as_secs(),
+        };
+        (entry, summary)
+    });
+    Verified {
+        conn,
+        user: game.user,
+        outcome,
+    }
+}
+
+/// Copies the users and leaderboard kept in files into the SQLite database, once, before switching
+/// the storage to `sqlite`
+pub fn import_files() {
+    let config = config::ServerConfig::load();
+    // The configuration directory is the current one
+    let dir = Path::new(".");
+    let files = storage::file::FileStorage::open(dir, config.sync)
+        .expect("Failed to open the files to import");
+    let mut sqlite = storage::sqlite::SqliteStorage::open(dir, config.sync)
+        .expect("Failed to open the SQLite database");
+    if let Err(err) = sqlite.import(&files) {
+        log::error!("Import into the SQLite database failed: {err}");
+        panic!("Import into the SQLite database failed: {err}");
+    }
+}
+
+pub fn run(port: u16, max_ghosts: usize) {
+    let config = config::ServerConfig::load();
+    log::info!("Using the {:?} storage", config.storage);
+    let storage = config
+        .storage
+        .open(Path::new("."), config.sync)
+        .expect("Failed to open the storage of the database");
+    let mut database = Database::new(storage, config.usernames, config.seasons);
+    let mut throttle = throttle::Throttle::new(config.login_throttle);
+
+    let conn_table = Arc::new(Mutex::new(game::ConnectionTable::new(max_ghosts)));
 
     heartbeat::setup(conn_table.clone());
 
     // UDP and TCP listeners are abstracted into the same interface, where both of them send messages
     // received through this channel
-    let recv = listeners::start(port);
+    let (send, recv) = channel();
+    listeners::start(port, send.clone());
     loop {
-        let msg = match recv.recv() {
-            Ok(msg) => msg,
+        let event = match recv.recv() {
+            Ok(event) => event,
             Err(err) => {
                 eprintln!("Error on recv: {err}");
                 break;
             }
         };
+        if let Err(err) = database.roll_seasons() {
+            log::error!("Failed to archive the seasons that ended: {err}");
+        }
+        let (source, msg) = match event {
+            Event::Message(source, msg) => (source, msg),
+            Event::Verified(verified) => {
+                record_result(&mut database, verified);
+                continue;
+            }
+        };
         let client_server::Message {
             connection: conn,
             message: msg,
@@ -42,7 +132,8 @@ pub fn run(port: u16) {
         use client_server::MessageEnum::{
             AddLeaderboardEntry, ChangePasswordRequest, ConnectRequest, ConnectedUsersRequest,
             CreateGameRequest, CreateUserRequest, Disconnect, Heartbeat, JoinGameRequest,
-            LeaderboardRequest, LoginRequest, LogoutRequest, QuitGameRequest,
+            LeaderboardRequest, LoginRequest, LogoutRequest, ProfileRequest, QuitGameRequest,
+            RatingLeaderboardRequest, SpectateGameRequest,
         };
         use server_client::Message;
         match msg {
@@ -64,18 +155,57 @@ pub fn run(port: u16) {
                 drop(conn_table);
             }
             CreateUserRequest(req) => {
-                if database.create_user(&req.user, &req.passwd) {
-                    log::info!("Created user {}", &req.user);
-                    conn.send(Message::CreateUserResponse(CreateUserResponse::Ok));
-                } else {
-                    conn.send(Message::CreateUserResponse(CreateUserResponse::Err));
+                match database.create_user(&req.user, &req.passwd) {
+                    Ok(user) => {
+                        log::info!("Created user {user}");
+                        conn.send(Message::CreateUserResponse(CreateUserResponse::Ok));
+                    }
+                    Err(CreateUserError::Storage(err)) => {
+                        log::error!("Failed to create user {:?}: {err}", &req.user);
+                        conn.send(Message::CreateUserResponse(CreateUserResponse::Err));
+                    }
+                    Err(reason) => {
+                        log::info!("User {:?} not created: {reason}", &req.user);
+                        conn.send(Message::CreateUserResponse(CreateUserResponse::Err));
+                    }
                 }
             }
             LoginRequest(req) => {
-                if database.login(&req.user, &req.passwd) {
+                let now = current_time().as_secs();
+                if let Some(until) = throttle.blocked_until(&req.user, source, now) {
+                    log::warn!(
+                        target: throttle::AUDIT,
+                        "Login of {:?} from {source} refused, blocked for {}s",
+                        &req.user,
+                        until - now
+                    );
+                    conn.send(Message::LoginResponse(LoginResponse::Locked(until)));
+                    continue;
+                }
+                let login = match database.login(&req.user, &req.passwd) {
+                    Ok(Some(user)) => {
+                        throttle.succeed(&user);
+                        Some(user)
+                    }
+                    Ok(None) => {
+                        let until = throttle.fail(&req.user, source, now);
+                        log::warn!(
+                            target: throttle::AUDIT,
+                            "Wrong login of {:?} from {source}, blocked for {}s",
+                            &req.user,
+                            until - now
+                        );
+                        None
+                    }
+                    Err(err) => {
+                        log::error!("Login of {:?} failed: {err}", &req.user);
+                        None
+                    }
+                };
+                if let Some(user) = login {
                     let mut conn_table = conn_table.lock().unwrap();
-                    if conn_table.login(&conn, &req.user) {
-                        conn.send(Message::LoginResponse(LoginResponse::Ok));
+                    if conn_table.login(&conn, &user) {
+                        conn.send(Message::LoginResponse(LoginResponse::Ok(user)));
                         drop(conn_table);
                         continue;
                     }
@@ -84,23 +214,58 @@ pub fn run(port: u16) {
                 conn.send(Message::LoginResponse(LoginResponse::Err));
             }
             ChangePasswordRequest(req) => {
-                let conn_table = conn_table.lock().unwrap();
-                if let Some(conn_data) = conn_table.get_connections().get(&conn) {
-                    if let Some(user) = conn_data.user.as_ref() {
-                        if database.change_password(user, &req.old_passwd, &req.new_passwd) {
-                            log::info!(
-                                "User {} with connection {:?} changed password",
-                                &user,
-                                &conn
-                            );
-                            conn.send(Message::ChangePasswordResponse(ChangePasswordResponse::Ok));
-                            drop(conn_table);
-                            continue;
-                        }
+                // Hashing takes a while, the table isn't kept locked meanwhile
+                let conn_table_guard = conn_table.lock().unwrap();
+                let user = conn_table_guard
+                    .get_connections()
+                    .get(&conn)
+                    .and_then(|conn_data| conn_data.user.clone());
+                drop(conn_table_guard);
+                let Some(user) = user else {
+                    conn.send(Message::ChangePasswordResponse(ChangePasswordResponse::Err));
+                    continue;
+                };
+                let now = current_time().as_secs();
+                if let Some(until) = throttle.blocked_until(&user, source, now) {
+                    log::warn!(
+                        target: throttle::AUDIT,
+                        "Password change of {user} from {source} refused, blocked for {}s",
+                        until - now
+                    );
+                    let response = ChangePasswordResponse::Locked(until);
+                    conn.send(Message::ChangePasswordResponse(response));
+                    continue;
+                }
+                let changed = match database.change_password(
+                    &user,
+                    &req.old_passwd,
+                    &req.new_passwd,
+                ) {
+                    Ok(true) => {
+                        throttle.succeed(&user);
+                        true
+                    }
+                    Ok(false) => {
+                        let until = throttle.fail(&user, source, now);
+                        log::warn!(
+                            target: throttle::AUDIT,
+                            "Wrong password to change that of {user} from {source}, \
+                             blocked for {}s",
+                            until - now
+                        );
+                        false
+                    }
+                    Err(err) => {
+                        log::error!("Password change of {user} failed: {err}");
+                        false
                     }
+                };
+                if changed {
+                    log::info!("User {} with connection {:?} changed password", &user, &conn);
+                    conn.send(Message::ChangePasswordResponse(ChangePasswordResponse::Ok));
+                } else {
+                    conn.send(Message::ChangePasswordResponse(ChangePasswordResponse::Err));
                 }
-                drop(conn_table);
-                conn.send(Message::ChangePasswordResponse(ChangePasswordResponse::Err));
             }
             LogoutRequest => {
                 let mut conn_table = conn_table.lock().unwrap();
@@ -130,21 +295,51 @@ pub fn run(port: u16) {
                                 conn_table.get_ghosts().get(user).unwrap()
                             ));
                         }
+                        GameStatus::Spectator => {
+                            users.push(format!(
+                                "{user}: assistindo o jogo de {}",
+                                conn_table.get_spectators().get(user).unwrap()
+                            ));
+                        }
                         GameStatus::Pacman(_) => {
-                            if let Some(other_user) = conn_table.get_pacmans().get(user).unwrap() {
-                                users.push(format!("{user}: em jogo com {other_user}"));
+                            let ghosts = conn_table.get_pacmans().get(user).unwrap();
+                            let spectators = conn_table.spectator_count(user);
+                            let rules = conn_table.get_rules().get(user).unwrap();
+                            if ghosts.is_empty() {
+                                users.push(format!(
+                                    "{user}: em jogo com ninguém (pode ser desafiado, {spectators} espectadores) - regras: {rules}"
+                                ));
+                            } else if ghosts.len() < conn_table.max_ghosts(user) {
+                                users.push(format!(
+                                    "{user}: em jogo com {} (pode ser desafiado, {spectators} espectadores) - regras: {rules}",
+                                    ghosts.join(", ")
+                                ));
                             } else {
                                 users.push(format!(
-                                    "{user}: em jogo com ninguém (pode ser desafiado)"
+                                    "{user}: em jogo com {} ({spectators} espectadores) - regras: {rules}",
+                                    ghosts.join(", ")
                                 ));
                             }
                         }
                     }
                 }
+                let names: Vec<String> = conn_table.get_users().keys().cloned().collect();
                 drop(conn_table);
-                conn.send(Message::ConnectedUsersResponse(ConnectedUsersResponse {
-                    users: users.into_boxed_slice(),
-                }));
+                let users = names
+                    .iter()
+                    .zip(users)
+                    .map(|(user, description)| {
+                        let ratings = database.ratings(user).unwrap_or_else(|err| {
+                            log::error!("Failed to read the ratings of {user}: {err}");
+                            Ratings::default()
+                        });
+                        ConnectedUser {
+                            description,
+                            ratings,
+                        }
+                    })
+                    .collect();
+                conn.send(Message::ConnectedUsersResponse(ConnectedUsersResponse { users }));
             }
             QuitGameRequest => {
                 let mut conn_table = conn_table.lock().unwrap();
@@ -153,8 +348,9 @@ pub fn run(port: u16) {
             }
             CreateGameRequest(req) => {
                 let mut conn_table = conn_table.lock().unwrap();
-                if conn_table.create_game(&conn, req.listener_addr) {
-                    conn.send(Message::CreateGameResponse(CreateGameResponse::Ok));
+                let seed = rand::random();
+                if conn_table.create_game(&conn, req.listener_addr, req.rules, seed) {
+                    conn.send(Message::CreateGameResponse(CreateGameResponse::Ok(seed)));
                 } else {
                     conn.send(Message::CreateGameResponse(CreateGameResponse::Err));
                 }
@@ -162,21 +358,142 @@ pub fn run(port: u16) {
             }
             JoinGameRequest(req) => {
                 let mut conn_table = conn_table.lock().unwrap();
-                if let Some(addr) = conn_table.join_game(&conn, &req.pacman) {
-                    conn.send(Message::JoinGameResponse(JoinGameResponse::Ok(addr)));
+                if let Some((addr, rules, ticket)) = conn_table.join_game(&conn, &req.pacman) {
+                    let user = conn_table.get_connections()[&conn].user.clone().unwrap();
+                    let pacman_conn = conn_table.get_users()[&req.pacman];
+                    pacman_conn.send(Message::JoinTicket(JoinTicket {
+                        user,
+                        ticket,
+                        spectator: false,
+                    }));
+                    conn.send(Message::JoinGameResponse(JoinGameResponse::Ok(
+                        addr, rules, ticket,
+                    )));
                 } else {
                     conn.send(Message::JoinGameResponse(JoinGameResponse::Err));
                 }
                 drop(conn_table);
             }
-            LeaderboardRequest => {
-                conn.send(Message::LeaderboardResponse(LeaderboardResponse {
-                    top10: database.get_leaderboard(),
-                }));
+            SpectateGameRequest(req) => {
+                let mut conn_table = conn_table.lock().unwrap();
+                if let Some((addr, ticket)) = conn_table.spectate_game(&conn, &req.pacman) {
+                    let user = conn_table.get_connections()[&conn].user.clone().unwrap();
+                    let pacman_conn = conn_table.get_users()[&req.pacman];
+                    pacman_conn.send(Message::JoinTicket(JoinTicket {
+                        user,
+                        ticket,
+                        spectator: true,
+                    }));
+                    let response = SpectateGameResponse::Ok(addr, ticket);
+                    conn.send(Message::SpectateGameResponse(response));
+                } else {
+                    conn.send(Message::SpectateGameResponse(SpectateGameResponse::Err));
+                }
+                drop(conn_table);
+            }
+            LeaderboardRequest(query) => {
+                let conn_table = conn_table.lock().unwrap();
+                let requester = conn_table
+                    .get_connections()
+                    .get(&conn)
+                    .and_then(|conn_data| conn_data.user.clone());
+                drop(conn_table);
+                match database.get_leaderboard(&query, requester.as_deref()) {
+                    Ok(Some(page)) => {
+                        conn.send(Message::LeaderboardResponse(LeaderboardResponse::Ok(page)));
+                    }
+                    Ok(None) => {
+                        let response = LeaderboardResponse::UnknownSeason;
+                        conn.send(Message::LeaderboardResponse(response));
+                    }
+                    Err(err) => {
+                        log::error!("Failed to read the leaderboard: {err}");
+                        conn.send(Message::LeaderboardResponse(LeaderboardResponse::Err));
+                    }
+                }
+            }
+            AddLeaderboardEntry(result) => {
+                let mut conn_table = conn_table.lock().unwrap();
+                let Some(game) = conn_table.hosted_game(&conn) else {
+                    log::info!("Connection {conn:?} sent a result without hosting a game");
+                    let reason = "there is no game hosted by this connection".to_owned();
+                    conn.send(Message::ResultResponse(ResultResponse::Rejected(reason)));
+                    continue;
+                };
+                // Sending the result ends the game
+                conn_table.kick(&conn);
+                drop(conn_table);
+                // Simulating a long match takes a while, other messages don't wait for it
+                let duration = current_time().saturating_sub(game.created);
+                let send = send.clone();
+                std::thread::spawn(move || {
+                    let verified = verify(conn, game, result, duration);
+                    let _ = send.send(Event::Verified(verified));
+                });
+            }
+            ProfileRequest(req) => {
+                let response = match database.profile(&req.user) {
+                    Ok(Some((user, stats))) => ProfileResponse::Ok(user, stats),
+                    Ok(None) => ProfileResponse::UnknownUser,
+                    Err(err) => {
+                        log::error!("Failed to read the profile of {:?}: {err}", &req.user);
+                        ProfileResponse::Err
+                    }
+                };
+                conn.send(Message::ProfileResponse(response));
             }
-            AddLeaderboardEntry(entry) => {
-                database.add_leaderboard_entry(entry);
+            RatingLeaderboardRequest(req) => {
+                let conn_table = conn_table.lock().unwrap();
+                let requester = conn_table
+                    .get_connections()
+                    .get(&conn)
+                    .and_then(|conn_data| conn_data.user.clone());
+                drop(conn_table);
+                let response =
+                    match database.rating_leaderboard(req.role, req.page, requester.as_deref()) {
+                        Ok(page) => RatingLeaderboardResponse::Ok(page),
+                        Err(err) => {
+                            log::error!("Failed to read the ratings: {err}");
+                            RatingLeaderboardResponse::Err
+                        }
+                    };
+                conn.send(Message::RatingLeaderboardResponse(response));
             }
         }
     }
 }
+
+/// Adds a verified result to the leaderboard and the statistics of its players, and answers the
+/// pacman that sent it
+fn record_result(database: &mut Database, verified: Verified) {
+    use server_client::Message;
+    let Verified {
+        conn,
+        user,
+        outcome,
+    } = verified;
+    let response = match outcome {
+        Ok((entry, summary)) => {
+            log::info!(
+                "User {user} finished a game with score {} and final hash {:016x}",
+                entry.score,
+                entry.hash.unwrap_or_default()
+            );
+            if let Err(err) = database.update_stats(&entry, &summary) {
+                log::error!("Failed to update the statistics of the players: {err}");
+            }
+            match database.add_leaderboard_entry(entry) {
+                Ok(()) => ResultResponse::Ok,
+                Err(err) => {
+                    log::error!("Failed to add the result to the leaderboard: {err}");
+                    ResultResponse::Rejected("the server failed to keep it".to_owned())
+                }
+            }
+        }
+        Err(reason) => {
+            log::info!("Rejected the result of {user}: {reason}");
+            ResultResponse::Rejected(reason)
+        }
+    };
+    conn.send(Message::ResultResponse(response));
+}
@@ -0,0 +1,35 @@
+//! Settings of the server read from `config.json` in the configuration directory
+//! Every setting is optional, missing ones take their default value
+
+use serde::Deserialize;
+
+use super::{
+    season::SeasonLength,
+    storage::{StorageBackend, SyncPolicy},
+    throttle::ThrottleConfig,
+    username::UsernamePolicy,
+};
+
+const CONFIG_FILE: &str = "config.json";
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub storage: StorageBackend,
+    pub sync: SyncPolicy,
+    pub usernames: UsernamePolicy,
+    pub seasons: SeasonLength,
+    pub login_throttle: ThrottleConfig,
+}
+
+impl ServerConfig {
+    pub fn load() -> Self {
+        let Ok(config_str) = std::fs::read_to_string(CONFIG_FILE) else {
+            log::info!("No {CONFIG_FILE} found, using the default settings");
+            return Self::default();
+        };
+        let config = serde_json::from_str(&config_str).expect("Failed to parse config.json");
+        log::info!("Loaded settings: {config:?}");
+        config
+    }
+}
@@ -1,98 +1,336 @@
 //! Module to deal with persistent user and password data
+//! Usernames follow the `UsernamePolicy` and passwords are only stored hashed, whatever `Storage`
+//! keeps them
 
-use std::{
-    fs::File,
-    io::{Read, Write},
+use pacman_communication::{
+    client_server::{LeaderboardQuery, TimeWindow},
+    current_time,
+    server_client::{LeaderboardPage, RatingEntry, RatingPage, SeasonInfo},
+    LeaderboardEntry, Ratings, Role, UserStats, LEADERBOARD_PAGE_SIZE,
 };
+use thiserror::Error;
 
-use pacman_communication::LeaderboardEntry;
+use super::{
+    password::{self, Verification},
+    rating,
+    results::MatchSummary,
+    season::{ArchivedSeason, SeasonLength},
+    storage::{personal_bests, rank_of, select_results, ResultFilter, Storage, StorageError},
+    username::{fold, UsernamePolicy},
+};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
 
-fn user_file_path(user: &str) -> String {
-    format!("users/{user}")
+#[derive(Error, Debug)]
+pub enum CreateUserError {
+    #[error("{0}")]
+    InvalidName(String),
+    #[error("the name is already taken")]
+    NameTaken,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
 }
 
-pub struct Database;
+pub struct Database {
+    storage: Box<dyn Storage>,
+    policy: UsernamePolicy,
+    season_length: SeasonLength,
+    rolled_until: u64, // Start of the current season when seasons were last rolled over
+}
 
 impl Database {
-    pub fn new() -> Self {
-        let _ = std::fs::create_dir("users");
-        Self
+    pub fn new(
+        storage: Box<dyn Storage>,
+        policy: UsernamePolicy,
+        season_length: SeasonLength,
+    ) -> Self {
+        Self {
+            storage,
+            policy,
+            season_length,
+            rolled_until: 0,
+        }
     }
 
-    fn open_user_file(&mut self, user: &str) -> Option<File> {
-        if let Ok(file) = File::open(user_file_path(user)) {
-            Some(file)
-        } else {
-            None
+    /// Returns the name of the new user if created
+    pub fn create_user(&mut self, user: &str, password: &str) -> Result<String, CreateUserError> {
+        let user = self.policy.check(user).map_err(CreateUserError::InvalidName)?;
+        let key = fold(&user);
+        if self.storage.find_user(&key)?.is_some() {
+            return Err(CreateUserError::NameTaken);
         }
+        self.storage
+            .create_user(&key, &user, &password::hash(password))?;
+        Ok(user)
     }
 
-    pub fn user_exists(&mut self, user: &str) -> bool {
-        self.open_user_file(user).is_some()
+    /// Returns the name of the user as it was created if successful, None otherwise
+    /// A password still stored in plaintext is replaced by its hash
+    pub fn login(&mut self, user: &str, passwd: &str) -> Result<Option<String>, StorageError> {
+        let Some(record) = self.storage.find_user(&fold(user))? else {
+            password::verify_dummy(passwd);
+            return Ok(None);
+        };
+        let Some(cur_passwd) = self.storage.password_hash(record.id)? else {
+            password::verify_dummy(passwd);
+            return Ok(None);
+        };
+        match password::verify(&cur_passwd, passwd) {
+            Verification::Valid => Ok(Some(record.name)),
+            Verification::Plaintext => {
+                log::info!("Hashing the plaintext password of user {}", &record.name);
+                self.storage
+                    .set_password_hash(record.id, &password::hash(passwd))?;
+                Ok(Some(record.name))
+            }
+            Verification::Invalid => Ok(None),
+        }
     }
 
-    /// Returns true if created, false if otherwise
-    pub fn create_user(&mut self, user: &str, password: &str) -> bool {
-        // Username must be reasonable, forbid whitespaces and limit length to 20
-        if user.chars().any(char::is_whitespace)
-            || user.chars().count() > 20
-            || self.user_exists(user)
+    /// Returns true if successful, false otherwise
+    pub fn change_password(
+        &mut self,
+        user: &str,
+        old_passwd: &str,
+        new_passwd: &str,
+    ) -> Result<bool, StorageError> {
+        let Some(record) = self.storage.find_user(&fold(user))? else {
+            password::verify_dummy(old_passwd);
+            return Ok(false);
+        };
+        let Some(cur_passwd) = self.storage.password_hash(record.id)? else {
+            password::verify_dummy(old_passwd);
+            return Ok(false);
+        };
+        if let Verification::Valid | Verification::Plaintext =
+            password::verify(&cur_passwd, old_passwd)
         {
-            false
+            self.storage
+                .set_password_hash(record.id, &password::hash(new_passwd))?;
+            Ok(true)
         } else {
-            let mut file = File::create(format!("users/{user}")).unwrap();
-            file.write_all(password.as_bytes()).unwrap();
-            true
+            Ok(false)
         }
     }
 
-    /// Returns true if successful, false otherwise
-    pub fn login(&mut self, user: &str, passwd: &str) -> bool {
-        if let Some(mut file) = self.open_user_file(user) {
-            let mut cur_passwd = String::new();
-            let _ = file.read_to_string(&mut cur_passwd).unwrap();
-            passwd == cur_passwd
-        } else {
-            false
+    pub fn add_leaderboard_entry(&mut self, entry: LeaderboardEntry) -> Result<(), StorageError> {
+        self.storage.add_leaderboard_entry(&entry)
+    }
+
+    /// Adds a finished match to the statistics of its pacman and of its ghosts
+    pub fn update_stats(
+        &mut self,
+        entry: &LeaderboardEntry,
+        summary: &MatchSummary,
+    ) -> Result<(), StorageError> {
+        let mut pacman = match self.storage.find_user(&fold(&entry.user))? {
+            Some(record) => Some((record.id, self.storage.stats(record.id)?)),
+            None => None,
+        };
+        let mut ghosts = Vec::new();
+        for ghost in &summary.opponents {
+            let Some(record) = self.storage.find_user(&fold(ghost))? else { continue; };
+            let mut stats = self.storage.stats(record.id)?;
+            stats.ghost_games += 1;
+            stats.catches += summary.catches.get(ghost).copied().unwrap_or(0);
+            stats.time_played += summary.time_played.get(ghost).copied().unwrap_or(0);
+            ghosts.push((record.id, stats));
+        }
+        // Ratings only change if both sides of the match are users
+        if let Some((_, stats)) = &mut pacman {
+            let mut ghost_ratings: Vec<_> = ghosts.iter().map(|(_, stats)| stats.ratings).collect();
+            rating::update(&mut stats.ratings, &mut ghost_ratings, summary.pacman_outcome);
+            for ((_, stats), ratings) in ghosts.iter_mut().zip(ghost_ratings) {
+                stats.ratings = ratings;
+            }
         }
+        if let Some((id, mut stats)) = pacman {
+            stats.pacman_games += 1;
+            stats.best_score = stats.best_score.max(entry.score);
+            stats.total_score += entry.score;
+            stats.dots_eaten += summary.dots_eaten;
+            stats.time_played += entry.duration;
+            ghosts.push((id, stats));
+        }
+        self.storage.set_stats(&ghosts)
     }
 
-    /// Returns true if successful, false otherwise
-    pub fn change_password(&mut self, user: &str, old_passwd: &str, new_passwd: &str) -> bool {
-        if let Some(mut file) = self.open_user_file(user) {
-            let mut cur_passwd = String::new();
-            let _ = file.read_to_string(&mut cur_passwd).unwrap();
-            if old_passwd == cur_passwd {
-                let mut file = File::create(user_file_path(user)).unwrap();
-                file.write_all(new_passwd.as_bytes()).unwrap();
-                true
-            } else {
-                false
+    /// Name of the user as it was created and their statistics, None if the user doesn't exist
+    pub fn profile(&self, user: &str) -> Result<Option<(String, UserStats)>, StorageError> {
+        let Some(record) = self.storage.find_user(&fold(user))? else { return Ok(None); };
+        let stats = self.storage.stats(record.id)?;
+        Ok(Some((record.name, stats)))
+    }
+
+    /// Ratings of a user, the initial ones if the user doesn't exist
+    pub fn ratings(&self, user: &str) -> Result<Ratings, StorageError> {
+        Ok(self.profile(user)?.map(|(_, stats)| stats.ratings).unwrap_or_default())
+    }
+
+    /// The page of the users rated in `role`, best rated first, with the rank of `requester`
+    pub fn rating_leaderboard(
+        &self,
+        role: Role,
+        page: usize,
+        requester: Option<&str>,
+    ) -> Result<RatingPage, StorageError> {
+        let mut users: Vec<RatingEntry> = self
+            .storage
+            .every_stats()?
+            .into_iter()
+            .map(|(user, stats)| {
+                let (rating, games) = match role {
+                    Role::Pacman => (stats.ratings.pacman, stats.ratings.pacman_games),
+                    Role::Ghost => (stats.ratings.ghost, stats.ratings.ghost_games),
+                };
+                RatingEntry { user, rating, games }
+            })
+            .filter(|entry| entry.games > 0)
+            .collect();
+        users.sort_by(|a, b| b.rating.total_cmp(&a.rating).then_with(|| a.user.cmp(&b.user)));
+        let rank = requester.and_then(|requester| {
+            let requester = fold(requester);
+            let position = users.iter().position(|entry| fold(&entry.user) == requester);
+            position.map(|position| position + 1)
+        });
+        let total = users.len();
+        let start = page.saturating_mul(LEADERBOARD_PAGE_SIZE).min(total);
+        let entries = users.drain(start..).take(LEADERBOARD_PAGE_SIZE).collect();
+        Ok(RatingPage {
+            role,
+            first_position: start + 1,
+            entries,
+            total,
+            rank,
+        })
+    }
+
+    /// Archives the standings of every season that ended since the last call
+    /// Seasons without any result are skipped, and don't take a number
+    pub fn roll_seasons(&mut self) -> Result<(), StorageError> {
+        let now = current_time().as_secs();
+        let Some((current_start, _)) = self.season_length.period(now) else { return Ok(()); };
+        if current_start <= self.rolled_until {
+            return Ok(());
+        }
+        let mut seasons = self.storage.seasons()?;
+        let mut start = match seasons.last() {
+            Some(season) => season.end,
+            // The first season starts with the oldest result
+            None => match self.storage.oldest_result_time()? {
+                Some(oldest) => self.season_length.period(oldest).unwrap().0,
+                None => current_start,
+            },
+        };
+        while start < current_start {
+            let (_, end) = self.season_length.period(start).unwrap();
+            let end = end.min(current_start);
+            let standings = personal_bests(self.storage.results(start..end)?);
+            if !standings.is_empty() {
+                let season = ArchivedSeason {
+                    number: seasons.len() as u64 + 1,
+                    start,
+                    end,
+                    standings,
+                };
+                self.storage.add_season(&season)?;
+                log::info!(
+                    "Season {} ended, standings of {} users archived",
+                    season.number,
+                    season.standings.len()
+                );
+                seasons.push(season);
             }
-        } else {
-            false
+            start = end;
         }
+        self.rolled_until = current_start;
+        Ok(())
     }
 
-    // Keeps top 10
-    pub fn add_leaderboard_entry(&mut self, entry: LeaderboardEntry) {
-        let mut leaderboard: Vec<LeaderboardEntry> = self.get_leaderboard().to_vec();
-        leaderboard.push(entry);
-        leaderboard.sort();
-        leaderboard.reverse();
-        if leaderboard.len() > 10 {
-            leaderboard = leaderboard[..10].to_vec();
+    /// The season being played, None if there are no seasons
+    fn current_season(&self) -> Result<Option<SeasonInfo>, StorageError> {
+        let now = current_time().as_secs();
+        let Some((start, end)) = self.season_length.period(now) else { return Ok(None); };
+        let seasons = self.storage.seasons()?;
+        let last_end = seasons.last().map_or(0, |season| season.end);
+        Ok(Some(SeasonInfo {
+            number: seasons.len() as u64 + 1,
+            start: start.max(last_end),
+            end,
+        }))
+    }
+
+    /// The page of the results that match `query`, with the rank of `requester` among them
+    /// None if the query is for a season that doesn't exist
+    pub fn get_leaderboard(
+        &self,
+        query: &LeaderboardQuery,
+        requester: Option<&str>,
+    ) -> Result<Option<LeaderboardPage>, StorageError> {
+        let user = query.user.as_deref().map(fold);
+        let requester = requester.map(fold);
+        if let Some(number) = query.season {
+            let seasons = self.storage.seasons()?;
+            let Some(season) = seasons.into_iter().find(|season| season.number == number) else {
+                return Ok(None);
+            };
+            let info = SeasonInfo {
+                number,
+                start: season.start,
+                end: season.end,
+            };
+            // Archived standings are kept whole, they are paged here
+            let results = select_results(season.standings, query.personal_bests, None);
+            let rank = requester.and_then(|requester| rank_of(&results, &requester));
+            let results = select_results(results, false, user.as_deref());
+            let total = results.len();
+            let start = query.page.saturating_mul(LEADERBOARD_PAGE_SIZE).min(total);
+            let entries = results.into_iter().skip(start).take(LEADERBOARD_PAGE_SIZE).collect();
+            return Ok(Some(LeaderboardPage {
+                first_position: start + 1,
+                entries,
+                total,
+                rank,
+                season: Some(info),
+            }));
         }
-        let mut file = File::create("leaderboard").unwrap();
-        let leaderboard = leaderboard.into_boxed_slice();
-        file.write_all(serde_json::to_string(&leaderboard).unwrap().as_bytes())
-            .unwrap();
+        let (since, season) = if query.window == TimeWindow::Season {
+            let season = self.current_season()?;
+            (season.as_ref().map_or(0, |season| season.start), season)
+        } else {
+            (window_start(query.window), None)
+        };
+        let filter = ResultFilter {
+            since,
+            personal_bests: query.personal_bests,
+        };
+        let rank = match &requester {
+            Some(requester) => self.storage.result_rank(&filter, requester)?,
+            None => None,
+        };
+        let total = self.storage.count_results(&filter, user.as_deref())?;
+        let start = query.page.saturating_mul(LEADERBOARD_PAGE_SIZE).min(total);
+        let entries = self
+            .storage
+            .results_page(&filter, user.as_deref(), start, LEADERBOARD_PAGE_SIZE)?
+            .into();
+        Ok(Some(LeaderboardPage {
+            first_position: start + 1,
+            entries,
+            total,
+            rank,
+            season,
+        }))
     }
+}
 
-    pub fn get_leaderboard(&self) -> Box<[LeaderboardEntry]> {
-        let Ok(mut file) = File::open("leaderboard") else { return Box::new([]); };
-        let mut leaderboard_str = String::new();
-        file.read_to_string(&mut leaderboard_str).unwrap();
-        serde_json::from_str(&leaderboard_str).unwrap()
+/// Start of the window in seconds since UNIX_EPOCH, for windows that don't depend on the season
+fn window_start(window: TimeWindow) -> u64 {
+    let today = current_time().as_secs() / SECS_PER_DAY;
+    match window {
+        TimeWindow::Today => today * SECS_PER_DAY,
+        // 1970-01-01 was a Thursday, 3 days after a Monday
+        TimeWindow::Week => (today - (today + 3) % 7) * SECS_PER_DAY,
+        TimeWindow::Season | TimeWindow::AllTime => 0,
     }
 }
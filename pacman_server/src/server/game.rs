@@ -1,10 +1,15 @@
-use pacman_communication::{current_time, Connection};
+use pacman_communication::{
+    current_time,
+    game::{GameRules, MAX_REMOTE_GHOSTS},
+    Connection,
+};
 use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
 
 #[derive(Clone, PartialEq)]
 pub enum GameStatus {
     Pacman(SocketAddr), // Pacman must have a TCPListener in this address
     Ghost,
+    Spectator,
     Idle,
 }
 
@@ -15,23 +20,69 @@ pub struct ConnectionData {
     pub last_heartbeat: Duration,
 }
 
+/// Time a ghost spent in a game, from joining it until leaving it
+#[derive(Clone, Debug)]
+pub struct GhostStay {
+    pub user: String,
+    pub joined: Duration,       // As in `current_time`
+    pub left: Option<Duration>, // None while the ghost is still in the game
+}
+
+/// Game created by a pacman, as the server set it up
+pub struct HostedGame {
+    pub user: String,
+    pub rules: GameRules,
+    pub seed: u64,
+    pub created: Duration, // As in `current_time`
+    /// Every time the server let a user join the game as a ghost, even if they left it, in order
+    pub ghosts: Vec<GhostStay>,
+}
+
+impl HostedGame {
+    /// Whether the server ever let `user` join the game as a ghost
+    pub fn joined(&self, user: &str) -> bool {
+        self.ghosts.iter().any(|stay| stay.user == user)
+    }
+
+    /// Time `user` spent in the game as a ghost, if the game ended at `end`
+    pub fn time_played(&self, user: &str, end: Duration) -> Duration {
+        self.ghosts
+            .iter()
+            .filter(|stay| stay.user == user)
+            .map(|stay| stay.left.unwrap_or(end).min(end).saturating_sub(stay.joined))
+            .sum()
+    }
+}
+
 pub struct ConnectionTable {
     connections: BTreeMap<Connection, ConnectionData>,
     users: BTreeMap<String, Connection>,
-    pacmans: BTreeMap<String, Option<String>>, // Map : PacmanUsername -> Option<GhostUsername>
+    pacmans: BTreeMap<String, Vec<String>>, // Map : PacmanUsername -> GhostUsernames (in join order)
     // Every game must have a pacman, but not
     // necessarily a ghost
     ghosts: BTreeMap<String, String>, // Map : GhostUsername -> PacmanUsername
+    spectators: BTreeMap<String, String>, // Map : SpectatorUsername -> PacmanUsername
+    rules: BTreeMap<String, GameRules>,   // Map : PacmanUsername -> Rules of their game
+    seeds: BTreeMap<String, u64>,         // Map : PacmanUsername -> Seed of their game
+    created: BTreeMap<String, Duration>,  // Map : PacmanUsername -> Creation time of their game
+    joined: BTreeMap<String, Vec<GhostStay>>, // Map : PacmanUsername -> Stays of its ghosts
+    max_ghosts: usize,                    // Maximum number of ghosts that may join a single game
 }
 
 impl ConnectionTable {
     #[must_use]
-    pub fn new() -> Self {
+    pub fn new(max_ghosts: usize) -> Self {
         Self {
             connections: BTreeMap::new(),
             users: BTreeMap::new(),
             pacmans: BTreeMap::new(),
             ghosts: BTreeMap::new(),
+            spectators: BTreeMap::new(),
+            rules: BTreeMap::new(),
+            seeds: BTreeMap::new(),
+            created: BTreeMap::new(),
+            joined: BTreeMap::new(),
+            max_ghosts: max_ghosts.clamp(1, MAX_REMOTE_GHOSTS),
         }
     }
 
@@ -43,7 +94,7 @@ impl ConnectionTable {
         &self.users
     }
 
-    pub fn get_pacmans(&self) -> &BTreeMap<String, Option<String>> {
+    pub fn get_pacmans(&self) -> &BTreeMap<String, Vec<String>> {
         &self.pacmans
     }
 
@@ -51,17 +102,57 @@ impl ConnectionTable {
         &self.ghosts
     }
 
+    pub fn get_spectators(&self) -> &BTreeMap<String, String> {
+        &self.spectators
+    }
+
+    /// Number of users watching the game created by `pacman`
+    pub fn spectator_count(&self, pacman: &str) -> usize {
+        self.spectators.values().filter(|p| *p == pacman).count()
+    }
+
+    pub fn get_rules(&self) -> &BTreeMap<String, GameRules> {
+        &self.rules
+    }
+
+    /// The game created by `conn`, if it is hosting one
+    pub fn hosted_game(&self, conn: &Connection) -> Option<HostedGame> {
+        let conn_data = self.connections.get(conn)?;
+        let user = conn_data.user.as_ref()?;
+        let GameStatus::Pacman(_) = conn_data.status else { return None; };
+        Some(HostedGame {
+            user: user.clone(),
+            rules: self.rules.get(user)?.clone(),
+            seed: *self.seeds.get(user)?,
+            created: *self.created.get(user)?,
+            ghosts: self.joined.get(user)?.clone(),
+        })
+    }
+
+    /// Number of ghosts that may join the game created by `pacman`, limited by the server and by
+    /// the rules of the game
+    pub fn max_ghosts(&self, pacman: &str) -> usize {
+        self.rules
+            .get(pacman)
+            .map_or(self.max_ghosts, |rules| rules.max_remote_ghosts.min(self.max_ghosts))
+    }
+
     /// Kick connection from game
     /// Returns true if kicked from a game
     pub fn kick(&mut self, conn: &Connection) -> bool {
         let Some(conn_data) = self.connections.get_mut(conn) else { return false; };
         let Some(user) = conn_data.user.as_ref() else { return false; };
-        use GameStatus::{Ghost, Idle, Pacman};
+        use GameStatus::{Ghost, Idle, Pacman, Spectator};
         match conn_data.status {
             Pacman(_) => {
-                log::info!("Kicking pacman (connection: {conn:?}, user: {user}). Also kicking ghost from the game if it exists.");
+                log::info!("Kicking pacman (connection: {conn:?}, user: {user}). Also kicking ghosts and spectators from the game if they exist.");
                 conn_data.status = Idle;
-                if let Some(ghost) = self.pacmans.remove(user).unwrap() {
+                let user = user.clone();
+                self.rules.remove(&user);
+                self.seeds.remove(&user);
+                self.created.remove(&user);
+                self.joined.remove(&user);
+                for ghost in self.pacmans.remove(&user).unwrap() {
                     let ghost_conn = self.users.get(&ghost).unwrap();
                     log::info!(
                         "Kicking ghost (connection {ghost_conn:?}, user: {ghost}) from the game."
@@ -69,13 +160,38 @@ impl ConnectionTable {
                     self.connections.get_mut(ghost_conn).unwrap().status = Idle;
                     self.ghosts.remove(&ghost).unwrap();
                 }
+                let spectators: Vec<String> = self
+                    .spectators
+                    .iter()
+                    .filter(|(_, pacman)| **pacman == user)
+                    .map(|(spectator, _)| spectator.clone())
+                    .collect();
+                for spectator in spectators {
+                    let spectator_conn = self.users.get(&spectator).unwrap();
+                    log::info!("Kicking spectator (connection {spectator_conn:?}, user: {spectator}) from the game.");
+                    self.connections.get_mut(spectator_conn).unwrap().status = Idle;
+                    self.spectators.remove(&spectator);
+                }
+                true
+            }
+            Spectator => {
+                log::info!("Spectator (connection {conn:?}, user: {user}) left the game.");
+                conn_data.status = Idle;
+                self.spectators.remove(user);
                 true
             }
             Ghost => {
                 log::info!("Kicking ghost (connection {conn:?}, user: {user}) from the game.");
                 conn_data.status = Idle;
                 let pacman = self.ghosts.remove(user).unwrap();
-                *self.pacmans.get_mut(&pacman).unwrap() = None;
+                self.pacmans
+                    .get_mut(&pacman)
+                    .unwrap()
+                    .retain(|ghost| ghost != user);
+                let stays = self.joined.get_mut(&pacman).into_iter().flatten();
+                if let Some(stay) = stays.filter(|stay| stay.user == *user).last() {
+                    stay.left.get_or_insert_with(current_time);
+                }
                 true
             }
             Idle => false,
@@ -113,7 +229,7 @@ impl ConnectionTable {
 
     pub fn login(&mut self, conn: &Connection, user: &str) -> bool {
         if let Some(conn_data) = self.connections.get_mut(conn) {
-            if self.users.get(user).is_none() {
+            if !self.users.contains_key(user) {
                 log::info!("Connection {conn:?} logged in as {user}");
                 conn_data.user = Some(user.to_owned());
                 self.users.insert(user.to_owned(), *conn);
@@ -125,7 +241,7 @@ impl ConnectionTable {
 
     // Returns true if the connection was inserted, false if it already existed
     pub fn insert(&mut self, conn: &Connection) -> bool {
-        if self.connections.get(conn).is_some() {
+        if self.connections.contains_key(conn) {
             false
         } else {
             log::info!("Connection added: {conn:?}");
@@ -141,35 +257,60 @@ impl ConnectionTable {
         }
     }
 
-    pub fn create_game(&mut self, conn: &Connection, listener_addr: SocketAddr) -> bool {
+    pub fn create_game(
+        &mut self,
+        conn: &Connection,
+        listener_addr: SocketAddr,
+        rules: GameRules,
+        seed: u64,
+    ) -> bool {
         let Some(conn_data) = self.connections.get_mut(conn) else { return false; };
         let Some(user) = conn_data.user.as_mut() else { return false; };
-        if conn_data.status != GameStatus::Idle {
+        if conn_data.status != GameStatus::Idle || rules.validate().is_err() {
             false
         } else {
-            log::info!("User {user} with connection {conn:?} created a game on {listener_addr:?}");
+            log::info!("User {user} with connection {conn:?} created a game on {listener_addr:?} with seed {seed} and rules {rules:?}");
             conn_data.status = GameStatus::Pacman(listener_addr);
-            self.pacmans.insert(user.clone(), None);
+            self.pacmans.insert(user.clone(), Vec::new());
+            self.rules.insert(user.clone(), rules);
+            self.seeds.insert(user.clone(), seed);
+            self.created.insert(user.clone(), current_time());
+            self.joined.insert(user.clone(), Vec::new());
             true
         }
     }
 
-    /// Returns the `listener_addr` of pacman if joining the game was sucessful
-    pub fn join_game(&mut self, conn: &Connection, pacman: &str) -> Option<SocketAddr> {
-        let res = || -> Option<SocketAddr> {
-            let Some(conn_data) = self.connections.get(conn) else { return None; };
-            let Some(user) = conn_data.user.as_ref() else { return None; };
-            let Some(pacman_conn) = self.users.get_mut(pacman) else { return None; };
-            let Some(pacman_conn_data) = self.connections.get(pacman_conn) else { return None; };
+    /// Returns the `listener_addr` of pacman, the rules of the game and a new one-time ticket the
+    /// ghost must present to pacman if joining the game was sucessful
+    /// A game accepts ghosts until it reaches `max_ghosts`
+    pub fn join_game(
+        &mut self,
+        conn: &Connection,
+        pacman: &str,
+    ) -> Option<(SocketAddr, GameRules, u64)> {
+        let max_ghosts = self.max_ghosts(pacman);
+        let res = || -> Option<(SocketAddr, GameRules, u64)> {
+            let conn_data = self.connections.get(conn)?;
+            let user = conn_data.user.as_ref()?;
+            if conn_data.status != GameStatus::Idle {
+                return None;
+            }
+            let pacman_conn = self.users.get_mut(pacman)?;
+            let pacman_conn_data = self.connections.get(pacman_conn)?;
             let GameStatus::Pacman(addr) = pacman_conn_data.status else { return None; };
-            let Some(other_player) = self.pacmans.get_mut(pacman) else { return None; };
-            if other_player.is_some() {
+            let other_players = self.pacmans.get_mut(pacman)?;
+            if other_players.len() >= max_ghosts {
                 return None;
             }
-            *other_player = Some(user.clone());
+            other_players.push(user.clone());
+            self.joined.get_mut(pacman)?.push(GhostStay {
+                user: user.clone(),
+                joined: current_time(),
+                left: None,
+            });
             self.ghosts.insert(user.clone(), pacman.to_owned());
             log::info!("Ghost (user: {user}, connection: {conn:?}) joined game created by user {pacman} with connection {pacman_conn:?}");
-            Some(addr)
+            Some((addr, self.rules.get(pacman)?.clone(), rand::random()))
         }();
         // let res = res();
         if res.is_some() {
@@ -178,4 +319,20 @@ impl ConnectionTable {
         }
         res
     }
+
+    /// Returns the `listener_addr` of pacman and a new one-time ticket the spectator must present
+    /// to pacman if the connection can watch its game
+    pub fn spectate_game(&mut self, conn: &Connection, pacman: &str) -> Option<(SocketAddr, u64)> {
+        let conn_data = self.connections.get(conn)?;
+        let user = conn_data.user.as_ref()?.clone();
+        if conn_data.status != GameStatus::Idle {
+            return None;
+        }
+        let pacman_conn = self.users.get(pacman)?;
+        let GameStatus::Pacman(addr) = self.connections.get(pacman_conn)?.status else { return None; };
+        log::info!("Spectator (user: {user}, connection: {conn:?}) is watching game created by user {pacman} with connection {pacman_conn:?}");
+        self.spectators.insert(user, pacman.to_owned());
+        self.connections.get_mut(conn).unwrap().status = GameStatus::Spectator;
+        Some((addr, rand::random()))
+    }
 }
@@ -1,25 +1,31 @@
 //! Defines the listener
 use std::{
-    io::Read,
     net::{Ipv4Addr, SocketAddrV4, TcpListener, UdpSocket},
-    sync::mpsc::{channel, Receiver},
+    sync::mpsc::Sender,
+    time::Duration,
 };
 
-use pacman_communication::{client_server, PacmanMessage};
+use pacman_communication::{read_frame, PacmanMessage, MAX_DATAGRAM_SIZE};
 
-pub fn start(port: u16) -> Receiver<client_server::Message> {
-    let (send, recv) = channel();
+use super::Event;
+
+/// Clients send a single message on each TCP connection, those that stall are dropped
+const TCP_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Messages are sent with the address they came from, the connection in them is only what
+/// the client claims
+pub fn start(port: u16, send: Sender<Event>) {
     {
         // Udp Listener
         let send = send.clone();
         std::thread::spawn(move || {
-            let mut buf = [0; 9001];
+            let mut buf = [0; MAX_DATAGRAM_SIZE];
             let listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).unwrap();
             loop {
-                match listener.recv(&mut buf) {
-                    Ok(amt) => {
+                match listener.recv_from(&mut buf) {
+                    Ok((amt, source)) => {
                         let Some(msg) = PacmanMessage::from_bytes(&buf[..amt]) else { continue; };
-                        send.send(msg).unwrap();
+                        send.send(Event::Message(source.ip(), msg)).unwrap();
                     }
                     Err(err) => {
                         if err.kind() != std::io::ErrorKind::WouldBlock {
@@ -32,17 +38,21 @@ pub fn start(port: u16) -> Receiver<client_server::Message> {
     }
     {
         std::thread::spawn(move || {
-            let mut buf = [0u8; 9001];
             let listener =
                 TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).unwrap();
             for stream in listener.incoming() {
                 match stream {
                     Ok(mut stream) => {
                         let send = send.clone();
-                        let Ok(amt) = stream.read(&mut buf) else { break; };
-                        if let Some(msg) = PacmanMessage::from_bytes(&buf[..amt]) {
-                            send.send(msg).unwrap();
-                        }
+                        let Ok(source) = stream.peer_addr() else { continue; };
+                        // Large messages take a while, other clients don't wait for them
+                        std::thread::spawn(move || {
+                            let _ = stream.set_read_timeout(Some(TCP_READ_TIMEOUT));
+                            let Ok(buf) = read_frame(&mut stream) else { return; };
+                            if let Some(msg) = PacmanMessage::from_bytes(&buf) {
+                                send.send(Event::Message(source.ip(), msg)).unwrap();
+                            }
+                        });
                     }
                     Err(err) => {
                         eprintln!("Unknown error: {err}");
@@ -51,5 +61,4 @@ pub fn start(port: u16) -> Receiver<client_server::Message> {
             }
         });
     }
-    recv
 }
@@ -0,0 +1,67 @@
+//! Passwords are stored as salted Argon2 hashes, in the PHC string format
+
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use subtle::ConstantTimeEq;
+
+pub enum Verification {
+    Valid,
+    /// Valid, but stored in plaintext by an older version of the server, so it should be hashed
+    Plaintext,
+    Invalid,
+}
+
+pub fn hash(passwd: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(passwd.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// Checks `passwd` against what is stored in a user file, comparing in constant time
+pub fn verify(stored: &str, passwd: &str) -> Verification {
+    let Ok(stored_hash) = PasswordHash::new(stored) else {
+        return if bool::from(stored.as_bytes().ct_eq(passwd.as_bytes())) {
+            Verification::Plaintext
+        } else {
+            Verification::Invalid
+        };
+    };
+    match Argon2::default().verify_password(passwd.as_bytes(), &stored_hash) {
+        Ok(()) => Verification::Valid,
+        Err(_) => Verification::Invalid,
+    }
+}
+
+/// Takes as long as checking the password of an existing user, so that a wrong login doesn't
+/// reveal whether the user exists
+pub fn verify_dummy(passwd: &str) {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let dummy_hash = DUMMY_HASH.get_or_init(|| hash("senha"));
+    let _ = verify(dummy_hash, passwd);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_are_salted_and_checked() {
+        let stored = hash("senha123");
+        assert!(stored.starts_with("$argon2"));
+        assert_ne!(stored, hash("senha123"));
+        assert!(matches!(verify(&stored, "senha123"), Verification::Valid));
+        assert!(matches!(verify(&stored, "senha124"), Verification::Invalid));
+    }
+
+    #[test]
+    fn plaintext_passwords_are_recognized() {
+        assert!(matches!(verify("senha123", "senha123"), Verification::Plaintext));
+        assert!(matches!(verify("senha123", "senha"), Verification::Invalid));
+    }
+}
@@ -0,0 +1,112 @@
+//! Elo ratings of users as pacman and as ghost. The remote ghosts of a match play as a team,
+//! rated as their average, and every one of them gets the team's result
+//! Ratings start at `INITIAL_RATING` and move faster during the first `PROVISIONAL_GAMES` of a
+//! role
+
+use pacman_communication::{Ratings, PROVISIONAL_GAMES};
+
+const PROVISIONAL_K: f64 = 48.0;
+const K: f64 = 24.0;
+
+fn k_factor(games: u64) -> f64 {
+    if games < PROVISIONAL_GAMES {
+        PROVISIONAL_K
+    } else {
+        K
+    }
+}
+
+/// Chance of a player rated `rating` beating one rated `opponent`
+fn expected(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+/// Updates the ratings of a match with `pacman_outcome` from 0 (the ghosts won) to 1 (pacman
+/// won). Nothing changes without remote ghosts
+pub fn update(pacman: &mut Ratings, ghosts: &mut [Ratings], pacman_outcome: f64) {
+    if ghosts.is_empty() {
+        return;
+    }
+    let ghosts_rating = ghosts.iter().map(|ghost| ghost.ghost).sum::<f64>() / ghosts.len() as f64;
+    let pacman_rating = pacman.pacman;
+    pacman.pacman += k_factor(pacman.pacman_games)
+        * (pacman_outcome - expected(pacman_rating, ghosts_rating));
+    pacman.pacman_games += 1;
+    for ghost in ghosts {
+        ghost.ghost += k_factor(ghost.ghost_games)
+            * ((1.0 - pacman_outcome) - expected(ghosts_rating, pacman_rating));
+        ghost.ghost_games += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(rating: f64, expected: f64) {
+        assert!((rating - expected).abs() < 1e-9, "{rating} is not {expected}");
+    }
+
+    #[test]
+    fn winner_takes_from_the_loser() {
+        let mut pacman = Ratings::default();
+        let mut ghosts = [Ratings::default()];
+        update(&mut pacman, &mut ghosts, 1.0);
+        // Both are provisional and equally rated, so the winner was expected to score half
+        assert_close(pacman.pacman, 1524.0);
+        assert_close(ghosts[0].ghost, 1476.0);
+        assert_eq!((pacman.pacman_games, ghosts[0].ghost_games), (1, 1));
+        // Ratings of the other role don't change
+        assert_close(pacman.ghost, 1500.0);
+        assert_close(ghosts[0].pacman, 1500.0);
+    }
+
+    #[test]
+    fn expected_results_move_ratings_less() {
+        let settled = Ratings {
+            pacman: 1700.0,
+            pacman_games: PROVISIONAL_GAMES,
+            ..Ratings::default()
+        };
+        let mut pacman = settled;
+        update(&mut pacman, &mut [Ratings::default()], 1.0);
+        let gain = pacman.pacman - 1700.0;
+        assert!(gain > 0.0 && gain < K / 2.0);
+        let mut pacman = settled;
+        update(&mut pacman, &mut [Ratings::default()], 0.0);
+        assert!(1700.0 - pacman.pacman > K / 2.0);
+        // A draw between equals changes nothing
+        let mut pacman = Ratings::default();
+        let mut ghosts = [Ratings::default()];
+        update(&mut pacman, &mut ghosts, 0.5);
+        assert_close(pacman.pacman, 1500.0);
+        assert_close(ghosts[0].ghost, 1500.0);
+    }
+
+    #[test]
+    fn ghosts_play_as_a_team() {
+        let strong = Ratings {
+            ghost: 1700.0,
+            ..Ratings::default()
+        };
+        let weak = Ratings {
+            ghost: 1300.0,
+            ..Ratings::default()
+        };
+        let mut pacman = Ratings::default();
+        let mut ghosts = [strong, weak];
+        update(&mut pacman, &mut ghosts, 0.0);
+        // The team is rated 1500, like pacman, and each ghost gets the team's result
+        assert_close(pacman.pacman, 1476.0);
+        assert_close(ghosts[0].ghost, 1724.0);
+        assert_close(ghosts[1].ghost, 1324.0);
+    }
+
+    #[test]
+    fn matches_without_remote_ghosts_are_not_rated() {
+        let mut pacman = Ratings::default();
+        update(&mut pacman, &mut [], 1.0);
+        assert_close(pacman.pacman, 1500.0);
+        assert_eq!(pacman.pacman_games, 0);
+    }
+}
@@ -0,0 +1,253 @@
+//! Results sent by pacmans are checked by simulating their matches again with the seed and
+//! rules the server chose
+
+use std::{collections::BTreeMap, time::Duration};
+
+use pacman_communication::{
+    client_server::GameResult,
+    game::{Game, GameMode, Input, Map, TICK_INTERVAL},
+};
+
+use crate::server::game::HostedGame;
+
+/// Ticks a real-time game may have beyond the ones that fit in the time it was hosted for, as the
+/// clocks of the server and of the pacman don't start together
+const TICK_SLACK: u64 = 8;
+
+/// What the simulation of a match tells about its players
+#[derive(Debug)]
+pub struct MatchSummary {
+    /// Users who joined the match as ghosts, in the order they joined. The server let all of them
+    /// join the game
+    pub opponents: Vec<String>,
+    pub dots_eaten: u64,
+    /// Lives each of the `opponents` took from pacman
+    pub catches: BTreeMap<String, u64>,
+    /// Seconds each of the `opponents` was in the game, from joining it until leaving it or the
+    /// end of the game
+    pub time_played: BTreeMap<String, u64>,
+    /// Share of its lives pacman kept from the `opponents`, from 0 to 1. Lives left when pacman
+    /// gives up count as taken
+    pub pacman_outcome: f64,
+}
+
+/// Returns why the result doesn't match the game created by the server, if it doesn't
+/// `duration` is how long the game was hosted for, which bounds the ticks it may have
+pub fn verify(
+    result: &GameResult,
+    hosted: &HostedGame,
+    duration: Duration,
+) -> Result<MatchSummary, String> {
+    let (rules, seed) = (&hosted.rules, hosted.seed);
+    if result.seed != seed {
+        return Err(format!("seed {} is not the seed of the game, {seed}", result.seed));
+    }
+    // Games created through the server are always played on the default map and its scoring, so
+    // results on any other map are never ranked
+    if result.map != Map::default() {
+        return Err("the map is not the one the game was created with".to_owned());
+    }
+    let mut game = Game::with_map(result.map.clone(), rules.clone(), seed)
+        .map_err(|err| format!("invalid game: {err}"))?;
+    let mut opponents: Vec<String> = Vec::new();
+    let mut ghost_users = BTreeMap::new(); // Map : GhostId -> Username
+    let max_ticks = match rules.mode {
+        GameMode::TurnBased => 0,
+        GameMode::RealTime => {
+            let fit = duration.as_millis() / TICK_INTERVAL.as_millis();
+            let fit = u64::try_from(fit).unwrap_or(u64::MAX).saturating_add(TICK_SLACK);
+            rules.turn_limit.map_or(fit, |limit| fit.min(limit))
+        }
+    };
+    let mut ticks: u64 = 0;
+    for (i, input) in result.inputs.iter().enumerate() {
+        if game.game_over() {
+            return Err(format!("input {i} ({input:?}) comes after the end of the game"));
+        }
+        match input {
+            Input::AddRemoteGhost(user) if !hosted.joined(user) => {
+                return Err(format!("{user} never joined the game"));
+            }
+            Input::Ticks(n) => {
+                ticks = ticks.saturating_add(*n);
+                if ticks > max_ticks {
+                    return Err(format!("{ticks} ticks, but the game could have {max_ticks}"));
+                }
+            }
+            _ => {}
+        }
+        game.apply(input)
+            .map_err(|err| format!("invalid input {i} ({input:?}): {err}"))?;
+        if let Input::AddRemoteGhost(user) = input {
+            if let Some(id) = game.remote_ghost_id(user) {
+                ghost_users.insert(id, user.clone());
+            }
+            if !opponents.contains(user) {
+                opponents.push(user.clone());
+            }
+        }
+    }
+    if game.score() != result.score {
+        return Err(format!(
+            "score {} doesn't match the simulated score {}",
+            result.score,
+            game.score()
+        ));
+    }
+    if game.state_hash() != result.hash {
+        return Err(format!(
+            "final hash {:016x} doesn't match the simulated hash {:016x}",
+            result.hash,
+            game.state_hash()
+        ));
+    }
+    let mut catches = BTreeMap::new();
+    for id in game.caught_by() {
+        if let Some(user) = ghost_users.get(id) {
+            *catches.entry(user.clone()).or_insert(0) += 1;
+        }
+    }
+    let gave_up = !game.game_over() || result.inputs.contains(&Input::Forfeit);
+    let lives_given_up = if gave_up { game.lives() } else { 0 };
+    let lives_taken = catches.values().sum::<u64>() + u64::from(lives_given_up);
+    let pacman_outcome = 1.0 - (lives_taken as f64 / f64::from(rules.lives.max(1))).min(1.0);
+    let end = hosted.created + duration;
+    let time_played = opponents
+        .iter()
+        .map(|user| (user.clone(), hosted.time_played(user, end).as_secs()))
+        .collect();
+    Ok(MatchSummary {
+        opponents,
+        dots_eaten: game.dots_eaten(),
+        catches,
+        time_played,
+        pacman_outcome,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pacman_communication::{
+        game::{GameRules, MoveOutcome},
+        Direction::*,
+    };
+
+    use super::*;
+    use crate::server::game::GhostStay;
+
+    const SEED: u64 = 42;
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn hosted(ghosts: &[&str]) -> HostedGame {
+        HostedGame {
+            user: "ana".to_owned(),
+            rules: GameRules::default(),
+            seed: SEED,
+            created: Duration::ZERO,
+            ghosts: ghosts.iter().map(|&ghost| stay(ghost, 0, None)).collect(),
+        }
+    }
+
+    fn stay(user: &str, joined: u64, left: Option<u64>) -> GhostStay {
+        GhostStay {
+            user: user.to_owned(),
+            joined: Duration::from_secs(joined),
+            left: left.map(Duration::from_secs),
+        }
+    }
+
+    /// Result of a match against the remote ghost bob, as the pacman sends it
+    fn genuine_result() -> GameResult {
+        let mut game = Game::with_rules(GameRules::default(), SEED).unwrap();
+        let bob = game.add_remote_ghost("bob").unwrap();
+        for dir in [West, West, East, East, East, East] {
+            game.move_ai_ghost(0).unwrap();
+            game.move_ghost(bob, North).unwrap();
+            if game.move_pacman(dir).unwrap() == MoveOutcome::GameOver {
+                break;
+            }
+        }
+        assert!(game.score() > 0);
+        GameResult {
+            score: game.score(),
+            hash: game.state_hash(),
+            seed: SEED,
+            map: game.map().clone(),
+            inputs: game.history().iter().map(|record| record.input.clone()).collect(),
+        }
+    }
+
+    #[test]
+    fn genuine_result_is_accepted() {
+        let summary = verify(&genuine_result(), &hosted(&["bob"]), HOUR).unwrap();
+        assert_eq!(summary.opponents, ["bob"]);
+        assert!(summary.dots_eaten > 0);
+    }
+
+    #[test]
+    fn ghosts_are_credited_only_while_in_the_game() {
+        let mut hosted = hosted(&[]);
+        hosted.ghosts = vec![stay("bob", 10, Some(40)), stay("bob", 50, None)];
+        let summary = verify(&genuine_result(), &hosted, Duration::from_secs(100)).unwrap();
+        assert_eq!(summary.time_played["bob"], 30 + 50);
+    }
+
+    #[test]
+    fn tampered_result_is_rejected() {
+        let hosted = hosted(&["bob"]);
+        let mut result = genuine_result();
+        result.score += 100;
+        assert!(verify(&result, &hosted, HOUR).unwrap_err().contains("score"));
+
+        let mut result = genuine_result();
+        result.hash ^= 1;
+        assert!(verify(&result, &hosted, HOUR).unwrap_err().contains("hash"));
+
+        let mut result = genuine_result();
+        result.seed += 1;
+        assert!(verify(&result, &hosted, HOUR).is_err());
+
+        // The same moves with a different final state
+        let mut result = genuine_result();
+        result.inputs.push(Input::PassPacman);
+        assert!(verify(&result, &hosted, HOUR).is_err());
+    }
+
+    #[test]
+    fn ghost_that_never_joined_is_rejected() {
+        let err = verify(&genuine_result(), &hosted(&[]), HOUR).unwrap_err();
+        assert!(err.contains("bob never joined"));
+    }
+
+    #[test]
+    fn inputs_after_the_end_are_rejected() {
+        let mut result = genuine_result();
+        result.inputs.push(Input::Forfeit);
+        result.inputs.push(Input::PassPacman);
+        let err = verify(&result, &hosted(&["bob"]), HOUR).unwrap_err();
+        assert!(err.contains("after the end"));
+    }
+
+    #[test]
+    fn ticks_beyond_the_time_hosted_are_rejected() {
+        let mut hosted = hosted(&[]);
+        hosted.rules.mode = GameMode::RealTime;
+        let mut game = Game::with_rules(hosted.rules.clone(), SEED).unwrap();
+        for _ in 0..40 {
+            game.tick().unwrap();
+        }
+        let mut result = GameResult {
+            score: game.score(),
+            hash: game.state_hash(),
+            seed: SEED,
+            map: game.map().clone(),
+            inputs: vec![Input::Ticks(40)],
+        };
+        assert!(verify(&result, &hosted, Duration::from_secs(10)).is_ok());
+        assert!(verify(&result, &hosted, Duration::from_secs(5)).is_err());
+        result.inputs = vec![Input::Ticks(u64::MAX)];
+        assert!(verify(&result, &hosted, HOUR).unwrap_err().contains("ticks"));
+    }
+}
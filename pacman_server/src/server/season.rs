@@ -0,0 +1,102 @@
+//! Competitions over a fixed stretch of time. Seasons follow the calendar in UTC: monthly ones
+//! start on the first day of each month, the others every `days` days since UNIX_EPOCH
+//! When a season ends the best result of each user in it is archived as its final standings
+
+use pacman_communication::{civil_from_days, LeaderboardEntry};
+use serde::{Deserialize, Serialize};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SeasonLength {
+    /// A single leaderboard that is never reset
+    #[default]
+    Never,
+    Monthly,
+    Days(u64),
+}
+
+impl SeasonLength {
+    /// Start and end of the season `time` is in, in seconds since UNIX_EPOCH
+    pub fn period(self, time: u64) -> Option<(u64, u64)> {
+        let day = time / SECS_PER_DAY;
+        match self {
+            Self::Never => None,
+            Self::Monthly => {
+                let (year, month, _) = civil_from_days(day);
+                let (next_year, next_month) =
+                    if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                Some((
+                    days_from_civil(year, month) * SECS_PER_DAY,
+                    days_from_civil(next_year, next_month) * SECS_PER_DAY,
+                ))
+            }
+            Self::Days(days) => {
+                let days = days.max(1);
+                let start = day - day % days;
+                Some((start * SECS_PER_DAY, (start + days) * SECS_PER_DAY))
+            }
+        }
+    }
+}
+
+/// A season that ended, with the best result of each user who played in it, best first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchivedSeason {
+    pub number: u64, // Counted from 1
+    pub start: u64,
+    pub end: u64,
+    pub standings: Vec<LeaderboardEntry>,
+}
+
+/// Day counted from UNIX_EPOCH of the first day of a month, from Howard Hinnant's
+/// `days_from_civil`
+fn days_from_civil(year: u64, month: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JAN_2024: u64 = 1_704_067_200;
+    const FEB_2024: u64 = 1_706_745_600;
+    const MAR_2024: u64 = 1_709_251_200;
+    const DEC_2023: u64 = 1_701_388_800;
+
+    #[test]
+    fn monthly_seasons_follow_the_calendar() {
+        let monthly = SeasonLength::Monthly;
+        // 2024 is a leap year
+        assert_eq!(monthly.period(FEB_2024 + 28 * SECS_PER_DAY), Some((FEB_2024, MAR_2024)));
+        assert_eq!(monthly.period(DEC_2023 + 14 * SECS_PER_DAY), Some((DEC_2023, JAN_2024)));
+        assert_eq!(monthly.period(0), Some((0, 31 * SECS_PER_DAY)));
+    }
+
+    #[test]
+    fn season_starts_on_its_first_second() {
+        let monthly = SeasonLength::Monthly;
+        assert_eq!(monthly.period(MAR_2024 - 1), Some((FEB_2024, MAR_2024)));
+        assert_eq!(monthly.period(MAR_2024).unwrap().0, MAR_2024);
+        let weekly = SeasonLength::Days(7);
+        let week = 7 * SECS_PER_DAY;
+        assert_eq!(weekly.period(0), Some((0, week)));
+        assert_eq!(weekly.period(week - 1), Some((0, week)));
+        assert_eq!(weekly.period(week), Some((week, 2 * week)));
+    }
+
+    #[test]
+    fn other_lengths() {
+        assert_eq!(SeasonLength::Never.period(JAN_2024), None);
+        // Zero days would never end, it counts as one
+        let day = Some((JAN_2024, JAN_2024 + SECS_PER_DAY));
+        assert_eq!(SeasonLength::Days(0).period(JAN_2024), day);
+    }
+}
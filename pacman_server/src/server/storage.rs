@@ -0,0 +1,397 @@
+//! Where the database keeps users, their credentials and the leaderboard
+//! `Database` decides what is stored, a `Storage` only reads and writes it
+
+pub mod file;
+pub mod memory;
+pub mod sqlite;
+
+use std::{cmp::Ordering, ops::Range, path::Path};
+
+use pacman_communication::{LeaderboardEntry, UserStats};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{season::ArchivedSeason, username::fold};
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid stored data: {0}")]
+    InvalidData(#[from] serde_json::Error),
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserRecord {
+    pub id: u64,
+    pub name: String, // As the user created it
+}
+
+/// Users are looked up by a key, the folded form of their name, and kept by an ID the storage
+/// chooses
+pub trait Storage {
+    fn find_user(&self, key: &str) -> Result<Option<UserRecord>, StorageError>;
+    /// Stores a new user, whose key must not be taken
+    fn create_user(
+        &mut self,
+        key: &str,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<UserRecord, StorageError>;
+    fn password_hash(&self, id: u64) -> Result<Option<String>, StorageError>;
+    fn set_password_hash(&mut self, id: u64, password_hash: &str) -> Result<(), StorageError>;
+    /// Every result of a match that ended within `times` (as in `LeaderboardEntry::time`), best
+    /// first as in `best_first`
+    fn results(&self, times: Range<u64>) -> Result<Vec<LeaderboardEntry>, StorageError>;
+    /// When the oldest match with a known end ended, results kept by older servers have none
+    fn oldest_result_time(&self) -> Result<Option<u64>, StorageError> {
+        Ok(self.results(1..u64::MAX)?.iter().map(|entry| entry.time).min())
+    }
+    /// The results of `filter` from position `offset` (the first is 0) on, at most `limit` of
+    /// them, and only those of the user with key `user` if given
+    fn results_page(
+        &self,
+        filter: &ResultFilter,
+        user: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<LeaderboardEntry>, StorageError> {
+        let results = self.results(filter.since..u64::MAX)?;
+        let results = select_results(results, filter.personal_bests, user);
+        Ok(results.into_iter().skip(offset).take(limit).collect())
+    }
+    /// Number of results of `filter`, only those of the user with key `user` if given
+    fn count_results(
+        &self,
+        filter: &ResultFilter,
+        user: Option<&str>,
+    ) -> Result<usize, StorageError> {
+        let results = self.results(filter.since..u64::MAX)?;
+        Ok(select_results(results, filter.personal_bests, user).len())
+    }
+    /// Position (the first is 1) of the best result of the user with key `user` among the
+    /// results of `filter`, None if they have none
+    fn result_rank(
+        &self,
+        filter: &ResultFilter,
+        user: &str,
+    ) -> Result<Option<usize>, StorageError> {
+        let results = self.results(filter.since..u64::MAX)?;
+        let results = select_results(results, filter.personal_bests, None);
+        Ok(rank_of(&results, user))
+    }
+    /// Keeps the result of a match
+    fn add_leaderboard_entry(&mut self, entry: &LeaderboardEntry) -> Result<(), StorageError>;
+    /// Every season that ended, oldest first
+    fn seasons(&self) -> Result<Vec<ArchivedSeason>, StorageError>;
+    fn add_season(&mut self, season: &ArchivedSeason) -> Result<(), StorageError>;
+    /// Statistics of a user, the default ones if they never finished a match
+    fn stats(&self, id: u64) -> Result<UserStats, StorageError>;
+    /// Saves the statistics of several users (by ID) at once: either all of them are saved or,
+    /// if it fails, none
+    fn set_stats(&mut self, stats: &[(u64, UserStats)]) -> Result<(), StorageError>;
+    /// Name and statistics of every user, in no particular order
+    fn every_stats(&self) -> Result<Vec<(String, UserStats)>, StorageError>;
+}
+
+/// Results a page of the leaderboard is taken from
+#[derive(Debug, Clone)]
+pub struct ResultFilter {
+    /// Results of matches that ended at `since` or later (as in `LeaderboardEntry::time`)
+    pub since: u64,
+    /// Only the best result of each user
+    pub personal_bests: bool,
+}
+
+/// Order of the leaderboard, the same in every storage: higher score first, then the result that
+/// came first, then by name. Results equal in all of those stay in the order they were added
+pub fn best_first(a: &LeaderboardEntry, b: &LeaderboardEntry) -> Ordering {
+    b.score
+        .cmp(&a.score)
+        .then(a.time.cmp(&b.time))
+        .then_with(|| a.user.cmp(&b.user))
+}
+
+/// The best result of each user, from results sorted best first
+pub fn personal_bests(mut results: Vec<LeaderboardEntry>) -> Vec<LeaderboardEntry> {
+    let mut seen = std::collections::BTreeSet::new();
+    results.retain(|entry| seen.insert(fold(&entry.user)));
+    results
+}
+
+/// The results of `results`, sorted best first, that a `ResultFilter` with `personal_bests`
+/// and the user with key `user` select
+pub fn select_results(
+    mut results: Vec<LeaderboardEntry>,
+    personal_bests_only: bool,
+    user: Option<&str>,
+) -> Vec<LeaderboardEntry> {
+    if personal_bests_only {
+        results = personal_bests(results);
+    }
+    if let Some(user) = user {
+        results.retain(|entry| fold(&entry.user) == user);
+    }
+    results
+}
+
+/// Position (the first is 1) of the first of `results` of the user with key `user`
+pub fn rank_of(results: &[LeaderboardEntry], user: &str) -> Option<usize> {
+    let position = results.iter().position(|entry| fold(&entry.user) == user);
+    position.map(|position| position + 1)
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Files in the configuration directory
+    #[default]
+    Files,
+    /// Nothing is kept after the server stops, meant for tests
+    Memory,
+    /// A single SQLite database in the configuration directory
+    Sqlite,
+}
+
+impl StorageBackend {
+    /// `dir` is where the files and the SQLite database are kept
+    pub fn open(self, dir: &Path, sync: SyncPolicy) -> Result<Box<dyn Storage>, StorageError> {
+        Ok(match self {
+            Self::Files => Box::new(file::FileStorage::open(dir, sync)?),
+            Self::Memory => Box::new(memory::MemoryStorage::default()),
+            Self::Sqlite => Box::new(sqlite::SqliteStorage::open(dir, sync)?),
+        })
+    }
+}
+
+/// When written data is flushed to the disk
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    /// Before every write is considered done, so a crash or power loss never loses it
+    #[default]
+    Always,
+    /// Whenever the operating system decides, faster but the last writes may be lost
+    Never,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    /// Runs `test` against a new, empty storage of each backend, each in its own directory
+    fn for_each_backend(test: impl Fn(&mut dyn Storage)) {
+        for backend in [StorageBackend::Memory, StorageBackend::Files, StorageBackend::Sqlite] {
+            let dir = std::env::temp_dir().join(format!(
+                "pacman-storage-{}-{}",
+                std::process::id(),
+                NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut storage = backend.open(&dir, SyncPolicy::Never).unwrap();
+            test(storage.as_mut());
+            drop(storage);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    fn create(storage: &mut dyn Storage, name: &str) -> UserRecord {
+        storage.create_user(&fold(name), name, &format!("hash of {name}")).unwrap()
+    }
+
+    fn entry(score: u64, user: &str, time: u64) -> LeaderboardEntry {
+        LeaderboardEntry {
+            score,
+            user: user.to_owned(),
+            hash: Some(score),
+            time,
+            opponents: Vec::new(),
+            map: String::new(),
+            duration: 60,
+        }
+    }
+
+    fn scores(results: &[LeaderboardEntry]) -> Vec<(u64, &str)> {
+        results.iter().map(|entry| (entry.score, entry.user.as_str())).collect()
+    }
+
+    #[test]
+    fn users_are_found_by_folded_name() {
+        for_each_backend(|storage| {
+            let ana = create(storage, "Ana");
+            let bob = create(storage, "bob");
+            assert_ne!(ana.id, bob.id);
+            let found = storage.find_user(&fold("ANA")).unwrap().unwrap();
+            assert_eq!((found.id, found.name.as_str()), (ana.id, "Ana"));
+            assert_eq!(storage.find_user(&fold("Bob")).unwrap().unwrap().id, bob.id);
+            assert!(storage.find_user(&fold("carla")).unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn password_hashes_are_updated() {
+        for_each_backend(|storage| {
+            let ana = create(storage, "Ana");
+            let bob = create(storage, "bob");
+            assert_eq!(storage.password_hash(ana.id).unwrap().unwrap(), "hash of Ana");
+            storage.set_password_hash(ana.id, "new hash").unwrap();
+            assert_eq!(storage.password_hash(ana.id).unwrap().unwrap(), "new hash");
+            assert_eq!(storage.password_hash(bob.id).unwrap().unwrap(), "hash of bob");
+        });
+    }
+
+    #[test]
+    fn results_are_sorted_best_first() {
+        for_each_backend(|storage| {
+            for name in ["Ana", "bob", "carla"] {
+                create(storage, name);
+            }
+            assert_eq!(storage.oldest_result_time().unwrap(), None);
+            storage.add_leaderboard_entry(&entry(10, "Ana", 100)).unwrap();
+            storage.add_leaderboard_entry(&entry(30, "bob", 200)).unwrap();
+            storage.add_leaderboard_entry(&entry(20, "Ana", 300)).unwrap();
+            // Kept by an older server, without the time it ended
+            storage.add_leaderboard_entry(&entry(5, "carla", 0)).unwrap();
+
+            let all = storage.results(0..u64::MAX).unwrap();
+            assert_eq!(scores(&all), [(30, "bob"), (20, "Ana"), (10, "Ana"), (5, "carla")]);
+            let recent = storage.results(150..300).unwrap();
+            assert_eq!(scores(&recent), [(30, "bob")]);
+            assert_eq!(all[0].hash, Some(30));
+            assert_eq!(all[0].duration, 60);
+            assert_eq!(storage.oldest_result_time().unwrap(), Some(100));
+        });
+    }
+
+    #[test]
+    fn ties_are_ordered_the_same_in_every_backend() {
+        for_each_backend(|storage| {
+            for name in ["Ana", "bob", "carla"] {
+                create(storage, name);
+            }
+            for (user, time, hash) in [
+                ("carla", 200, 1),
+                ("bob", 200, 2),
+                ("bob", 100, 3),
+                ("Ana", 200, 4),
+                ("bob", 100, 5),
+            ] {
+                let mut entry = entry(10, user, time);
+                entry.hash = Some(hash);
+                storage.add_leaderboard_entry(&entry).unwrap();
+            }
+            let hashes = |results: Vec<LeaderboardEntry>| -> Vec<Option<u64>> {
+                results.iter().map(|entry| entry.hash).collect()
+            };
+            let all = storage.results(0..u64::MAX).unwrap();
+            assert_eq!(hashes(all), [Some(3), Some(5), Some(4), Some(2), Some(1)]);
+            let every = ResultFilter { since: 0, personal_bests: false };
+            let bests = ResultFilter { since: 0, personal_bests: true };
+            let page = storage.results_page(&every, None, 0, 10).unwrap();
+            assert_eq!(hashes(page), [Some(3), Some(5), Some(4), Some(2), Some(1)]);
+            let page = storage.results_page(&bests, None, 0, 10).unwrap();
+            assert_eq!(hashes(page), [Some(3), Some(4), Some(1)]);
+            assert_eq!(storage.result_rank(&every, "ana").unwrap(), Some(3));
+            assert_eq!(storage.result_rank(&bests, "carla").unwrap(), Some(3));
+        });
+    }
+
+    #[test]
+    fn results_are_paged_counted_and_ranked() {
+        for_each_backend(|storage| {
+            for name in ["Ana", "bob", "carla"] {
+                create(storage, name);
+            }
+            storage.add_leaderboard_entry(&entry(10, "Ana", 100)).unwrap();
+            storage.add_leaderboard_entry(&entry(30, "bob", 200)).unwrap();
+            storage.add_leaderboard_entry(&entry(20, "Ana", 300)).unwrap();
+            storage.add_leaderboard_entry(&entry(5, "carla", 400)).unwrap();
+            let every = ResultFilter { since: 0, personal_bests: false };
+            let bests = ResultFilter { since: 0, personal_bests: true };
+            let recent = ResultFilter { since: 250, personal_bests: true };
+
+            let page = storage.results_page(&every, None, 1, 2).unwrap();
+            assert_eq!(scores(&page), [(20, "Ana"), (10, "Ana")]);
+            let page = storage.results_page(&bests, None, 0, 10).unwrap();
+            assert_eq!(scores(&page), [(30, "bob"), (20, "Ana"), (5, "carla")]);
+            let page = storage.results_page(&every, Some(&fold("ANA")), 0, 10).unwrap();
+            assert_eq!(scores(&page), [(20, "Ana"), (10, "Ana")]);
+            let page = storage.results_page(&recent, None, 0, 10).unwrap();
+            assert_eq!(scores(&page), [(20, "Ana"), (5, "carla")]);
+
+            assert_eq!(storage.count_results(&every, None).unwrap(), 4);
+            assert_eq!(storage.count_results(&bests, None).unwrap(), 3);
+            assert_eq!(storage.count_results(&every, Some("ana")).unwrap(), 2);
+            assert_eq!(storage.count_results(&recent, Some("bob")).unwrap(), 0);
+
+            assert_eq!(storage.result_rank(&every, "carla").unwrap(), Some(4));
+            assert_eq!(storage.result_rank(&bests, "carla").unwrap(), Some(3));
+            assert_eq!(storage.result_rank(&recent, "ana").unwrap(), Some(1));
+            assert_eq!(storage.result_rank(&recent, "bob").unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn seasons_are_kept_oldest_first() {
+        for_each_backend(|storage| {
+            assert!(storage.seasons().unwrap().is_empty());
+            for number in [1, 2] {
+                let season = ArchivedSeason {
+                    number,
+                    start: number * 1000,
+                    end: (number + 1) * 1000,
+                    standings: vec![entry(number * 10, "Ana", number * 1000 + 1)],
+                };
+                storage.add_season(&season).unwrap();
+            }
+            let seasons = storage.seasons().unwrap();
+            let numbers: Vec<_> = seasons.iter().map(|season| season.number).collect();
+            assert_eq!(numbers, [1, 2]);
+            assert_eq!((seasons[1].start, seasons[1].end), (2000, 3000));
+            assert_eq!(scores(&seasons[1].standings), [(20, "Ana")]);
+        });
+    }
+
+    #[test]
+    fn stats_are_saved_together() {
+        for_each_backend(|storage| {
+            let ana = create(storage, "Ana");
+            let bob = create(storage, "bob");
+            assert_eq!(storage.stats(ana.id).unwrap().pacman_games, 0);
+            let mut ana_stats = UserStats {
+                pacman_games: 2,
+                best_score: 300,
+                ..UserStats::default()
+            };
+            let bob_stats = UserStats {
+                ghost_games: 1,
+                catches: 1,
+                ..UserStats::default()
+            };
+            storage.set_stats(&[(ana.id, ana_stats.clone()), (bob.id, bob_stats)]).unwrap();
+            ana_stats.pacman_games = 3;
+            ana_stats.ratings.pacman = 1516.0;
+            storage.set_stats(&[(ana.id, ana_stats)]).unwrap();
+
+            let stats = storage.stats(ana.id).unwrap();
+            assert_eq!((stats.pacman_games, stats.best_score), (3, 300));
+            assert!((stats.ratings.pacman - 1516.0).abs() < f64::EPSILON);
+            let stats = storage.stats(bob.id).unwrap();
+            assert_eq!((stats.ghost_games, stats.catches), (1, 1));
+
+            let mut every_stats = storage.every_stats().unwrap();
+            every_stats.sort_by(|a, b| a.0.cmp(&b.0));
+            let games: Vec<_> = every_stats
+                .iter()
+                .map(|(name, stats)| (name.as_str(), stats.pacman_games, stats.ghost_games))
+                .collect();
+            assert_eq!(games, [("Ana", 3, 0), ("bob", 0, 1)]);
+        });
+    }
+}
@@ -0,0 +1,344 @@
+//! Every file is in the directory the storage is opened in
+//! Users are kept by their ID, never by the name they typed: `accounts/index.json` maps each key
+//! to its user, `accounts/<id>` holds the hash of the user's password and `stats/<id>` their
+//! statistics. The statistics of all the players of a match are first written together to
+//! `stats/pending`, which is applied again if the server stops before it is removed
+//! Files written before passwords were hashed hold it in plaintext until the user logs in again
+//! The leaderboard is a JSON file, `leaderboard`, with every result, best first, and `seasons`
+//! has the final standings of every season that ended
+//! Files are never rewritten in place: a new version is written next to the old one and renamed
+//! over it, so a crash leaves one of them whole. The index, the leaderboard and the seasons also
+//! keep their previous version as `<file>.bak`, used if the file is found corrupt when the server starts
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::File,
+    io::{ErrorKind, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use pacman_communication::{current_time, LeaderboardEntry, UserStats};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{best_first, Storage, StorageError, SyncPolicy, UserRecord};
+use crate::server::{season::ArchivedSeason, username::fold};
+
+const ACCOUNTS_DIR: &str = "accounts";
+const INDEX_FILE: &str = "accounts/index.json";
+const STATS_DIR: &str = "stats";
+const STATS_JOURNAL: &str = "stats/pending";
+/// Older servers kept a `users/<name>` file for each user
+const LEGACY_USERS_DIR: &str = "users";
+const LEADERBOARD_FILE: &str = "leaderboard";
+const SEASONS_FILE: &str = "seasons";
+
+fn user_file_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(ACCOUNTS_DIR).join(id.to_string())
+}
+
+fn stats_file_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(STATS_DIR).join(id.to_string())
+}
+
+/// `path` with `suffix` added to the name of its file
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+/// Replaces the file at `path` by one with `contents`, so that a crash leaves either the old or
+/// the new file, never a mix of both
+fn write_atomic(path: &Path, contents: &str, sync: SyncPolicy) -> Result<(), StorageError> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    if sync == SyncPolicy::Always {
+        file.sync_all()?;
+    }
+    drop(file);
+    std::fs::rename(&tmp_path, path)?;
+    if sync == SyncPolicy::Always {
+        sync_parent_dir(path)?;
+    }
+    Ok(())
+}
+
+/// Makes a rename in the directory of `path` survive a power loss
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<(), StorageError> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<(), StorageError> {
+    Ok(())
+}
+
+/// Copies the current version of a file to its backup, before it is replaced
+fn write_backup(path: &Path, sync: SyncPolicy) -> Result<(), StorageError> {
+    let backup = backup_path(path);
+    match std::fs::read_to_string(path) {
+        Ok(contents) => write_atomic(&backup, &contents, sync),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, StorageError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads a JSON file kept with a backup. If the file is corrupt, its backup replaces it
+fn recover_json<T: Serialize + DeserializeOwned>(
+    path: &Path,
+    sync: SyncPolicy,
+) -> Result<Option<T>, StorageError> {
+    let err = match read_json(path) {
+        Err(err @ StorageError::InvalidData(_)) => err,
+        result => return result,
+    };
+    log::error!("{} is corrupt: {err}", path.display());
+    let backup = backup_path(path);
+    match read_json::<T>(&backup) {
+        Ok(Some(value)) => {
+            write_atomic(path, &serde_json::to_string(&value)?, sync)?;
+            log::error!("{} was replaced by its backup, its last change is lost", path.display());
+            Ok(Some(value))
+        }
+        Ok(None) => {
+            log::error!("{} has no backup", path.display());
+            Err(err)
+        }
+        Err(backup_err) => {
+            log::error!("{} can't be read either: {backup_err}", backup.display());
+            Err(err)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct UserIndex {
+    next_id: u64,
+    users: BTreeMap<String, UserRecord>, // Map : Key -> User
+}
+
+pub struct FileStorage {
+    dir: PathBuf,
+    index: UserIndex,
+    sync: SyncPolicy,
+}
+
+impl FileStorage {
+    pub fn open(dir: &Path, sync: SyncPolicy) -> Result<Self, StorageError> {
+        std::fs::create_dir_all(dir.join(ACCOUNTS_DIR))?;
+        std::fs::create_dir_all(dir.join(STATS_DIR))?;
+        // Without its users the server can't run, so a corrupt index with no backup is an error
+        let index = match recover_json(&dir.join(INDEX_FILE), sync)? {
+            Some(index) => index,
+            None => Self::migrate_legacy_users(dir, sync)?,
+        };
+        Self::recover_leaderboard(dir, sync)?;
+        Self::apply_stats_journal(dir, sync)?;
+        // Requests for past seasons fail until they can be read
+        if let Err(err) = recover_json::<Vec<ArchivedSeason>>(&dir.join(SEASONS_FILE), sync) {
+            log::error!("Seasons can't be read: {err}");
+        }
+        let storage = Self {
+            dir: dir.to_owned(),
+            index,
+            sync,
+        };
+        storage.write_index()?;
+        Ok(storage)
+    }
+
+    /// A leaderboard that can't be recovered is moved aside, so the server starts with an empty
+    /// one
+    fn recover_leaderboard(dir: &Path, sync: SyncPolicy) -> Result<(), StorageError> {
+        let path = dir.join(LEADERBOARD_FILE);
+        match recover_json::<Vec<LeaderboardEntry>>(&path, sync) {
+            Err(StorageError::InvalidData(_)) => {
+                let suffix = format!(".corrupt-{}", current_time().as_secs());
+                let corrupt_path = with_suffix(&path, &suffix);
+                std::fs::rename(&path, &corrupt_path)?;
+                log::error!(
+                    "Leaderboard could not be recovered, it was moved to {}",
+                    corrupt_path.display()
+                );
+                Ok(())
+            }
+            // Requests for the leaderboard fail until it can be read
+            Err(err) => {
+                log::error!("Leaderboard can't be read: {err}");
+                Ok(())
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Writes the statistics saved together in the journal that weren't all written yet
+    fn apply_stats_journal(dir: &Path, sync: SyncPolicy) -> Result<(), StorageError> {
+        let journal = dir.join(STATS_JOURNAL);
+        let Some(stats): Option<Vec<(u64, UserStats)>> = read_json(&journal)? else {
+            return Ok(());
+        };
+        log::info!("Writing the statistics of {} users left in {STATS_JOURNAL}", stats.len());
+        Self::write_stats(dir, &stats, sync)
+    }
+
+    fn write_stats(
+        dir: &Path,
+        stats: &[(u64, UserStats)],
+        sync: SyncPolicy,
+    ) -> Result<(), StorageError> {
+        for (id, stats) in stats {
+            write_atomic(&stats_file_path(dir, *id), &serde_json::to_string(stats)?, sync)?;
+        }
+        std::fs::remove_file(dir.join(STATS_JOURNAL))?;
+        Ok(())
+    }
+
+    /// Copies the files of the users of an older server to their own IDs. The old files are
+    /// only removed once the index that points to the copies is saved
+    fn migrate_legacy_users(dir: &Path, sync: SyncPolicy) -> Result<UserIndex, StorageError> {
+        let mut index = UserIndex::default();
+        let entries = match std::fs::read_dir(dir.join(LEGACY_USERS_DIR)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(index),
+            Err(err) => return Err(err.into()),
+        };
+        let mut migrated = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else { continue; };
+            let key = fold(&name);
+            if index.users.contains_key(&key) {
+                log::info!("User {name} not migrated, another user has the same name");
+                continue;
+            }
+            let id = index.next_id;
+            let password = std::fs::read_to_string(entry.path())?;
+            write_atomic(&user_file_path(dir, id), &password, sync)?;
+            index.next_id += 1;
+            log::info!("Migrated user {name} to ID {id}");
+            index.users.insert(key, UserRecord { id, name });
+            migrated.push(entry.path());
+        }
+        write_atomic(&dir.join(INDEX_FILE), &serde_json::to_string(&index)?, sync)?;
+        for path in migrated {
+            std::fs::remove_file(path)?;
+        }
+        let _ = std::fs::remove_dir(dir.join(LEGACY_USERS_DIR));
+        Ok(index)
+    }
+
+    /// Every user, with their key
+    pub fn users(&self) -> impl Iterator<Item = (&String, &UserRecord)> {
+        self.index.users.iter()
+    }
+
+    fn read_leaderboard(&self) -> Result<Vec<LeaderboardEntry>, StorageError> {
+        Ok(read_json(&self.dir.join(LEADERBOARD_FILE))?.unwrap_or_default())
+    }
+
+    fn write_index(&self) -> Result<(), StorageError> {
+        let path = self.dir.join(INDEX_FILE);
+        write_backup(&path, self.sync)?;
+        write_atomic(&path, &serde_json::to_string(&self.index)?, self.sync)
+    }
+}
+
+impl Storage for FileStorage {
+    fn find_user(&self, key: &str) -> Result<Option<UserRecord>, StorageError> {
+        Ok(self.index.users.get(key).cloned())
+    }
+
+    fn create_user(
+        &mut self,
+        key: &str,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<UserRecord, StorageError> {
+        let record = UserRecord {
+            id: self.index.next_id,
+            name: name.to_owned(),
+        };
+        write_atomic(&user_file_path(&self.dir, record.id), password_hash, self.sync)?;
+        self.index.next_id += 1;
+        self.index.users.insert(key.to_owned(), record.clone());
+        self.write_index()?;
+        Ok(record)
+    }
+
+    fn password_hash(&self, id: u64) -> Result<Option<String>, StorageError> {
+        match std::fs::read_to_string(user_file_path(&self.dir, id)) {
+            Ok(password_hash) => Ok(Some(password_hash)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn set_password_hash(&mut self, id: u64, password_hash: &str) -> Result<(), StorageError> {
+        write_atomic(&user_file_path(&self.dir, id), password_hash, self.sync)
+    }
+
+    fn results(&self, times: Range<u64>) -> Result<Vec<LeaderboardEntry>, StorageError> {
+        let mut leaderboard = self.read_leaderboard()?;
+        leaderboard.retain(|entry| times.contains(&entry.time));
+        Ok(leaderboard)
+    }
+
+    fn add_leaderboard_entry(&mut self, entry: &LeaderboardEntry) -> Result<(), StorageError> {
+        let mut leaderboard = self.read_leaderboard()?;
+        leaderboard.push(entry.clone());
+        leaderboard.sort_by(best_first);
+        let path = self.dir.join(LEADERBOARD_FILE);
+        write_backup(&path, self.sync)?;
+        write_atomic(&path, &serde_json::to_string(&leaderboard)?, self.sync)
+    }
+
+    fn seasons(&self) -> Result<Vec<ArchivedSeason>, StorageError> {
+        Ok(read_json(&self.dir.join(SEASONS_FILE))?.unwrap_or_default())
+    }
+
+    fn add_season(&mut self, season: &ArchivedSeason) -> Result<(), StorageError> {
+        let mut seasons = self.seasons()?;
+        seasons.push(season.clone());
+        let path = self.dir.join(SEASONS_FILE);
+        write_backup(&path, self.sync)?;
+        write_atomic(&path, &serde_json::to_string(&seasons)?, self.sync)
+    }
+
+    fn stats(&self, id: u64) -> Result<UserStats, StorageError> {
+        Ok(read_json(&stats_file_path(&self.dir, id))?.unwrap_or_default())
+    }
+
+    fn set_stats(&mut self, stats: &[(u64, UserStats)]) -> Result<(), StorageError> {
+        write_atomic(&self.dir.join(STATS_JOURNAL), &serde_json::to_string(stats)?, self.sync)?;
+        Self::write_stats(&self.dir, stats, self.sync)
+    }
+
+    fn every_stats(&self) -> Result<Vec<(String, UserStats)>, StorageError> {
+        self.index
+            .users
+            .values()
+            .map(|user| Ok((user.name.clone(), self.stats(user.id)?)))
+            .collect()
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use pacman_communication::{LeaderboardEntry, UserStats};
+
+use super::{best_first, Storage, StorageError, UserRecord};
+use crate::server::season::ArchivedSeason;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    users: BTreeMap<String, UserRecord>, // Map : Key -> User
+    passwords: BTreeMap<u64, String>,    // Map : ID -> Password hash
+    leaderboard: Vec<LeaderboardEntry>,
+    seasons: Vec<ArchivedSeason>,
+    stats: BTreeMap<u64, UserStats>, // Map : ID -> Statistics
+}
+
+impl Storage for MemoryStorage {
+    fn find_user(&self, key: &str) -> Result<Option<UserRecord>, StorageError> {
+        Ok(self.users.get(key).cloned())
+    }
+
+    fn create_user(
+        &mut self,
+        key: &str,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<UserRecord, StorageError> {
+        let record = UserRecord {
+            id: self.users.len() as u64,
+            name: name.to_owned(),
+        };
+        self.passwords.insert(record.id, password_hash.to_owned());
+        self.users.insert(key.to_owned(), record.clone());
+        Ok(record)
+    }
+
+    fn password_hash(&self, id: u64) -> Result<Option<String>, StorageError> {
+        Ok(self.passwords.get(&id).cloned())
+    }
+
+    fn set_password_hash(&mut self, id: u64, password_hash: &str) -> Result<(), StorageError> {
+        self.passwords.insert(id, password_hash.to_owned());
+        Ok(())
+    }
+
+    fn results(&self, times: Range<u64>) -> Result<Vec<LeaderboardEntry>, StorageError> {
+        Ok(self
+            .leaderboard
+            .iter()
+            .filter(|entry| times.contains(&entry.time))
+            .cloned()
+            .collect())
+    }
+
+    fn add_leaderboard_entry(&mut self, entry: &LeaderboardEntry) -> Result<(), StorageError> {
+        self.leaderboard.push(entry.clone());
+        self.leaderboard.sort_by(best_first);
+        Ok(())
+    }
+
+    fn seasons(&self) -> Result<Vec<ArchivedSeason>, StorageError> {
+        Ok(self.seasons.clone())
+    }
+
+    fn add_season(&mut self, season: &ArchivedSeason) -> Result<(), StorageError> {
+        self.seasons.push(season.clone());
+        Ok(())
+    }
+
+    fn stats(&self, id: u64) -> Result<UserStats, StorageError> {
+        Ok(self.stats.get(&id).cloned().unwrap_or_default())
+    }
+
+    fn set_stats(&mut self, stats: &[(u64, UserStats)]) -> Result<(), StorageError> {
+        self.stats.extend(stats.iter().cloned());
+        Ok(())
+    }
+
+    fn every_stats(&self) -> Result<Vec<(String, UserStats)>, StorageError> {
+        self.users
+            .values()
+            .map(|user| Ok((user.name.clone(), self.stats(user.id)?)))
+            .collect()
+    }
+}
@@ -0,0 +1,475 @@
+//! Users, their credentials and the result of every match in a single SQLite database,
+//! `pacman.sqlite3` in the directory the storage is opened in
+//! Scores and hashes are unsigned but SQLite integers are signed, so they are stored with the same
+//! bits as an `i64`
+//! `PRAGMA user_version` counts the migrations applied to the database
+
+use std::{ops::Range, path::Path};
+
+use pacman_communication::{LeaderboardEntry, Ratings, UserStats};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{file::FileStorage, ResultFilter, Storage, StorageError, SyncPolicy, UserRecord};
+use crate::server::{season::ArchivedSeason, username::fold};
+
+const DATABASE_FILE: &str = "pacman.sqlite3";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY,
+        key TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        password_hash TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS matches (
+        id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id),
+        score INTEGER NOT NULL,
+        hash INTEGER,
+        finished_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS matches_by_score ON matches(score DESC);
+    CREATE INDEX IF NOT EXISTS matches_by_user ON matches(user_id);
+";
+
+/// Changes to `SCHEMA` made after databases were created with it, applied in order
+const MIGRATIONS: &[&str] = &["
+    ALTER TABLE matches ADD COLUMN opponents TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE matches ADD COLUMN map TEXT NOT NULL DEFAULT '';
+    ALTER TABLE matches ADD COLUMN duration INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX matches_by_time ON matches(finished_at);
+", "
+    CREATE TABLE seasons (
+        number INTEGER PRIMARY KEY,
+        start INTEGER NOT NULL,
+        end INTEGER NOT NULL,
+        standings TEXT NOT NULL
+    );
+", "
+    CREATE TABLE stats (
+        user_id INTEGER PRIMARY KEY REFERENCES users(id),
+        pacman_games INTEGER NOT NULL,
+        ghost_games INTEGER NOT NULL,
+        catches INTEGER NOT NULL,
+        best_score INTEGER NOT NULL,
+        total_score INTEGER NOT NULL,
+        dots_eaten INTEGER NOT NULL,
+        time_played INTEGER NOT NULL
+    );
+", "
+    ALTER TABLE stats ADD COLUMN pacman_rating REAL NOT NULL DEFAULT 1500;
+    ALTER TABLE stats ADD COLUMN ghost_rating REAL NOT NULL DEFAULT 1500;
+    ALTER TABLE stats ADD COLUMN rated_pacman_games INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE stats ADD COLUMN rated_ghost_games INTEGER NOT NULL DEFAULT 0;
+", "
+    CREATE INDEX matches_by_user_score ON matches(user_id, score DESC);
+"];
+
+const STATS_COLUMNS: &str = "stats.pacman_games, stats.ghost_games, stats.catches,
+    stats.best_score, stats.total_score, stats.dots_eaten, stats.time_played, stats.pacman_rating,
+    stats.ghost_rating, stats.rated_pacman_games, stats.rated_ghost_games";
+
+/// Results of matches that ended at `?1` or later, only the best of each user if `?2`, with the
+/// key of their user
+const FILTERED_RESULTS: &str = "
+    WITH ranked AS (
+        SELECT matches.id, matches.score, users.name, matches.hash, matches.finished_at,
+               matches.opponents, matches.map, matches.duration, users.key,
+               ROW_NUMBER() OVER (PARTITION BY matches.user_id
+                                  ORDER BY matches.score DESC, matches.finished_at,
+                                           matches.id) AS user_position
+        FROM matches JOIN users ON users.id = matches.user_id
+        WHERE matches.finished_at >= ?1
+    ),
+    filtered AS (SELECT * FROM ranked WHERE NOT ?2 OR user_position = 1)";
+
+/// Best first, as in `best_first`: the ID of a match tells which results were added first
+const RESULTS_ORDER: &str = "ORDER BY score DESC, finished_at, name, id";
+
+pub struct SqliteStorage {
+    connection: Connection,
+}
+
+impl SqliteStorage {
+    pub fn open(dir: &Path, sync: SyncPolicy) -> Result<Self, StorageError> {
+        let connection = Connection::open(dir.join(DATABASE_FILE))?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        // SQLite's journal already keeps the database consistent, this only decides what a power
+        // loss may lose
+        let synchronous = match sync {
+            SyncPolicy::Always => "FULL",
+            SyncPolicy::Never => "OFF",
+        };
+        connection.pragma_update(None, "synchronous", synchronous)?;
+        let mut storage = Self { connection };
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    fn migrate(&mut self) -> Result<(), StorageError> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute_batch(SCHEMA)?;
+        let version: usize =
+            transaction.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            transaction.execute_batch(migration)?;
+            log::info!("Applied migration {} to {DATABASE_FILE}", i + 1);
+        }
+        transaction.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Copies every user and result kept in files into the database, in a single transaction
+    /// Nothing is imported into a database that already has users, so running it twice is harmless
+    pub fn import(&mut self, files: &FileStorage) -> Result<(), StorageError> {
+        let transaction = self.connection.transaction()?;
+        let has_users: bool =
+            transaction.query_row("SELECT EXISTS (SELECT 1 FROM users)", [], |row| row.get(0))?;
+        if has_users {
+            log::info!("{DATABASE_FILE} already has users, nothing imported");
+            return Ok(());
+        }
+        let mut users = 0;
+        for (key, user) in files.users() {
+            let Some(password_hash) = files.password_hash(user.id)? else {
+                log::info!("User {} has no password file, not imported", &user.name);
+                continue;
+            };
+            users += transaction.execute(
+                "INSERT INTO users (key, name, password_hash) VALUES (?1, ?2, ?3)",
+                params![key, &user.name, password_hash],
+            )?;
+            // The user may get another ID in the database
+            let id = transaction.last_insert_rowid() as u64;
+            insert_stats(&transaction, id, &files.stats(user.id)?)?;
+        }
+        let mut matches = 0;
+        for entry in files.results(0..u64::MAX)? {
+            let inserted = insert_match(&transaction, &entry)?;
+            if inserted == 0 {
+                log::info!("Result of unknown user {} not imported", &entry.user);
+            }
+            matches += inserted;
+        }
+        let seasons = files.seasons()?;
+        for season in &seasons {
+            insert_season(&transaction, season)?;
+        }
+        transaction.commit()?;
+        log::info!(
+            "Imported {users} users, {matches} results and {} seasons into {DATABASE_FILE}",
+            seasons.len()
+        );
+        Ok(())
+    }
+}
+
+/// Returns the number of results inserted, 0 if the user doesn't exist
+fn insert_match(connection: &Connection, entry: &LeaderboardEntry) -> Result<usize, StorageError> {
+    Ok(connection.execute(
+        "INSERT INTO matches (user_id, score, hash, finished_at, opponents, map, duration)
+         SELECT id, ?2, ?3, ?4, ?5, ?6, ?7 FROM users WHERE key = ?1",
+        params![
+            fold(&entry.user),
+            entry.score as i64,
+            entry.hash.map(|hash| hash as i64),
+            entry.time as i64,
+            serde_json::to_string(&entry.opponents)?,
+            &entry.map,
+            entry.duration as i64,
+        ],
+    )?)
+}
+
+fn insert_season(connection: &Connection, season: &ArchivedSeason) -> Result<(), StorageError> {
+    connection.execute(
+        "INSERT INTO seasons (number, start, end, standings) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            season.number as i64,
+            season.start as i64,
+            season.end as i64,
+            serde_json::to_string(&season.standings)?,
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_stats(connection: &Connection, id: u64, stats: &UserStats) -> Result<(), StorageError> {
+    connection.execute(
+        "INSERT OR REPLACE INTO stats (user_id, pacman_games, ghost_games, catches, best_score,
+                                       total_score, dots_eaten, time_played, pacman_rating,
+                                       ghost_rating, rated_pacman_games, rated_ghost_games)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            id as i64,
+            stats.pacman_games as i64,
+            stats.ghost_games as i64,
+            stats.catches as i64,
+            stats.best_score as i64,
+            stats.total_score as i64,
+            stats.dots_eaten as i64,
+            stats.time_played as i64,
+            stats.ratings.pacman,
+            stats.ratings.ghost,
+            stats.ratings.pacman_games as i64,
+            stats.ratings.ghost_games as i64,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Times past the largest SQLite integer are as good as no limit
+fn sql_time(time: u64) -> i64 {
+    time.min(i64::MAX as u64) as i64
+}
+
+/// Result read from a row with the score, name, hash, time, opponents, map and duration of a
+/// match, the opponents still in JSON
+fn entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<(LeaderboardEntry, String)> {
+    let entry = LeaderboardEntry {
+        score: row.get::<_, i64>(0)? as u64,
+        user: row.get(1)?,
+        hash: row.get::<_, Option<i64>>(2)?.map(|hash| hash as u64),
+        time: row.get::<_, i64>(3)? as u64,
+        opponents: Vec::new(),
+        map: row.get(5)?,
+        duration: row.get::<_, i64>(6)? as u64,
+    };
+    Ok((entry, row.get(4)?))
+}
+
+fn entries_from_rows(
+    rows: impl Iterator<Item = rusqlite::Result<(LeaderboardEntry, String)>>,
+) -> Result<Vec<LeaderboardEntry>, StorageError> {
+    let mut entries = Vec::new();
+    for row in rows {
+        let (mut entry, opponents) = row?;
+        entry.opponents = serde_json::from_str(&opponents)?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Statistics read from the `STATS_COLUMNS` of a row, starting at column `first`
+fn stats_from_row(row: &rusqlite::Row, first: usize) -> rusqlite::Result<UserStats> {
+    let integer = |i: usize| row.get::<_, i64>(first + i).map(|value| value as u64);
+    Ok(UserStats {
+        pacman_games: integer(0)?,
+        ghost_games: integer(1)?,
+        catches: integer(2)?,
+        best_score: integer(3)?,
+        total_score: integer(4)?,
+        dots_eaten: integer(5)?,
+        time_played: integer(6)?,
+        ratings: Ratings {
+            pacman: row.get(first + 7)?,
+            ghost: row.get(first + 8)?,
+            pacman_games: integer(9)?,
+            ghost_games: integer(10)?,
+        },
+    })
+}
+
+impl Storage for SqliteStorage {
+    fn find_user(&self, key: &str) -> Result<Option<UserRecord>, StorageError> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT id, name FROM users WHERE key = ?1",
+                params![key],
+                |row| {
+                    Ok(UserRecord {
+                        id: row.get::<_, i64>(0)? as u64,
+                        name: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    fn create_user(
+        &mut self,
+        key: &str,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<UserRecord, StorageError> {
+        self.connection.execute(
+            "INSERT INTO users (key, name, password_hash) VALUES (?1, ?2, ?3)",
+            params![key, name, password_hash],
+        )?;
+        Ok(UserRecord {
+            id: self.connection.last_insert_rowid() as u64,
+            name: name.to_owned(),
+        })
+    }
+
+    fn password_hash(&self, id: u64) -> Result<Option<String>, StorageError> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT password_hash FROM users WHERE id = ?1",
+                params![id as i64],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_password_hash(&mut self, id: u64, password_hash: &str) -> Result<(), StorageError> {
+        self.connection.execute(
+            "UPDATE users SET password_hash = ?2 WHERE id = ?1",
+            params![id as i64, password_hash],
+        )?;
+        Ok(())
+    }
+
+    fn results(&self, times: Range<u64>) -> Result<Vec<LeaderboardEntry>, StorageError> {
+        let mut statement = self.connection.prepare(
+            "SELECT matches.score, users.name, matches.hash, matches.finished_at,
+                    matches.opponents, matches.map, matches.duration
+             FROM matches JOIN users ON users.id = matches.user_id
+             WHERE matches.finished_at >= ?1 AND matches.finished_at < ?2
+             ORDER BY matches.score DESC, matches.finished_at, users.name, matches.id",
+        )?;
+        let times = params![sql_time(times.start), sql_time(times.end)];
+        let rows = statement.query_map(times, entry_from_row)?;
+        entries_from_rows(rows)
+    }
+
+    fn oldest_result_time(&self) -> Result<Option<u64>, StorageError> {
+        let oldest: Option<i64> = self.connection.query_row(
+            "SELECT MIN(finished_at) FROM matches WHERE finished_at > 0",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(oldest.map(|time| time as u64))
+    }
+
+    fn results_page(
+        &self,
+        filter: &ResultFilter,
+        user: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<LeaderboardEntry>, StorageError> {
+        let mut statement = self.connection.prepare(&format!(
+            "{FILTERED_RESULTS}
+             SELECT score, name, hash, finished_at, opponents, map, duration FROM filtered
+             WHERE ?3 IS NULL OR key = ?3 {RESULTS_ORDER} LIMIT ?4 OFFSET ?5"
+        ))?;
+        let rows = statement.query_map(
+            params![
+                filter.since as i64,
+                filter.personal_bests,
+                user,
+                limit.min(i64::MAX as usize) as i64,
+                offset.min(i64::MAX as usize) as i64,
+            ],
+            entry_from_row,
+        )?;
+        entries_from_rows(rows)
+    }
+
+    fn count_results(
+        &self,
+        filter: &ResultFilter,
+        user: Option<&str>,
+    ) -> Result<usize, StorageError> {
+        let count: i64 = self.connection.query_row(
+            &format!(
+                "{FILTERED_RESULTS} SELECT COUNT(*) FROM filtered WHERE ?3 IS NULL OR key = ?3"
+            ),
+            params![filter.since as i64, filter.personal_bests, user],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn result_rank(
+        &self,
+        filter: &ResultFilter,
+        user: &str,
+    ) -> Result<Option<usize>, StorageError> {
+        let rank: Option<i64> = self.connection.query_row(
+            &format!(
+                "{FILTERED_RESULTS}
+                 SELECT MIN(position) FROM (
+                     SELECT key, ROW_NUMBER() OVER ({RESULTS_ORDER}) AS position FROM filtered
+                 ) WHERE key = ?3"
+            ),
+            params![filter.since as i64, filter.personal_bests, user],
+            |row| row.get(0),
+        )?;
+        Ok(rank.map(|rank| rank as usize))
+    }
+
+    fn add_leaderboard_entry(&mut self, entry: &LeaderboardEntry) -> Result<(), StorageError> {
+        let transaction = self.connection.transaction()?;
+        insert_match(&transaction, entry)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn seasons(&self) -> Result<Vec<ArchivedSeason>, StorageError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT number, start, end, standings FROM seasons ORDER BY number")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)? as u64,
+                row.get::<_, i64>(1)? as u64,
+                row.get::<_, i64>(2)? as u64,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+        let mut seasons = Vec::new();
+        for row in rows {
+            let (number, start, end, standings) = row?;
+            seasons.push(ArchivedSeason {
+                number,
+                start,
+                end,
+                standings: serde_json::from_str(&standings)?,
+            });
+        }
+        Ok(seasons)
+    }
+
+    fn add_season(&mut self, season: &ArchivedSeason) -> Result<(), StorageError> {
+        insert_season(&self.connection, season)
+    }
+
+    fn stats(&self, id: u64) -> Result<UserStats, StorageError> {
+        let stats = self
+            .connection
+            .query_row(
+                &format!("SELECT {STATS_COLUMNS} FROM stats WHERE user_id = ?1"),
+                params![id as i64],
+                |row| stats_from_row(row, 0),
+            )
+            .optional()?;
+        Ok(stats.unwrap_or_default())
+    }
+
+    fn set_stats(&mut self, stats: &[(u64, UserStats)]) -> Result<(), StorageError> {
+        let transaction = self.connection.transaction()?;
+        for (id, stats) in stats {
+            insert_stats(&transaction, *id, stats)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn every_stats(&self) -> Result<Vec<(String, UserStats)>, StorageError> {
+        // Users who never finished a match have no row in `stats`
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT users.name, stats.user_id IS NOT NULL, {STATS_COLUMNS}
+             FROM users LEFT JOIN stats ON stats.user_id = users.id"
+        ))?;
+        let rows = statement.query_map([], |row| {
+            let stats = if row.get(1)? { stats_from_row(row, 2)? } else { UserStats::default() };
+            Ok((row.get(0)?, stats))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
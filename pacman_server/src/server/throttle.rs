@@ -0,0 +1,158 @@
+//! Limits on wrong passwords, against guessing them. Failures are counted per account and per
+//! source address, and each one makes both wait twice as long as the previous one before the
+//! next try. After `max_failures` they are locked out for `lockout_secs` instead
+//! Failures are forgotten `lockout_secs` after the last one, and those of an account when its
+//! password is given right
+
+use std::{collections::HashMap, net::IpAddr};
+
+use serde::Deserialize;
+
+use super::username::fold;
+
+/// Target of the log entries of the audit log, `log/audit.txt`
+pub const AUDIT: &str = "audit";
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    /// Failures in a row before a lockout
+    pub max_failures: u32,
+    /// Wait after the first failure, doubled after each of the next ones
+    pub backoff_secs: u64,
+    pub lockout_secs: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            backoff_secs: 1,
+            lockout_secs: 15 * 60,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    last: u64,
+    blocked_until: u64,
+}
+
+pub struct Throttle {
+    config: ThrottleConfig,
+    accounts: HashMap<String, Failures>, // Map : Folded username -> Failures
+    addresses: HashMap<IpAddr, Failures>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config,
+            accounts: HashMap::new(),
+            addresses: HashMap::new(),
+        }
+    }
+
+    /// Until when, in seconds since UNIX_EPOCH, the account or the address have to wait before
+    /// trying again, None if they don't
+    pub fn blocked_until(&mut self, user: &str, address: IpAddr, now: u64) -> Option<u64> {
+        self.forget(now);
+        let account = self.accounts.get(&fold(user)).map_or(0, |failures| failures.blocked_until);
+        let address = self.addresses.get(&address).map_or(0, |failures| failures.blocked_until);
+        let until = account.max(address);
+        (until > now).then_some(until)
+    }
+
+    /// Counts a wrong password and returns until when the account or the address have to wait
+    pub fn fail(&mut self, user: &str, address: IpAddr, now: u64) -> u64 {
+        let config = self.config;
+        let account = self.accounts.entry(fold(user)).or_default().fail(config, now);
+        let address = self.addresses.entry(address).or_default().fail(config, now);
+        account.max(address)
+    }
+
+    /// The account's password was given right, the failures from the address still count
+    pub fn succeed(&mut self, user: &str) {
+        self.accounts.remove(&fold(user));
+    }
+
+    fn forget(&mut self, now: u64) {
+        let lockout = self.config.lockout_secs;
+        self.accounts.retain(|_, failures| failures.last + lockout > now);
+        self.addresses.retain(|_, failures| failures.last + lockout > now);
+    }
+}
+
+impl Failures {
+    fn fail(&mut self, config: ThrottleConfig, now: u64) -> u64 {
+        self.count += 1;
+        self.last = now;
+        let wait = if self.count >= config.max_failures {
+            config.lockout_secs
+        } else {
+            let backoff = 1u64.checked_shl(self.count - 1).unwrap_or(u64::MAX);
+            config.backoff_secs.saturating_mul(backoff).min(config.lockout_secs)
+        };
+        self.blocked_until = now + wait;
+        self.blocked_until
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const HOME: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const ELSEWHERE: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    fn throttle() -> Throttle {
+        Throttle::new(ThrottleConfig {
+            max_failures: 4,
+            backoff_secs: 2,
+            lockout_secs: 100,
+        })
+    }
+
+    #[test]
+    fn waits_double_after_each_failure() {
+        let mut throttle = throttle();
+        assert_eq!(throttle.blocked_until("ana", HOME, 1000), None);
+        assert_eq!(throttle.fail("ana", HOME, 1000), 1002);
+        assert_eq!(throttle.blocked_until("ana", HOME, 1001), Some(1002));
+        assert_eq!(throttle.blocked_until("ana", HOME, 1002), None);
+        assert_eq!(throttle.fail("ana", HOME, 1002), 1006);
+        assert_eq!(throttle.fail("ana", HOME, 1006), 1014);
+    }
+
+    #[test]
+    fn locks_out_after_too_many_failures() {
+        let mut throttle = throttle();
+        for now in [1000, 1010, 1020] {
+            throttle.fail("ana", HOME, now);
+        }
+        assert_eq!(throttle.fail("ana", HOME, 1030), 1130);
+        // The account is locked from anywhere, in any case, and the address for any account
+        assert_eq!(throttle.blocked_until("ANA", ELSEWHERE, 1100), Some(1130));
+        assert_eq!(throttle.blocked_until("bob", HOME, 1100), Some(1130));
+        assert_eq!(throttle.blocked_until("bob", ELSEWHERE, 1100), None);
+        // Failures are forgotten once the lockout ends, so the backoff starts over
+        assert_eq!(throttle.blocked_until("ana", HOME, 1130), None);
+        assert_eq!(throttle.fail("ana", HOME, 1130), 1132);
+    }
+
+    #[test]
+    fn right_password_forgets_the_account_failures() {
+        let mut throttle = throttle();
+        throttle.fail("ana", HOME, 1000);
+        throttle.fail("ana", HOME, 1002);
+        throttle.succeed("Ana");
+        assert_eq!(throttle.blocked_until("ana", ELSEWHERE, 1003), None);
+        // Still counted for the address
+        assert_eq!(throttle.blocked_until("ana", HOME, 1003), Some(1006));
+        assert_eq!(throttle.fail("ana", ELSEWHERE, 1010), 1012);
+    }
+}
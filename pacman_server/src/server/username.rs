@@ -0,0 +1,119 @@
+//! Rules for the usernames users may create
+//! Names are normalized to Unicode NFKC, so visually identical names are the same name, and two
+//! names that only differ in case belong to the same user
+
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Allow letters and digits of any script, instead of only ASCII ones
+    pub unicode: bool,
+    /// Characters allowed besides letters and digits, but never as the first character
+    pub extra_chars: String,
+    /// Names nobody may create, in any case
+    pub reserved: Vec<String>,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 3,
+            max_length: 20,
+            unicode: false,
+            extra_chars: "_-.".to_owned(),
+            reserved: ["admin", "administrador", "root", "server", "servidor", "sistema"]
+                .map(str::to_owned)
+                .to_vec(),
+        }
+    }
+}
+
+/// Normal form of a name, used to look users up
+pub fn normalize(user: &str) -> String {
+    user.nfkc().collect()
+}
+
+/// Key under which a name is unique, regardless of case
+pub fn fold(user: &str) -> String {
+    normalize(user).to_lowercase()
+}
+
+impl UsernamePolicy {
+    /// Returns the normalized name if it can be created, otherwise why it can't
+    pub fn check(&self, user: &str) -> Result<String, String> {
+        let user = normalize(user);
+        let length = user.chars().count();
+        if length < self.min_length || length > self.max_length {
+            return Err(format!(
+                "length {length} is not between {} and {}",
+                self.min_length, self.max_length
+            ));
+        }
+        let is_alphanumeric = |c: char| {
+            if self.unicode {
+                c.is_alphanumeric()
+            } else {
+                c.is_ascii_alphanumeric()
+            }
+        };
+        if !user.starts_with(is_alphanumeric) {
+            return Err("it doesn't start with a letter or digit".to_owned());
+        }
+        if let Some(c) = user
+            .chars()
+            .find(|&c| !is_alphanumeric(c) && !self.extra_chars.contains(c))
+        {
+            return Err(format!("character {c:?} is not allowed"));
+        }
+        let folded = user.to_lowercase();
+        if self.reserved.iter().any(|name| fold(name) == folded) {
+            return Err("the name is reserved".to_owned());
+        }
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_normalized_and_folded() {
+        // Fullwidth letters and the "fi" ligature are compatibility characters
+        assert_eq!(normalize("Ａｎａ"), "Ana");
+        assert_eq!(normalize("ﬁona"), "fiona");
+        assert_eq!(fold("ＡＮＡ"), fold("ana"));
+        assert_eq!(fold("JOSÉ"), "josé");
+        // A decomposed accent is the same name as the composed one
+        assert_eq!(fold("Jose\u{301}"), fold("José"));
+    }
+
+    #[test]
+    fn policy_rejects_invalid_names() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(policy.check("Ａｎａ").unwrap(), "Ana");
+        assert_eq!(policy.check("ana_99").unwrap(), "ana_99");
+        assert!(policy.check("ab").is_err());
+        assert!(policy.check(&"a".repeat(21)).is_err());
+        assert!(policy.check("_ana").is_err());
+        assert!(policy.check("ana maria").is_err());
+        assert!(policy.check("José").is_err());
+        assert!(policy.check("ADMIN").is_err());
+        assert!(policy.check("Ａｄｍｉｎ").is_err());
+    }
+
+    #[test]
+    fn policy_allows_unicode_when_configured() {
+        let policy = UsernamePolicy {
+            unicode: true,
+            ..UsernamePolicy::default()
+        };
+        assert_eq!(policy.check("Jose\u{301}").unwrap(), "José");
+        assert!(policy.check("Ωμέγα").is_ok());
+        assert!(policy.check("_José").is_err());
+    }
+}